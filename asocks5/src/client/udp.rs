@@ -12,6 +12,7 @@ use std::net::Ipv4Addr;
 use std::net::SocketAddrV4;
 use std::net::{self, SocketAddr};
 use std::time::Duration;
+use tokio::net::udp::{RecvHalf, SendHalf};
 use tokio::net::{TcpStream, UdpSocket};
 
 #[derive(Debug)]
@@ -56,13 +57,7 @@ impl Socks5Datagram {
     }
 
     pub async fn send_to(mut self, d: &[u8], addr: Address) -> io::Result<Socks5Datagram> {
-        let mut buf = BytesMut::with_capacity((&addr).len() + 3 + d.len());
-        buf.put_slice(&[
-            0, 0, // reserved
-            0, // fragment id
-        ]);
-        write_address(&addr, &mut buf);
-        buf.put_slice(d);
+        let buf = encode_datagram(d, &addr);
         self.socket.send_to(buf.as_ref(), &self.proxy_addr).await?;
         let new_self = Socks5Datagram {
             socket: self.socket,
@@ -74,36 +69,92 @@ impl Socks5Datagram {
 
     pub async fn recv_from(mut self, buf: &mut [u8]) -> Result<(Address, usize), SocksError> {
         let mut header: Vec<u8> = vec![];
-        header.resize(998, 0);
+        header.resize(65535, 0);
 
         let (len, addr) = self.socket.recv_from(&mut header).await?;
         trace!("received dgram with {} bytes from {:?}", len, addr);
         header.resize(len, 0);
-        trace!("dgram {:?}", header);
-        let mut cursor = io::Cursor::new(header);
+        decode_datagram(header, buf)
+    }
 
-        let mut rb = [0u8; 2];
-        cursor.read_exact(&mut rb)?;
-        if rb[0] != 0 || rb[1] != 0 {
-            return Err(SocksError::InvalidData {
-                msg: "invalid reserved bytes",
-                data: rb.to_vec(),
-            });
-        }
-        let frag = cursor.read_u8()?;
-        if frag != 0 {
-            return Err(SocksError::InvalidData {
-                msg: "invalid fragment id",
-                data: vec![frag],
-            });
-        }
-        let a = read_address(&mut cursor)?;
-        trace!("read address {:?} with length {}", a, a.len());
-        let n = cursor.read(buf)?;
-        Ok((a, n))
+    /// Split into halves that can send and receive concurrently, so the association can be
+    /// used for many datagrams.
+    /// The association lasts as long as the returned tcp connection is open.
+    pub fn split(self) -> (Socks5RecvHalf, Socks5SendHalf, TcpStream) {
+        let (recv, send) = self.socket.split();
+        let send = Socks5SendHalf {
+            send,
+            proxy_addr: self.proxy_addr,
+        };
+        (Socks5RecvHalf { recv }, send, self.stream)
     }
 
     pub fn get_ref(&self) -> &UdpSocket {
         &self.socket
     }
 }
+
+#[derive(Debug)]
+pub struct Socks5RecvHalf {
+    recv: RecvHalf,
+}
+
+#[derive(Debug)]
+pub struct Socks5SendHalf {
+    send: SendHalf,
+    proxy_addr: SocketAddr,
+}
+
+impl Socks5RecvHalf {
+    pub async fn recv_from(&mut self, buf: &mut [u8]) -> Result<(Address, usize), SocksError> {
+        let mut header = vec![0; 65535];
+        let (len, addr) = self.recv.recv_from(&mut header).await?;
+        trace!("received dgram with {} bytes from {:?}", len, addr);
+        header.truncate(len);
+        decode_datagram(header, buf)
+    }
+}
+
+impl Socks5SendHalf {
+    pub async fn send_to(&mut self, d: &[u8], addr: &Address) -> io::Result<()> {
+        let buf = encode_datagram(d, addr);
+        self.send.send_to(buf.as_ref(), &self.proxy_addr).await?;
+        Ok(())
+    }
+}
+
+fn encode_datagram(d: &[u8], addr: &Address) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(addr.len() + 3 + d.len());
+    buf.put_slice(&[
+        0, 0, // reserved
+        0, // fragment id
+    ]);
+    write_address(addr, &mut buf);
+    buf.put_slice(d);
+    buf
+}
+
+fn decode_datagram(header: Vec<u8>, buf: &mut [u8]) -> Result<(Address, usize), SocksError> {
+    trace!("dgram {:?}", header);
+    let mut cursor = io::Cursor::new(header);
+
+    let mut rb = [0u8; 2];
+    cursor.read_exact(&mut rb)?;
+    if rb[0] != 0 || rb[1] != 0 {
+        return Err(SocksError::InvalidData {
+            msg: "invalid reserved bytes",
+            data: rb.to_vec(),
+        });
+    }
+    let frag = cursor.read_u8()?;
+    if frag != 0 {
+        return Err(SocksError::InvalidData {
+            msg: "invalid fragment id",
+            data: vec![frag],
+        });
+    }
+    let a = read_address(&mut cursor)?;
+    trace!("read address {:?} with length {}", a, a.len());
+    let n = cursor.read(buf)?;
+    Ok((a, n))
+}
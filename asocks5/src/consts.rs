@@ -3,6 +3,8 @@ use std::convert::TryFrom;
 use super::socks::SocksError;
 
 pub const SOCKS5_VERSION: u8 = 0x05;
+/// of the username/password sub-negotiation
+pub const SOCKS5_AUTH_VERSION: u8 = 0x01;
 
 #[allow(dead_code)]
 #[derive(Clone, Copy)]
@@ -19,6 +19,50 @@ pub async fn handle_socks_head(s: &mut TcpStream, h: HandshakeRequest) -> Result
     Ok(())
 }
 
+/// Username/password authentication, RFC 1929,
+/// returns the username if the check passes
+pub async fn handle_socks_head_auth<F>(
+    s: &mut TcpStream,
+    h: HandshakeRequest,
+    check: F,
+) -> Result<Vec<u8>, SocksError>
+where
+    F: Fn(&[u8], &[u8]) -> bool,
+{
+    trace!("socks req: {:?}", h);
+    if !h.methods.contains(&(AuthMethod::PASSWORD as u8)) {
+        warn!("Client doesn't offer password authentication");
+        write_socks_response(s, AuthMethod::NotAcceptable).await?;
+        return Err(SocksError::NoSupportAuth);
+    }
+    write_socks_response(s, AuthMethod::PASSWORD).await?;
+    let ver = s.read_u8().await?;
+    if ver != consts::SOCKS5_AUTH_VERSION {
+        return Err(SocksError::InvalidData {
+            msg: "invalid auth version",
+            data: vec![ver],
+        });
+    }
+    let user = read_short_bytes(s).await?;
+    let password = read_short_bytes(s).await?;
+    if check(&user, &password) {
+        s.write_all(&[consts::SOCKS5_AUTH_VERSION, 0]).await?;
+        Ok(user)
+    } else {
+        s.write_all(&[consts::SOCKS5_AUTH_VERSION, 1]).await?;
+        let user = String::from_utf8_lossy(&user).into_owned();
+        Err(SocksError::AuthFailed { user })
+    }
+}
+
+/// prefixed by its length in one byte
+async fn read_short_bytes(s: &mut TcpStream) -> Result<Vec<u8>, SocksError> {
+    let n = s.read_u8().await?;
+    let mut buf = vec![0; usize::from(n)];
+    s.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
 async fn write_socks_response(s: &mut TcpStream, meth: AuthMethod) -> Result<(), SocksError> {
     let buf = &[consts::SOCKS5_VERSION as u8, meth as u8];
     s.write_all(buf).await?;
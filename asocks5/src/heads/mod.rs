@@ -2,4 +2,4 @@ mod command;
 mod handshake;
 
 pub use self::command::read_command_async;
-pub use self::handshake::handle_socks_head;
+pub use self::handshake::{handle_socks_head, handle_socks_head_auth};
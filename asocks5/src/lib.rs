@@ -7,7 +7,7 @@ pub mod socks;
 
 pub use self::client::connect_socks_socket_addr;
 pub use self::client::connect_socks_to;
-pub use self::client::udp::Socks5Datagram;
+pub use self::client::udp::{Socks5Datagram, Socks5RecvHalf, Socks5SendHalf};
 pub use self::consts::Command;
 
 #[cfg(test)]
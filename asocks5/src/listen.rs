@@ -2,8 +2,8 @@ use failure::Error;
 use tokio::net::TcpStream;
 
 use super::socks::read_handshake_request;
-use crate::heads::handle_socks_head;
 use crate::heads::read_command_async;
+use crate::heads::{handle_socks_head, handle_socks_head_auth};
 use crate::socks::TcpRequestHeader;
 
 pub async fn handle_socks_handshake(
@@ -15,3 +15,29 @@ pub async fn handle_socks_handshake(
     let (s, req) = read_command_async(ts, peer).await?;
     Ok((s, req))
 }
+
+/// Compares a password in time independent of where it differs,
+/// for checks given to `handle_socks_handshake_auth`
+pub fn password_eq(given: &[u8], expected: &[u8]) -> bool {
+    let diff = given
+        .iter()
+        .zip(expected.iter())
+        .fold(0, |d, (a, b)| d | (a ^ b));
+    diff == 0 && given.len() == expected.len()
+}
+
+/// Requires a username and password accepted by the check,
+/// the username is returned
+pub async fn handle_socks_handshake_auth<F>(
+    mut ts: TcpStream,
+    check: F,
+) -> Result<(TcpStream, TcpRequestHeader, Vec<u8>), Error>
+where
+    F: Fn(&[u8], &[u8]) -> bool,
+{
+    let peer = ts.peer_addr()?;
+    let h = read_handshake_request(&mut ts).await?;
+    let user = handle_socks_head_auth(&mut ts, h, check).await?;
+    let (s, req) = read_command_async(ts, peer).await?;
+    Ok((s, req, user))
+}
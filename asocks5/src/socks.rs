@@ -30,6 +30,8 @@ pub enum SocksError {
     InvalidDomainEncoding,
     #[fail(display = "No supported auth methods")]
     NoSupportAuth,
+    #[fail(display = "Authentication failed for user {}", user)]
+    AuthFailed { user: String },
     #[fail(display = "Unsupported command {}", cmd)]
     CommandUnSupport { cmd: u8 },
     #[fail(display = "Invalid reply {}", reply)]
@@ -0,0 +1,110 @@
+//! tests on the request of plain http traffic
+use bytes::Bytes;
+use regex::bytes::Regex;
+use std::fmt;
+use std::fmt::Formatter;
+
+use crate::relay::inspect::HttpInfo;
+use crate::util::BsDisp;
+
+#[derive(Clone)]
+pub enum HttpTest {
+    Method(Bytes),
+    PathPrefix(Bytes),
+    Path(TextPattern),
+    UserAgent(TextPattern),
+}
+
+impl HttpTest {
+    pub fn passes(&self, h: &HttpInfo) -> bool {
+        use self::HttpTest::*;
+        match self {
+            Method(m) => h.method == m,
+            PathPrefix(p) => h.path.starts_with(p),
+            Path(p) => p.matches(&h.path),
+            UserAgent(p) => h.user_agent.as_ref().is_some_and(|u| p.matches(u)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum TextPattern {
+    /// "*" matches any text, "?" any one byte
+    Glob(Bytes),
+    Regex(Regex),
+}
+
+impl TextPattern {
+    pub fn matches(&self, text: &[u8]) -> bool {
+        match self {
+            TextPattern::Glob(g) => glob_match(g, text),
+            TextPattern::Regex(r) => r.is_match(text),
+        }
+    }
+}
+
+/// The whole text must match
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // where the last star is, and the text it has taken
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                // the star takes one more byte
+                Some((sp, st)) => {
+                    star = Some((sp, st + 1));
+                    p = sp + 1;
+                    t = st + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+impl fmt::Display for HttpTest {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        use self::HttpTest::*;
+        match self {
+            Method(m) => write!(f, "method {}", BsDisp::new(m)),
+            PathPrefix(p) => write!(f, "path ^\"{}\"", BsDisp::new(p)),
+            Path(p) => write!(f, "path {}", p),
+            UserAgent(p) => write!(f, "ua {}", p),
+        }
+    }
+}
+
+impl fmt::Display for TextPattern {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match self {
+            TextPattern::Glob(g) => write!(f, "~ \"{}\"", BsDisp::new(g)),
+            TextPattern::Regex(r) => write!(f, "=~ \"{}\"", r),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    #[test]
+    fn globs() {
+        assert!(glob_match(b"curl/*", b"curl/7.68.0"));
+        assert!(glob_match(b"*Firefox*", b"Mozilla/5.0 Gecko Firefox/75.0"));
+        assert!(glob_match(b"a?c*", b"abc"));
+        assert!(glob_match(b"*a*b", b"xaxxab"));
+        assert!(!glob_match(b"curl/*", b"Wget/1.20"));
+        assert!(!glob_match(b"a?c", b"ac"));
+        assert!(!glob_match(b"*a*b", b"xaxxa"));
+    }
+}
@@ -7,12 +7,21 @@ use std::fmt;
 use std::fmt::Formatter;
 use std::mem;
 
+mod http;
+mod schedule;
 mod text;
+mod tls;
 
+use self::http::HttpTest;
+pub use self::schedule::Schedule;
 pub use self::text::read_branch;
+pub use self::text::read_port_set;
+pub use self::text::read_schedule;
 pub use self::text::var_name;
+use self::tls::TlsTest;
 use crate::conf::main::RefVal;
 use crate::conf::Egress;
+use crate::relay::inspect::TcpProtocol;
 use crate::relay::route::TcpTrafficInfo;
 use crate::util::BsDisp;
 
@@ -24,6 +33,8 @@ pub enum RoutingBranch {
     Conditional(RoutingCondition),
     /// a match is found
     Final(RoutingAction),
+    /// another named rule, which is inserted after loading
+    Use(RefVal<Box<RoutingBranch>>),
 }
 
 impl RoutingBranch {
@@ -32,6 +43,7 @@ impl RoutingBranch {
         match self {
             Final(d) => Some(d.clone()),
             Conditional(c) => c.decide(info),
+            Use(r) => r.val().decision(info),
             Sequential(s) => {
                 for r in s {
                     if let Some(d) = r.decision(info) {
@@ -47,11 +59,17 @@ impl RoutingBranch {
         RoutingBranch::Final(x)
     }
 
+    fn new_test(t: RoutingTest, b: RoutingBranch) -> RoutingBranch {
+        RoutingBranch::Conditional(RoutingCondition::Test(t, Box::new(b)))
+    }
+
     pub fn insert_gateways(&mut self, gw: &BTreeMap<Bytes, Egress>) -> Result<(), Error> {
         use self::RoutingBranch::*;
         match self {
             Final(ref mut d) => d.insert_gateways(gw)?,
             Conditional(ref mut c) => c.insert_gateways(gw)?,
+            Use(RefVal::Val(ref mut b)) => b.insert_gateways(gw)?,
+            Use(RefVal::Ref(_)) => {}
             Sequential(ref mut s) => {
                 for r in s {
                     r.insert_gateways(gw)?;
@@ -60,53 +78,319 @@ impl RoutingBranch {
         }
         Ok(())
     }
+
+    pub fn insert_names(&mut self, names: &RuleNames) -> Result<(), Error> {
+        use self::RoutingBranch::*;
+        match self {
+            Final(_) | Use(RefVal::Ref(_)) => {}
+            Conditional(ref mut c) => c.insert_names(names)?,
+            Use(RefVal::Val(ref mut b)) => b.insert_names(names)?,
+            Sequential(ref mut s) => {
+                for r in s {
+                    r.insert_names(names)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops at the first rule not found, and returns its name,
+    /// the rules found are already inserted
+    pub fn insert_rules(&mut self, rules: &BTreeMap<Bytes, RoutingBranch>) -> Result<(), Bytes> {
+        use self::RoutingBranch::*;
+        match self {
+            Final(_) => {}
+            Conditional(ref mut c) => c.insert_rules(rules)?,
+            Use(ref mut r) => {
+                if let Some(n) = r.get_ref() {
+                    let b = rules.get(&n).ok_or(n)?;
+                    *r = RefVal::Val(Box::new(b.clone()));
+                }
+            }
+            Sequential(ref mut s) => {
+                for r in s {
+                    r.insert_rules(rules)?;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
 pub enum RoutingCondition {
-    Domain(BTreeMap<Bytes, RoutingBranch>),
-    IpAddr(BTreeMap<Bytes, RoutingBranch>),
-    Port(u16, Box<RoutingBranch>),
-    Protocol(BTreeMap<Bytes, RoutingBranch>),
+    Domain(Arms<Bytes>),
+    IpAddr(Arms<Bytes>),
+    Protocol(Arms<Bytes>),
+    /// the address zone of the client
+    Source(Arms<Bytes>),
+    /// the name authenticated by the relay
+    User(Arms<Bytes>),
+    /// the name of the relay
+    Inbound(Arms<Bytes>),
+    /// keyed by the names of port sets, which are inserted after loading,
+    /// a port in more than one set goes to the first one listed
+    Port(Arms<Bytes>, Vec<(Bytes, PortSet)>),
+    /// schedules containing the time of the connection
+    Time(Arms<RefVal<Schedule>>),
+    /// tests on plain http requests
+    Http(Arms<HttpTest>),
+    /// tests on the client hello of tls
+    Tls(Arms<TlsTest>),
+    /// the branch is taken only when the test passes
+    Test(RoutingTest, Box<RoutingBranch>),
 }
 
 impl RoutingCondition {
     fn decide(&self, info: &TcpTrafficInfo) -> Option<RoutingAction> {
         use self::RoutingCondition::*;
         match self {
-            Domain(x) => {
-                let r = x.get(info.domain_region()?)?;
-                r.decision(info)
+            Domain(x) => x.decide_key(info, info.domain_region()),
+            IpAddr(x) => x.decide_key(info, info.ip_region()),
+            Protocol(x) => x.decide_key(info, Some(info.protocol().name())),
+            Source(x) => x.decide_key(info, info.source_region()),
+            User(x) => x.decide_key(info, info.user()),
+            Inbound(x) => x.decide_key(info, info.inbound()),
+            Port(x, sets) => {
+                let port = info.addr().port();
+                let set = sets.iter().find(|(_, s)| s.contains(port));
+                x.decide_key(info, set.map(|(n, _)| n.as_ref()))
             }
-            IpAddr(x) => x.get(info.ip_region()?)?.decision(info),
-            Port(x, y) => {
-                if info.addr().port() != *x {
-                    None
-                } else {
+            Time(x) => x.decide(info, |s| s.val().contains(info.now())),
+            Http(x) => match info.protocol() {
+                TcpProtocol::PlainHttp(h) => x.decide(info, |t| t.passes(h)),
+                _ => x.decide(info, |_| false),
+            },
+            Tls(x) => match info.protocol() {
+                TcpProtocol::Tls(t) => x.decide(info, |k| k.passes(t)),
+                _ => x.decide(info, |_| false),
+            },
+            Test(t, y) => {
+                if t.passes(info) {
                     y.decision(info)
+                } else {
+                    None
                 }
             }
-            Protocol(x) => x.get(info.protocol().name())?.decision(info),
+        }
+    }
+
+    fn branches_mut(&mut self) -> Box<dyn Iterator<Item = &mut RoutingBranch> + '_> {
+        use self::RoutingCondition::*;
+        match self {
+            Domain(x) | IpAddr(x) | Protocol(x) | Source(x) | User(x) | Inbound(x) | Port(x, _) => {
+                Box::new(x.branches_mut())
+            }
+            Time(x) => Box::new(x.branches_mut()),
+            Http(x) => Box::new(x.branches_mut()),
+            Tls(x) => Box::new(x.branches_mut()),
+            Test(_, b) => Box::new(std::iter::once(b.as_mut())),
         }
     }
 
     fn insert_gateways(&mut self, gw: &BTreeMap<Bytes, Egress>) -> Result<(), Error> {
+        for b in self.branches_mut() {
+            b.insert_gateways(gw)?;
+        }
+        Ok(())
+    }
+
+    /// names in the keys and tests, then in the branches
+    fn insert_names(&mut self, names: &RuleNames) -> Result<(), Error> {
         use self::RoutingCondition::*;
-        let m = match self {
-            Port(_, ref mut b) => {
-                return b.insert_gateways(gw);
+        match self {
+            Test(ref mut t, _) => t.insert_names(names)?,
+            Time(x) => {
+                for (s, _) in x.list.iter_mut() {
+                    insert_schedule(s, names)?;
+                }
+            }
+            Port(x, ref mut found) => {
+                found.clear();
+                for (n, _) in &x.list {
+                    let s = names
+                        .ports
+                        .get(n)
+                        .ok_or_else(|| format_err!("Unknown port set {}", BsDisp::new(n)))?;
+                    found.push((n.clone(), s.clone()));
+                }
             }
-            Domain(x) => x,
-            IpAddr(x) => x,
-            Protocol(x) => x,
+            _ => {}
+        }
+        for b in self.branches_mut() {
+            b.insert_names(names)?;
+        }
+        Ok(())
+    }
+
+    fn insert_rules(&mut self, rules: &BTreeMap<Bytes, RoutingBranch>) -> Result<(), Bytes> {
+        for b in self.branches_mut() {
+            b.insert_rules(rules)?;
+        }
+        Ok(())
+    }
+}
+
+/// tried in order, the "else" branch is taken if none matches
+#[derive(Clone)]
+pub struct Arms<K> {
+    list: Vec<(K, RoutingBranch)>,
+    otherwise: Option<Box<RoutingBranch>>,
+}
+
+impl<K> Arms<K> {
+    /// no key for the "else" branch
+    fn new(arms: Vec<(Option<K>, RoutingBranch)>) -> Arms<K> {
+        let mut a = Arms {
+            list: vec![],
+            otherwise: None,
         };
-        for v in m.values_mut() {
-            v.insert_gateways(gw)?;
+        for (k, b) in arms {
+            match k {
+                Some(k) => a.list.push((k, b)),
+                None => a.otherwise = Some(Box::new(b)),
+            }
+        }
+        a
+    }
+
+    fn decide<F>(&self, info: &TcpTrafficInfo, matches: F) -> Option<RoutingAction>
+    where
+        F: Fn(&K) -> bool,
+    {
+        match self.list.iter().find(|(k, _)| matches(k)) {
+            Some((_, b)) => b.decision(info),
+            None => self.otherwise.as_ref()?.decision(info),
+        }
+    }
+
+    fn branches_mut(&mut self) -> impl Iterator<Item = &mut RoutingBranch> {
+        let o = self.otherwise.as_mut().map(|b| b.as_mut());
+        self.list.iter_mut().map(|(_, b)| b).chain(o)
+    }
+
+    fn print_else(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        if let Some(ref b) = self.otherwise {
+            writeln!(f, "else => {}", b)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+impl<K: fmt::Display> Arms<K> {
+    fn print(&self, f: &mut Formatter, kind: &str) -> Result<(), fmt::Error> {
+        writeln!(f, "{} {{", kind)?;
+        for (k, b) in &self.list {
+            writeln!(f, "{} => {}", k, b)?;
+        }
+        self.print_else(f)
+    }
+}
+
+/// keyed by the names of zones, protocols, users and so on
+impl Arms<Bytes> {
+    fn decide_key(&self, info: &TcpTrafficInfo, key: Option<&[u8]>) -> Option<RoutingAction> {
+        self.decide(info, |k| Some(k.as_ref()) == key)
+    }
+
+    fn print_keys(&self, f: &mut Formatter, kind: &str) -> Result<(), fmt::Error> {
+        writeln!(f, "{} {{", kind)?;
+        for (k, b) in &self.list {
+            writeln!(f, "{} => {}", BsDisp::new(k), b)?;
+        }
+        self.print_else(f)
+    }
+}
+
+fn insert_schedule(s: &mut RefVal<Schedule>, names: &RuleNames) -> Result<(), Error> {
+    s.insert_value(&names.schedules)
+        .map_err(|n| format_err!("Unknown schedule {}", BsDisp::new(&n)))
+}
+
+/// a yes-or-no question about the traffic
+#[derive(Clone)]
+pub enum RoutingTest {
+    Domain(Bytes),
+    IpAddr(Bytes),
+    Protocol(Bytes),
+    Source(Bytes),
+    User(Bytes),
+    Inbound(Bytes),
+    Port(RefVal<PortSet>),
+    Time(RefVal<Schedule>),
+    Http(HttpTest),
+    Tls(TlsTest),
+    Not(Box<RoutingTest>),
+    /// passes when every one of them passes
+    All(Vec<RoutingTest>),
+}
+
+impl RoutingTest {
+    fn passes(&self, info: &TcpTrafficInfo) -> bool {
+        use self::RoutingTest::*;
+        match self {
+            Domain(x) => info.domain_region() == Some(x),
+            IpAddr(x) => info.ip_region() == Some(x),
+            Protocol(x) => info.protocol().name() == x,
+            Source(x) => info.source_region() == Some(x),
+            User(x) => info.user() == Some(x),
+            Inbound(x) => info.inbound() == Some(x),
+            Port(x) => x.val().contains(info.addr().port()),
+            Time(x) => x.val().contains(info.now()),
+            Http(x) => match info.protocol() {
+                TcpProtocol::PlainHttp(h) => x.passes(h),
+                _ => false,
+            },
+            Tls(x) => match info.protocol() {
+                TcpProtocol::Tls(t) => x.passes(t),
+                _ => false,
+            },
+            Not(x) => !x.passes(info),
+            All(x) => x.iter().all(|t| t.passes(info)),
+        }
+    }
+
+    fn insert_names(&mut self, names: &RuleNames) -> Result<(), Error> {
+        use self::RoutingTest::*;
+        match self {
+            Port(x) => x
+                .insert_value(&names.ports)
+                .map_err(|n| format_err!("Unknown port set {}", BsDisp::new(&n)))?,
+            Time(x) => insert_schedule(x, names)?,
+            Not(x) => x.insert_names(names)?,
+            All(x) => {
+                for t in x {
+                    t.insert_names(names)?;
+                }
+            }
+            Domain(_) | IpAddr(_) | Protocol(_) | Source(_) | User(_) | Inbound(_) | Http(_)
+            | Tls(_) => {}
         }
         Ok(())
     }
 }
 
+/// Things defined in the config for rules to refer to by name
+#[derive(Default)]
+pub struct RuleNames {
+    pub ports: BTreeMap<Bytes, PortSet>,
+    pub schedules: BTreeMap<Bytes, Schedule>,
+}
+
+/// ports and inclusive ranges of them
+#[derive(Clone, Debug, PartialEq)]
+pub struct PortSet(pub Vec<(u16, u16)>);
+
+impl PortSet {
+    pub fn single(port: u16) -> PortSet {
+        PortSet(vec![(port, port)])
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.0.iter().any(|&(a, b)| a <= port && port <= b)
+    }
+}
+
 /// a chosen route
 #[derive(Clone)]
 pub enum RoutingAction {
@@ -148,6 +432,8 @@ impl fmt::Display for RoutingBranch {
             }
             Conditional(x) => write!(f, "cond {}", x)?,
             Final(x) => write!(f, "{}", x)?,
+            Use(RefVal::Ref(n)) => write!(f, "use {}", BsDisp::new(n))?,
+            Use(RefVal::Val(x)) => write!(f, "{}", x)?,
         }
         Ok(())
     }
@@ -166,6 +452,8 @@ impl fmt::Debug for RoutingBranch {
             }
             Conditional(x) => write!(f, "cond {}", x)?,
             Final(x) => write!(f, "egress {}", x)?,
+            Use(RefVal::Ref(n)) => write!(f, "use {}", BsDisp::new(n))?,
+            Use(RefVal::Val(x)) => write!(f, "{:?}", x)?,
         }
         Ok(())
     }
@@ -175,26 +463,52 @@ impl fmt::Display for RoutingCondition {
     fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
         use self::RoutingCondition::*;
         match self {
-            Domain(ref m) => {
-                write!(f, "domain ")?;
-                print_mapping(m, f)?;
-            }
-            IpAddr(ref m) => {
-                write!(f, "ip ")?;
-                print_mapping(m, f)?;
-            }
-            Protocol(ref m) => {
-                write!(f, "protocol ")?;
-                print_mapping(m, f)?;
-            }
-            Port(x, y) => {
-                write!(f, "port eq {} => {}", x, y)?;
+            Domain(x) => x.print_keys(f, "domain")?,
+            IpAddr(x) => x.print_keys(f, "ip")?,
+            Protocol(x) => x.print_keys(f, "protocol")?,
+            Source(x) => x.print_keys(f, "source")?,
+            User(x) => x.print_keys(f, "user")?,
+            Inbound(x) => x.print_keys(f, "inbound")?,
+            Port(x, _) => x.print_keys(f, "port")?,
+            Time(x) => x.print(f, "time")?,
+            Http(ref x) => x.print(f, "http")?,
+            Tls(ref x) => x.print(f, "tls")?,
+            Test(x, y) => {
+                write!(f, "{} => {}", x, y)?;
             }
         }
         Ok(())
     }
 }
 
+impl fmt::Display for RoutingTest {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        use self::RoutingTest::*;
+        match self {
+            Domain(x) => write!(f, "domain {}", BsDisp::new(x)),
+            IpAddr(x) => write!(f, "ip {}", BsDisp::new(x)),
+            Protocol(x) => write!(f, "protocol {}", BsDisp::new(x)),
+            Source(x) => write!(f, "source {}", BsDisp::new(x)),
+            User(x) => write!(f, "user {}", BsDisp::new(x)),
+            Inbound(x) => write!(f, "inbound {}", BsDisp::new(x)),
+            Port(RefVal::Ref(n)) => write!(f, "port in {}", BsDisp::new(n)),
+            Port(RefVal::Val(x)) => write!(f, "port in {}", x),
+            Time(RefVal::Ref(n)) => write!(f, "time {}", BsDisp::new(n)),
+            Time(RefVal::Val(x)) => write!(f, "time {}", x),
+            Http(x) => write!(f, "http {}", x),
+            Tls(x) => write!(f, "tls {}", x),
+            Not(x) => write!(f, "not {}", x),
+            All(x) => {
+                write!(f, "all [")?;
+                for y in x {
+                    write!(f, " {}", y)?;
+                }
+                write!(f, " ]")
+            }
+        }
+    }
+}
+
 impl fmt::Display for RoutingAction {
     fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
         use self::RoutingAction::*;
@@ -212,14 +526,296 @@ impl fmt::Display for RoutingAction {
     }
 }
 
-fn print_mapping(
-    map: &BTreeMap<Bytes, RoutingBranch>,
-    f: &mut Formatter,
-) -> Result<(), fmt::Error> {
-    writeln!(f, "{{")?;
-    for (k, v) in map.iter() {
-        writeln!(f, "{:?} => {}", k, v)?;
+impl fmt::Display for PortSet {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "[")?;
+        for (i, &(a, b)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            if a == b {
+                write!(f, "{}", a)?;
+            } else {
+                write!(f, "{}-{}", a, b)?;
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        read_branch, read_port_set, read_schedule, RoutingAction, RoutingBranch, RuleNames,
+    };
+    use crate::conf::{DomainMatcher, IpMatcher};
+    use crate::relay::inspect::{HttpInfo, TcpProtocol, TlsVersion, TlsWithSni};
+    use crate::relay::route::{TcpClient, TcpTrafficInfo};
+    use crate::relay::TcpRouter;
+    use crate::resolver::DomainHints;
+    use chrono::{TimeZone, Utc};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    fn names() -> RuleNames {
+        let mut names = RuleNames::default();
+        for (n, s) in &[
+            ("web", "[80, 443, 8000-8999]\n"),
+            ("mail", "[25,465 , 587]\n"),
+            ("tls", "[443, 465, 993]\n"),
+        ] {
+            let s = read_port_set(s.as_bytes()).unwrap().1;
+            names.ports.insert(n.to_string().into(), s);
+        }
+        let night = read_schedule(b"mon-fri 22:00-06:00 utc\n").unwrap().1;
+        names.schedules.insert("night".into(), night);
+        let rest = read_schedule(b"sat sun utc\n").unwrap().1;
+        names.schedules.insert("rest".into(), rest);
+        names
+    }
+
+    fn client(user: Option<&str>, inbound: Option<&str>) -> TcpClient {
+        TcpClient {
+            addr: ([192, 0, 2, 100], 50000).into(),
+            inbound: inbound.map(Into::into),
+            user: user.map(Into::into),
+        }
+    }
+
+    fn load_rule(text: &str) -> RoutingBranch {
+        let (_, mut b) = read_branch(text.as_bytes()).unwrap();
+        b.insert_names(&names()).unwrap();
+        b
+    }
+
+    fn show(d: Option<RoutingAction>) -> String {
+        d.as_ref().map_or("none".into(), RoutingAction::to_string)
+    }
+
+    fn decide(text: &str, info: &TcpTrafficInfo) -> String {
+        show(load_rule(text).decision(info))
+    }
+
+    fn route(text: &str, port: u16, domain: Option<&str>, p: &TcpProtocol) -> String {
+        let addr = ([192, 0, 2, 1], port).into();
+        let c = client(None, None);
+        decide(
+            text,
+            &TcpTrafficInfo::new(addr, p, &c).regions(domain, None),
+        )
+    }
+
+    #[test]
+    fn combinators() {
+        let rule = "any [
+            all [
+              domain abroad
+              protocol ssh
+              not port eq 443
+            ] => proxy
+            not cond domain abroad => direct
+            cond protocol {
+              tls => reset
+              else => cond port eq 22 => jump
+            }
+        ]
+        ";
+        let ssh = TcpProtocol::SSH;
+        let other = TcpProtocol::Unidentified;
+        assert_eq!(route(rule, 22, Some("abroad"), &ssh), "proxy");
+        assert_eq!(route(rule, 443, Some("abroad"), &ssh), "none");
+        assert_eq!(route(rule, 22, Some("abroad"), &other), "jump");
+        assert_eq!(route(rule, 22, None, &other), "direct");
+        assert_eq!(route(rule, 22, Some("home"), &ssh), "direct");
+        let shown = load_rule(rule).to_string();
+        assert!(shown.contains("cond protocol {\ntls => reset\nelse => cond port in [22]"));
+    }
+
+    #[test]
+    fn port_sets() {
+        let rule = "any [
+            cond port in [1-1023, 3128] => cond port {
+              web => reset
+              mail => jump
+              else => cond port ne 22 => proxy
+            }
+            cond port in web => direct
+        ]
+        ";
+        let p = TcpProtocol::Unidentified;
+        let via = |port| route(rule, port, None, &p);
+        assert_eq!(via(443), "reset");
+        assert_eq!(via(587), "jump");
+        assert_eq!(via(3128), "proxy");
+        assert_eq!(via(22), "none");
+        assert_eq!(via(8080), "direct");
+        assert_eq!(via(9000), "none");
+        // overlapping sets are tried in the order they're listed
+        let tls_first = "cond port {\n  tls => reset\n  web => proxy\n}\n";
+        let web_first = "cond port {\n  web => proxy\n  tls => reset\n}\n";
+        assert_eq!(route(tls_first, 443, None, &p), "reset");
+        assert_eq!(route(web_first, 443, None, &p), "proxy");
+        assert_eq!(route(web_first, 993, None, &p), "reset");
+        assert!(read_port_set(b"[80-22]\n").is_err());
+        let (_, mut b) = read_branch(b"cond port { chat => direct }\n").unwrap();
+        assert!(b.insert_names(&RuleNames::default()).is_err());
+    }
+
+    #[test]
+    fn client_source() {
+        let rule = "any [
+            cond source {
+              guests => vpn
+            }
+            cond not source office => reset
+            direct
+        ]
+        ";
+        let p = TcpProtocol::Unidentified;
+        let addr = ([192, 0, 2, 1], 443).into();
+        let c = client(None, None);
+        let via = |source| {
+            decide(
+                rule,
+                &TcpTrafficInfo::new(addr, &p, &c).regions(None, source),
+            )
+        };
+        assert_eq!(via(Some("guests")), "vpn");
+        assert_eq!(via(Some("office")), "direct");
+        assert_eq!(via(None), "reset");
+    }
+
+    #[test]
+    fn user_and_inbound() {
+        let rule = "any [
+            cond user {
+              alice => proxy
+              else => cond inbound tproxy => reset
+            }
+            cond inbound {
+              lan-socks => direct
+            }
+        ]
+        ";
+        let p = TcpProtocol::Unidentified;
+        let addr = ([192, 0, 2, 1], 443).into();
+        let via =
+            |user, inbound| decide(rule, &TcpTrafficInfo::new(addr, &p, &client(user, inbound)));
+        assert_eq!(via(Some("alice"), Some("tproxy")), "proxy");
+        assert_eq!(via(Some("bob"), Some("tproxy")), "reset");
+        assert_eq!(via(None, Some("lan-socks")), "direct");
+        assert_eq!(via(None, None), "none");
+    }
+
+    #[test]
+    fn time_of_week() {
+        let rule = "cond time {
+            night => metered
+            rest => cond not time 00:00-24:00 => reset
+            else => direct
+        }
+        ";
+        let p = TcpProtocol::Unidentified;
+        let c = client(None, None);
+        let addr = ([192, 0, 2, 1], 443).into();
+        let at = |day, h| {
+            let conf = Path::new("test/conf.d");
+            let d = Arc::new(DomainMatcher::new(conf).unwrap());
+            let i = Arc::new(IpMatcher::new(conf).unwrap());
+            // the first of may 2020 is a friday
+            let now = Utc.ymd(2020, 5, day).and_hms(h, 30, 0);
+            let r = TcpRouter::new(d, i, DomainHints::default(), load_rule(rule));
+            show(
+                r.with_clock(Box::new(move || now))
+                    .route(&c, addr, None, &p),
+            )
+        };
+        assert_eq!(at(1, 23), "metered");
+        assert_eq!(at(1, 12), "direct");
+        assert_eq!(at(2, 5), "metered");
+        assert_eq!(at(3, 11), "none");
+        assert_eq!(at(4, 5), "direct");
+        let (_, mut b) = read_branch(b"cond time day => direct\n").unwrap();
+        assert!(b.insert_names(&names()).is_err());
+    }
+
+    #[test]
+    fn http_requests() {
+        let rule = r#"cond http {
+            ua ~ "curl/*" => reset
+            path ^"/api/" => proxy
+            method CONNECT => direct
+            ua =~ "^Mozilla/5\.0 .*Firefox" => metered
+            else => cond http path ~ "*.iso" => metered
+        }
+        "#;
+        let http = |m: &str, path: &str, ua: Option<&str>| {
+            let h = HttpInfo::new(
+                m.as_bytes(),
+                path.as_bytes(),
+                b"example.com",
+                ua.map(str::as_bytes),
+            );
+            route(rule, 80, None, &TcpProtocol::PlainHttp(h))
+        };
+        assert_eq!(http("GET", "/api/x", Some("curl/7.68.0")), "reset");
+        assert_eq!(http("GET", "/api/x", None), "proxy");
+        assert_eq!(http("GET", "/apix", Some("Wget/1.20")), "none");
+        assert_eq!(http("CONNECT", "/", None), "direct");
+        assert_eq!(
+            http("GET", "/", Some("Mozilla/5.0 (X11) Firefox/75.0")),
+            "metered"
+        );
+        assert_eq!(http("GET", "/debian.iso", None), "metered");
+        assert_eq!(route(rule, 80, None, &TcpProtocol::Unidentified), "none");
+        let (_, b) = read_branch(rule.as_bytes()).unwrap();
+        assert!(b.to_string().contains(r#"path ^"/api/" => proxy"#));
+    }
+
+    #[test]
+    fn tls_client_hello() {
+        let rule = r#"cond tls {
+            ja3 0123456789abcdef0123456789ABCDEF => reset
+            alpn h2 => proxy
+            alpn acme-tls/1 => jump
+            alpn "x y" => reset
+            version 1.3 => direct
+            cipher 49199 => slow
+            else => metered
+        }
+        "#;
+        let tls = |alpn: &[&str], version, ja3: &str| {
+            let t = TlsWithSni {
+                version: TlsVersion::Tls12,
+                sni: None,
+                alpn: alpn.iter().map(|&a| a.into()).collect(),
+                versions: vec![version],
+                ciphers: vec![0x1301, 0xc02f],
+                ja3: ja3.into(),
+            };
+            route(rule, 443, None, &TcpProtocol::Tls(t))
+        };
+        let other = "00000000000000000000000000000000";
+        assert_eq!(tls(&["h2", "http/1.1"], TlsVersion::Tls13, other), "proxy");
+        assert_eq!(tls(&["http/1.1"], TlsVersion::Tls13, other), "direct");
+        assert_eq!(tls(&["acme-tls/1"], TlsVersion::Tls12, other), "jump");
+        assert_eq!(tls(&["x y"], TlsVersion::Tls12, other), "reset");
+        assert_eq!(tls(&[], TlsVersion::Tls12, other), "slow");
+        assert_eq!(
+            tls(
+                &["h2"],
+                TlsVersion::Tls13,
+                "0123456789abcdef0123456789abcdef"
+            ),
+            "reset"
+        );
+        assert_eq!(
+            route(rule, 443, None, &TcpProtocol::Unidentified),
+            "metered"
+        );
+        let (_, b) = read_branch(b"cond not tls version 1.2 => direct\n").unwrap();
+        assert_eq!(b.to_string(), "cond not tls version 1.2 => direct");
+        let shown = load_rule(rule).to_string();
+        assert!(shown.contains("alpn acme-tls/1 => jump\nalpn \"x y\" => reset"));
     }
-    write!(f, "}}")?;
-    Ok(())
 }
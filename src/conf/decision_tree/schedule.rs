@@ -0,0 +1,149 @@
+//! times of the week when rules apply
+use chrono::{DateTime, Datelike, Local, Timelike, Utc, Weekday};
+use failure::Error;
+use std::fmt;
+use std::fmt::Formatter;
+
+const ALL_DAYS: u8 = 0x7f;
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// One word of a schedule
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScheduleToken {
+    /// one bit for each day, monday first
+    Days(u8),
+    /// minutes after midnight
+    Hours(u16, u16),
+    Utc,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    days: u8,
+    /// the end comes before the start when the time goes past midnight,
+    /// then the time after midnight belongs to the day before
+    hours: Option<(u16, u16)>,
+    /// otherwise local time
+    utc: bool,
+}
+
+impl Schedule {
+    /// The days are combined, and there can only be one range of hours
+    pub fn new(tokens: Vec<ScheduleToken>) -> Result<Schedule, Error> {
+        let mut s = Schedule {
+            days: 0,
+            hours: None,
+            utc: false,
+        };
+        for t in tokens {
+            match t {
+                ScheduleToken::Days(d) => s.days |= d,
+                ScheduleToken::Hours(a, b) => {
+                    if s.hours.is_some() {
+                        return Err(format_err!("More than one range of hours in a schedule"));
+                    }
+                    s.hours = Some((a, b));
+                }
+                ScheduleToken::Utc => s.utc = true,
+            }
+        }
+        if s.days == 0 {
+            s.days = ALL_DAYS;
+        }
+        Ok(s)
+    }
+
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let (day, minute) = if self.utc {
+            (now.weekday(), now.hour() * 60 + now.minute())
+        } else {
+            let l = now.with_timezone(&Local);
+            (l.weekday(), l.hour() * 60 + l.minute())
+        };
+        let minute = minute as u16;
+        match self.hours {
+            None => self.on(day),
+            Some((a, b)) if a <= b => a <= minute && minute < b && self.on(day),
+            Some((a, b)) => (a <= minute && self.on(day)) || (minute < b && self.on(day.pred())),
+        }
+    }
+
+    /// Words of schedules, which can't be names of them
+    pub fn is_keyword(name: &[u8]) -> bool {
+        name == b"utc" || parse_days(name).is_some()
+    }
+
+    fn on(&self, day: Weekday) -> bool {
+        self.days & (1 << day.num_days_from_monday()) != 0
+    }
+}
+
+/// like "mon", "mon-fri", "weekdays" or "weekend"
+pub fn parse_days(word: &[u8]) -> Option<u8> {
+    let day = |w: &[u8]| DAY_NAMES.iter().position(|d| d.as_bytes() == w);
+    match word {
+        b"weekdays" => Some(0x1f),
+        b"weekend" => Some(0x60),
+        w => match w.iter().position(|&c| c == b'-') {
+            Some(i) => {
+                let (a, b) = (day(&w[..i])?, day(&w[i + 1..])?);
+                // wraps around the end of the week
+                Some((a..=b + if b < a { 7 } else { 0 }).fold(0, |m, d| m | 1 << (d % 7)))
+            }
+            None => Some(1 << day(w)?),
+        },
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        let mut words = vec![];
+        if self.days != ALL_DAYS {
+            for (i, d) in DAY_NAMES.iter().enumerate() {
+                if self.days & (1 << i) != 0 {
+                    words.push(d.to_string());
+                }
+            }
+        }
+        if let Some((a, b)) = self.hours {
+            words.push(format!(
+                "{:02}:{:02}-{:02}:{:02}",
+                a / 60,
+                a % 60,
+                b / 60,
+                b % 60
+            ));
+        }
+        if self.utc {
+            words.push("utc".into());
+        }
+        write!(f, "{}", words.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_days, Schedule, ScheduleToken};
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn over_midnight() {
+        let fri = parse_days(b"fri").unwrap();
+        let s = vec![
+            ScheduleToken::Days(fri),
+            ScheduleToken::Hours(22 * 60, 6 * 60),
+            ScheduleToken::Utc,
+        ];
+        let s = Schedule::new(s).unwrap();
+        // 2020-05-01 is a friday
+        assert!(s.contains(Utc.ymd(2020, 5, 1).and_hms(23, 0, 0)));
+        assert!(s.contains(Utc.ymd(2020, 5, 2).and_hms(5, 59, 0)));
+        assert!(!s.contains(Utc.ymd(2020, 5, 2).and_hms(6, 0, 0)));
+        assert!(!s.contains(Utc.ymd(2020, 5, 1).and_hms(3, 0, 0)));
+        assert!(!s.contains(Utc.ymd(2020, 5, 2).and_hms(23, 0, 0)));
+        assert_eq!(s.to_string(), "fri 22:00-06:00 utc");
+        assert_eq!(parse_days(b"sat-mon"), Some(0x61));
+        assert_eq!(parse_days(b"weekdays"), parse_days(b"mon-fri"));
+        assert_eq!(parse_days(b"someday"), None);
+    }
+}
@@ -1,78 +1,295 @@
 //! parse the configuration file
 use super::super::util::{line_sep, opt_line_sep};
-use super::{RoutingAction, RoutingBranch, RoutingCondition};
+use super::http::{HttpTest, TextPattern};
+use super::schedule::{parse_days, ScheduleToken};
+use super::tls::TlsTest;
+use super::{Arms, PortSet, RoutingAction, RoutingBranch, RoutingCondition, RoutingTest, Schedule};
+use crate::conf::main::RefVal;
+use crate::relay::inspect::TlsVersion;
 use bytes::Bytes;
 use nom::{digit1, line_ending, multispace0, space0, space1};
-use std::collections::BTreeMap;
+use regex::bytes::Regex;
 use std::str;
 
 named!(read_cond<&[u8], RoutingCondition>,
+    alt!(read_mapping_cond | read_test_cond)
+);
+
+named!(read_mapping_cond<&[u8], RoutingCondition>,
     do_parse!(
         kind: var_name >>
         space0 >>
         d: switch!(value!(kind),
-            b"domain" => map!(read_mapping, |m| RoutingCondition::Domain(m)) |
-            b"ip" => map!(read_mapping, |m| RoutingCondition::IpAddr(m)) |
-            b"protocol" => map!(read_mapping, |m| RoutingCondition::Protocol(m)) |
-            b"port" => call!(read_port)
+            b"domain" => map!(read_mapping, RoutingCondition::Domain) |
+            b"ip" => map!(read_mapping, RoutingCondition::IpAddr) |
+            b"protocol" => map!(read_mapping, RoutingCondition::Protocol) |
+            b"source" => map!(read_mapping, RoutingCondition::Source) |
+            b"user" => map!(read_mapping, RoutingCondition::User) |
+            b"inbound" => map!(read_mapping, RoutingCondition::Inbound) |
+            b"time" => call!(read_time_mapping) |
+            b"http" => call!(read_http_mapping) |
+            b"tls" => call!(read_tls_mapping) |
+            b"port" => map!(read_mapping, |m| RoutingCondition::Port(m, vec![]))
           ) >>
         ( (d) )
     )
 );
 
-named!(read_mapping<&[u8], BTreeMap<Bytes, RoutingBranch> >,
+named!(read_test_cond<&[u8], RoutingCondition>,
     do_parse!(
-        char!('{') >> opt_line_sep >>
-        m: dbg_dmp!(read_map) >>
-        opt_line_sep >> char!('}') >>
-        ( m )
+        test: read_test >>
+        branch: read_arrow_branch >>
+        ( RoutingCondition::Test(test, Box::new(branch)) )
+    )
+);
+
+named!(read_test<&[u8], RoutingTest>,
+    do_parse!(
+        kind: terminated!(var_name, space0) >>
+        t: switch!(value!(kind),
+            b"domain" => map!(read_zone, |z| RoutingTest::Domain(z.into())) |
+            b"ip" => map!(read_zone, |z| RoutingTest::IpAddr(z.into())) |
+            b"protocol" => map!(read_zone, |p| RoutingTest::Protocol(p.into())) |
+            b"source" => map!(read_zone, |z| RoutingTest::Source(z.into())) |
+            b"user" => map!(read_zone, |u| RoutingTest::User(u.into())) |
+            b"inbound" => map!(read_zone, |i| RoutingTest::Inbound(i.into())) |
+            b"time" => map!(read_time_key, RoutingTest::Time) |
+            b"http" => map!(read_http_test, RoutingTest::Http) |
+            b"tls" => map!(read_tls_test, RoutingTest::Tls) |
+            b"port" => call!(read_port) |
+            b"not" => call!(read_not) |
+            b"all" => call!(read_all)
+          ) >>
+        ( t )
+    )
+);
+
+named!(read_zone<&[u8], &[u8]>,
+    verify!(var_name, |n: &[u8]| !n.is_empty())
+);
+
+named!(read_port<&[u8], RoutingTest>,
+    // space already consumed in read_test
+    alt!(
+        do_parse!(
+            tag!("eq") >> space1 >>
+            port: read_u16 >>
+            ( RoutingTest::Port(RefVal::Val(PortSet::single(port))) )
+        ) |
+        do_parse!(
+            tag!("ne") >> space1 >>
+            port: read_u16 >>
+            ( RoutingTest::Not(Box::new(RoutingTest::Port(RefVal::Val(PortSet::single(port))))) )
+        ) |
+        do_parse!(
+            tag!("in") >> space1 >>
+            set: alt!(
+                map!(read_port_set, RefVal::Val) |
+                map!(read_zone, |n| RefVal::Ref(n.into()))
+            ) >>
+            ( RoutingTest::Port(set) )
+        )
     )
 );
 
-named!(read_port<&[u8], RoutingCondition>,
+// like [22, 80, 8000-8999]
+named!(pub read_port_set<&[u8], PortSet>,
+    do_parse!(
+        char!('[') >> space0 >>
+        ranges: separated_nonempty_list!(tuple!(space0, char!(','), space0), read_port_range) >>
+        space0 >> char!(']') >>
+        ( PortSet(ranges) )
+    )
+);
+
+named!(read_port_range<&[u8], (u16, u16)>,
+    verify!(
+        do_parse!(
+            a: read_u16 >>
+            b: opt!(preceded!(char!('-'), read_u16)) >>
+            ( (a, b.unwrap_or(a)) )
+        ),
+        |(a, b)| a <= b
+    )
+);
+
+// "cond" may be written after "not"
+named!(read_not<&[u8], RoutingTest>,
+    do_parse!(
+        opt!(terminated!(tag!("cond"), space1)) >>
+        t: read_test >>
+        ( RoutingTest::Not(Box::new(t)) )
+    )
+);
+
+// tests listed one per line
+named!(read_all<&[u8], RoutingTest>,
+    do_parse!(
+        char!('[') >>
+        opt_line_sep >>
+        items: separated_nonempty_list!(line_sep, read_test) >>
+        opt_line_sep >>
+        char!(']') >>
+        ( RoutingTest::All(items) )
+    )
+);
+
+named!(read_arrow_branch<&[u8], RoutingBranch>,
     do_parse!(
-        // space already consumed in read_cond
-        tag!("eq")>>
-        space1 >>
-        port: read_u16 >>
         space0 >>
-        tag!("=>")>>
+        tag!("=>") >>
         space0 >>
         branch: read_branch >>
-        ( RoutingCondition::Port(port, Box::new(branch)) )
+        ( branch )
     )
 );
 
-named!(read_u16<&[u8], u16>,
-    map_res!(map_res!(digit1, str::from_utf8),
-             str::FromStr::from_str)
+// tried in order
+macro_rules! read_arms (
+    ($i:expr, $key:ident) => (
+        do_parse!($i,
+            char!('{') >> opt_line_sep >>
+            arms: separated_nonempty_list!(line_sep, pair!(
+                alt!(value!(None, read_else) | map!($key, Some)),
+                read_arrow_branch
+            )) >>
+            opt_line_sep >> char!('}') >>
+            ( Arms::new(arms) )
+        )
+    );
+);
+
+named!(read_else<&[u8], &[u8]>,
+    verify!(var_name, |n: &[u8]| n == b"else")
 );
 
-named!(read_map<&[u8], BTreeMap<Bytes, RoutingBranch> >,
+named!(read_mapping<&[u8], Arms<Bytes> >,
+    read_arms!(read_key)
+);
+
+named!(read_key<&[u8], Bytes>,
+    map!(read_zone, Bytes::from)
+);
+
+named!(read_time_mapping<&[u8], RoutingCondition>,
+    map!(read_arms!(read_time_key), RoutingCondition::Time)
+);
+
+named!(read_http_mapping<&[u8], RoutingCondition>,
+    map!(read_arms!(read_http_test), RoutingCondition::Http)
+);
+
+named!(read_tls_mapping<&[u8], RoutingCondition>,
+    map!(read_arms!(read_tls_test), RoutingCondition::Tls)
+);
+
+// one word of a schedule, or the name of one
+named!(read_time_key<&[u8], RefVal<Schedule>>,
+    alt!(
+        map_res!(read_schedule_token, |t| Schedule::new(vec![t]).map(RefVal::Val)) |
+        map!(read_zone, |n| RefVal::Ref(n.into()))
+    )
+);
+
+named!(read_http_test<&[u8], HttpTest>,
     do_parse!(
-        entries: separated_nonempty_list!(line_sep, read_map_entry) >>
-        ( entries.into_iter().collect() )
+        kind: terminated!(var_name, space0) >>
+        t: switch!(value!(kind),
+            b"method" => map!(read_zone, |m| HttpTest::Method(m.into())) |
+            b"path" => alt!(
+                map!(preceded!(char!('^'), read_quoted), |p| HttpTest::PathPrefix(p.into())) |
+                map!(read_text_pattern, HttpTest::Path)
+            ) |
+            b"ua" => map!(read_text_pattern, HttpTest::UserAgent)
+          ) >>
+        ( t )
     )
 );
 
-/// doesn't consume spaces before or after it
-named!(read_map_entry<&[u8], (Bytes, RoutingBranch)>,
+named!(read_tls_test<&[u8], TlsTest>,
     do_parse!(
-        keyword: map!(var_name, |bs: &[u8]| bs.into()) >>
-space0 >>
-tag!("=>") >>
-space0 >>
-        value: read_branch >>
-        ( (keyword, value) )
+        kind: terminated!(var_name, space0) >>
+        t: switch!(value!(kind),
+            b"alpn" => map!(alt!(read_quoted | take_while1!(is_alpn_char)),
+                |a| TlsTest::Alpn(a.into())) |
+            b"version" => map_opt!(
+                recognize!(tuple!(digit1, char!('.'), digit1)),
+                |v| TlsVersion::from_number(v).map(TlsTest::Version)
+            ) |
+            b"cipher" => map!(read_u16, TlsTest::Cipher) |
+            b"ja3" => map!(verify!(read_zone, |h: &[u8]| h.len() == 32), |h| TlsTest::Ja3(h.into()))
+          ) >>
+        ( t )
+    )
+);
+
+// like ~ "curl/*" or =~ "^curl/7\."
+named!(read_text_pattern<&[u8], TextPattern>,
+    alt!(
+        do_parse!(
+            tag!("=~") >> space0 >>
+            r: map_res!(map_res!(read_quoted, str::from_utf8), Regex::new) >>
+            ( TextPattern::Regex(r) )
+        ) |
+        do_parse!(
+            char!('~') >> space0 >>
+            g: read_quoted >>
+            ( TextPattern::Glob(g.into()) )
+        )
     )
 );
 
+named!(read_quoted<&[u8], &[u8]>,
+    delimited!(char!('"'), take_while!(|c| c != b'"'), char!('"'))
+);
+
+// like "sat sun", "mon-fri 09:00-18:00" or "22:00-06:00 utc"
+named!(pub read_schedule<&[u8], Schedule>,
+    map_res!(separated_nonempty_list!(space1, read_schedule_token), Schedule::new)
+);
+
+named!(read_schedule_token<&[u8], ScheduleToken>,
+    alt!(
+        do_parse!(
+            a: read_clock >>
+            char!('-') >>
+            b: read_clock >>
+            ( ScheduleToken::Hours(a, b) )
+        ) |
+        map_opt!(var_name, |w: &[u8]| match w {
+            b"utc" => Some(ScheduleToken::Utc),
+            w => parse_days(w).map(ScheduleToken::Days),
+        })
+    )
+);
+
+// minutes after midnight, from 00:00 to 24:00
+named!(read_clock<&[u8], u16>,
+    verify!(
+        do_parse!(
+            h: read_u16 >>
+            char!(':') >>
+            m: verify!(read_u16, |m| m < 60) >>
+            ( h.saturating_mul(60).saturating_add(m) )
+        ),
+        |t| t <= 24 * 60
+    )
+);
+
+named!(read_u16<&[u8], u16>,
+    map_res!(map_res!(digit1, str::from_utf8),
+             str::FromStr::from_str)
+);
+
 named!(pub read_branch<&[u8], RoutingBranch>,
     switch!(terminated!(var_name, space0),
         b"direct" => value!(RoutingBranch::new_final(RoutingAction::Direct)) |
         b"reset" => value!(RoutingBranch::new_final(RoutingAction::Reset)) |
         b"any" => delimited!(tag!("["), read_sequential, tag!("]")) |
         b"cond" => map!(read_cond, |c| RoutingBranch::Conditional(c)) |
+        b"use" => map!(read_zone, |n| RoutingBranch::Use(RefVal::Ref(n.into()))) |
+        b"not" => map!(pair!(read_not, read_arrow_branch), |(t, b)| RoutingBranch::new_test(t, b)) |
+        b"all" => map!(pair!(read_all, read_arrow_branch), |(t, b)| RoutingBranch::new_test(t, b)) |
         x => value!(RoutingBranch::new_final(RoutingAction::new_named(x)))
     )
 );
@@ -105,3 +322,8 @@ named!(pub var_name<&[u8], &[u8]>,
 fn is_alphanumunder(c: u8) -> bool {
     c.is_ascii_alphanumeric() || c == b'_' || c == b'-'
 }
+
+/// protocol ids like "h2" or "acme-tls/1" can be written without quotes
+pub fn is_alpn_char(c: u8) -> bool {
+    is_alphanumunder(c) || c == b'/' || c == b'.'
+}
@@ -0,0 +1,46 @@
+//! tests on the client hello of tls traffic
+use bytes::Bytes;
+use std::fmt;
+use std::fmt::Formatter;
+
+use super::text::is_alpn_char;
+use crate::relay::inspect::{TlsVersion, TlsWithSni};
+use crate::util::BsDisp;
+
+#[derive(Clone)]
+pub enum TlsTest {
+    /// one of the protocols offered
+    Alpn(Bytes),
+    /// the highest version offered
+    Version(TlsVersion),
+    /// one of the cipher suites offered, in decimal as in JA3
+    Cipher(u16),
+    Ja3(Bytes),
+}
+
+impl TlsTest {
+    pub fn passes(&self, t: &TlsWithSni) -> bool {
+        use self::TlsTest::*;
+        match self {
+            Alpn(a) => t.alpn.contains(a),
+            Version(v) => t.max_version() == *v,
+            Cipher(c) => t.ciphers.contains(c),
+            Ja3(j) => t.ja3.eq_ignore_ascii_case(j),
+        }
+    }
+}
+
+impl fmt::Display for TlsTest {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        use self::TlsTest::*;
+        match self {
+            Alpn(a) if !a.is_empty() && a.iter().all(|&c| is_alpn_char(c)) => {
+                write!(f, "alpn {}", BsDisp::new(a))
+            }
+            Alpn(a) => write!(f, "alpn \"{}\"", BsDisp::new(a)),
+            Version(v) => write!(f, "version {}", v.number().unwrap_or("?")),
+            Cipher(c) => write!(f, "cipher {}", c),
+            Ja3(j) => write!(f, "ja3 {}", BsDisp::new(j)),
+        }
+    }
+}
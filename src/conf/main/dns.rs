@@ -1,58 +1,490 @@
 use crate::conf::main::util::RefVal;
-use crate::conf::Egress;
+use crate::conf::{Egress, IpMatcher};
 use crate::util::BsDisp;
 use bytes::Bytes;
 use failure::Error;
+use http::Uri;
 use std::collections::BTreeMap;
 use std::fmt;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use trust_dns::rr::RecordType;
 
 #[derive(Debug, Clone)]
 pub struct DnsProxy {
-    pub listen: SocketAddr,
-    pub forward: BTreeMap<Bytes, NameServer>,
-    pub default: NameServer,
+    pub listen: Vec<DnsListen>,
+    /// Needed by encrypted listeners
+    pub identity: Option<TlsIdentity>,
+    pub cache: Option<CacheConf>,
+    /// Answered without forwarding
+    pub records: Vec<(String, LocalData)>,
+    /// In the format of /etc/hosts
+    pub hosts: Vec<PathBuf>,
+    /// Ttl of records and blocking answers made up locally
+    pub local_ttl: u32,
+    pub forward: BTreeMap<Bytes, DnsTarget>,
+    pub default: DnsTarget,
+    /// Checked in order before the forwarding tables
+    pub query_types: Vec<QueryTypeRule>,
+    /// Forwarding tables of clients in address zones,
+    /// each zone in them, including `else`, overrides the one in `forward`
+    pub client_forward: BTreeMap<Bytes, BTreeMap<Bytes, DnsTarget>>,
+    /// Answers checked for poisoning, by domain zone or `else`
+    pub verify: BTreeMap<Bytes, DnsVerify>,
+    /// EDNS client subnet sent upstream, by domain zone or `else`
+    pub client_subnet: BTreeMap<Bytes, ClientSubnet>,
+    /// Addresses given to domain names in fake-ip zones
+    pub fake_ip_pool: (Ipv4Addr, u8),
+    /// How many addresses in answers to remember the domain names of
+    pub learn_domains: Option<usize>,
+    /// Where every query is logged to, as json lines
+    pub query_log: Option<QueryLog>,
+    /// How often the counters of queries are logged
+    pub stats_interval: Option<Duration>,
+    pub dnssec: Option<DnssecConf>,
 }
 
+/// Answers in the zones are validated with DNSSEC, and bogus ones refused
+#[derive(Clone, Debug, PartialEq)]
+pub struct DnssecConf {
+    /// Domain zones, or `else`
+    pub zones: Vec<Bytes>,
+    /// DS records of trusted keys, the built-in root keys if none
+    pub trust_anchor: Option<PathBuf>,
+}
+
+/// One line in the dnssec section of the config
+pub enum DnssecItem {
+    Validate(Vec<Bytes>),
+    TrustAnchor(PathBuf),
+}
+
+impl DnssecConf {
+    pub fn new(items: Vec<DnssecItem>) -> Result<DnssecConf, Error> {
+        let mut zones = vec![];
+        let mut trust_anchor = None;
+        for i in items {
+            match i {
+                DnssecItem::Validate(z) => zones.extend(z),
+                DnssecItem::TrustAnchor(p) => trust_anchor = Some(p),
+            }
+        }
+        if zones.is_empty() {
+            return Err(format_err!("No zones to validate with dnssec"));
+        }
+        Ok(DnssecConf {
+            zones,
+            trust_anchor,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryLog {
+    Stderr,
+    File(PathBuf),
+}
+
+/// Queries of the type, and in the zone if given, go to the target instead,
+/// the zone of PTR queries is the address zone of the address in the name
+#[derive(Clone, Debug)]
+pub struct QueryTypeRule {
+    pub query_type: RecordType,
+    pub zone: Option<Bytes>,
+    pub target: DnsTarget,
+}
+
+/// What to do with the EDNS client subnet option of queries, RFC 7871
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientSubnet {
+    Strip,
+    Pass,
+    /// Replace it with the address and source prefix length
+    Inject(IpAddr, u8),
+}
+
+/// Addresses in an answer failing the check mean the answer is poisoned,
+/// so the query is sent again to a trusted nameserver
+#[derive(Clone, Debug)]
+pub struct DnsVerify {
+    pub check: AnswerCheck,
+    pub retry: NameServerGroup,
+}
+
+#[derive(Clone, Debug)]
+pub enum AnswerCheck {
+    /// All addresses must be in the address zone
+    Expect(Bytes),
+    /// No address can be in the address zone
+    Reject(Bytes),
+}
+
+/// What to do with queries of domain names in a zone
+#[derive(Clone, Debug)]
+pub enum DnsTarget {
+    Forward(NameServerGroup),
+    Block(BlockMode),
+    /// Answer with addresses from a pool, which are mapped back to
+    /// the domain names when relayed
+    FakeIp,
+}
+
+/// How blocked queries are answered
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockMode {
+    NxDomain,
+    /// 0.0.0.0 or ::
+    Zero,
+    /// No record, but no error either
+    Empty,
+}
+
+#[derive(Clone, Debug)]
+pub enum LocalData {
+    Addr(IpAddr),
+    Cname(String),
+}
+
+/// Where clients send queries to
+#[derive(Clone, Copy, Debug)]
+pub enum DnsListen {
+    Udp(SocketAddr),
+    /// Messages are prefixed with length
+    Tcp(SocketAddr),
+    /// Dns over tls
+    Tls(SocketAddr),
+    /// Dns over https, with http/2
+    Https(SocketAddr),
+}
+
+impl DnsListen {
+    fn is_encrypted(&self) -> bool {
+        matches!(self, DnsListen::Tls(_) | DnsListen::Https(_))
+    }
+}
+
+/// Pem files of the server certificate chain and its private key
+#[derive(Clone, Debug)]
+pub struct TlsIdentity {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// How responses are cached
+#[derive(Clone, Debug)]
+pub struct CacheConf {
+    /// Maximum number of cached responses
+    pub size: usize,
+    /// Ttls are clamped to this range
+    pub min_ttl: u32,
+    pub max_ttl: u32,
+    /// Negative answers are cached for at most this long
+    pub negative_ttl: u32,
+    /// Where responses are saved to, and loaded from on start
+    pub file: Option<PathBuf>,
+    /// Seconds between saves, it's also saved when shutting down
+    pub save_interval: u32,
+    /// Responses asked for this many times are refreshed before they expire,
+    /// 0 means never
+    pub prefetch: u32,
+}
+
+/// One line in the cache section of the config
+pub enum CacheItem<'a> {
+    Number(&'a [u8], u32),
+    File(PathBuf),
+}
+
+impl Default for CacheConf {
+    fn default() -> CacheConf {
+        CacheConf {
+            size: 1024,
+            min_ttl: 0,
+            max_ttl: 86400,
+            // RFC 2308 suggests one to three hours
+            negative_ttl: 3600,
+            file: None,
+            save_interval: 300,
+            prefetch: 0,
+        }
+    }
+}
+
+impl CacheConf {
+    pub fn new(entries: Vec<CacheItem>) -> Result<CacheConf, Error> {
+        let mut c = CacheConf::default();
+        for e in entries {
+            let (k, v) = match e {
+                CacheItem::File(p) => {
+                    c.file = Some(p);
+                    continue;
+                }
+                CacheItem::Number(k, v) => (k, v),
+            };
+            match k {
+                b"size" => c.size = v as usize,
+                b"min-ttl" => c.min_ttl = v,
+                b"max-ttl" => c.max_ttl = v,
+                b"negative-ttl" => c.negative_ttl = v,
+                b"save-interval" => c.save_interval = v,
+                b"prefetch" => c.prefetch = v,
+                _ => return Err(format_err!("Unknown dns cache option {}", BsDisp::new(k))),
+            }
+        }
+        if c.min_ttl > c.max_ttl {
+            return Err(format_err!("min-ttl of dns cache is larger than max-ttl"));
+        }
+        if c.save_interval == 0 {
+            return Err(format_err!("save-interval of dns cache must be positive"));
+        }
+        Ok(c)
+    }
+}
+
+/// One line in the dns section of the config
+pub enum DnsItem {
+    Listen(DnsListen),
+    Forward(Vec<(Bytes, DnsTarget)>),
+    /// Forwarding table of clients in the address zone
+    ClientForward(Bytes, Vec<(Bytes, DnsTarget)>),
+    QueryTypes(Vec<QueryTypeRule>),
+    Certificate(PathBuf),
+    Key(PathBuf),
+    Cache(CacheConf),
+    Records(Vec<(String, LocalData)>),
+    Hosts(PathBuf),
+    LocalTtl(u32),
+    Verify(Vec<(Bytes, DnsVerify)>),
+    ClientSubnet(Vec<(Bytes, ClientSubnet)>),
+    FakeIpPool(Ipv4Addr, u8),
+    LearnDomains(usize),
+    QueryLog(QueryLog),
+    StatsInterval(Duration),
+    Dnssec(DnssecConf),
+}
+
+/// How long to wait for a nameserver if not configured
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
 #[derive(Clone, Debug)]
 pub struct NameServer {
     pub egress: Option<RefVal<Egress>>,
     pub remote: NameServerRemote,
+    pub timeout: Duration,
+}
+
+/// Nameservers serving the same zone
+#[derive(Clone, Debug)]
+pub struct NameServerGroup {
+    pub strategy: Strategy,
+    pub servers: Vec<NameServer>,
+}
+
+/// Which nameservers in a group a query is sent to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    /// All at once, the first valid answer wins
+    Race,
+    /// One after another, until one gives a valid answer
+    Sequential,
+    /// Like sequential, but starting from a random one
+    Random,
 }
 #[derive(Clone, Debug)]
 pub enum NameServerRemote {
     Udp(SocketAddr),
     Tcp(SocketAddr),
+    /// Dns over tls, with the name to verify the certificate against
+    Tls(SocketAddr, String),
+    Https(DohServer),
+}
+
+/// A Dns over https server, as in RFC 8484
+#[derive(Clone, Debug)]
+pub struct DohServer {
+    /// Where queries are sent to
+    pub uri: Uri,
+    /// The address to connect to, so that the host in the uri
+    /// doesn't have to be resolved using dns
+    pub addr: SocketAddr,
+    /// Use GET requests instead of POST
+    pub get: bool,
 }
 
 impl DnsProxy {
-    pub fn new1(listen: SocketAddr, ms: Vec<(Bytes, NameServer)>) -> Result<DnsProxy, Error> {
-        let mut forward: BTreeMap<Bytes, NameServer> = ms.into_iter().collect();
+    pub fn new1(items: Vec<DnsItem>) -> Result<DnsProxy, Error> {
+        let mut listen = vec![];
+        let mut forward: BTreeMap<Bytes, DnsTarget> = BTreeMap::new();
+        let mut client_forward: BTreeMap<Bytes, BTreeMap<Bytes, DnsTarget>> = BTreeMap::new();
+        let mut query_types = vec![];
+        let mut cert = None;
+        let mut key = None;
+        let mut cache = None;
+        let mut records = vec![];
+        let mut hosts = vec![];
+        let mut local_ttl = 300;
+        let mut verify = BTreeMap::new();
+        let mut client_subnet = BTreeMap::new();
+        // reserved for benchmarking, RFC 2544
+        let mut fake_ip_pool = (Ipv4Addr::new(198, 18, 0, 0), 15);
+        let mut learn_domains = None;
+        let mut query_log = None;
+        let mut stats_interval = None;
+        let mut dnssec = None;
+        for i in items {
+            match i {
+                DnsItem::Listen(l) => listen.push(l),
+                DnsItem::Forward(ms) => forward.extend(ms),
+                DnsItem::QueryTypes(r) => query_types.extend(r),
+                DnsItem::ClientForward(c, ms) => client_forward.entry(c).or_default().extend(ms),
+                DnsItem::Certificate(p) => cert = Some(p),
+                DnsItem::Key(p) => key = Some(p),
+                DnsItem::Cache(c) => cache = Some(c),
+                DnsItem::Records(r) => records.extend(r),
+                DnsItem::Hosts(p) => hosts.push(p),
+                DnsItem::LocalTtl(t) => local_ttl = t,
+                DnsItem::Verify(v) => verify.extend(v),
+                DnsItem::ClientSubnet(c) => client_subnet.extend(c),
+                DnsItem::FakeIpPool(a, p) => fake_ip_pool = (a, p),
+                DnsItem::LearnDomains(n) => learn_domains = Some(n),
+                DnsItem::QueryLog(l) => query_log = Some(l),
+                DnsItem::StatsInterval(d) => stats_interval = Some(d),
+                DnsItem::Dnssec(d) => dnssec = Some(d),
+            }
+        }
+        if listen.is_empty() {
+            return Err(format_err!("Dns proxy doesn't listen on any address"));
+        }
+        let identity = match (cert, key) {
+            (Some(cert), Some(key)) => Some(TlsIdentity { cert, key }),
+            (None, None) => None,
+            _ => return Err(format_err!("Both certificate and key are needed")),
+        };
+        if identity.is_none() && listen.iter().any(DnsListen::is_encrypted) {
+            return Err(format_err!(
+                "Dns over tls or https needs a certificate and key"
+            ));
+        }
         let b: Bytes = "else".into();
         let d = forward
             .remove(&b)
             .ok_or_else(|| format_err!("No default dns"))?;
         Ok(DnsProxy {
             listen,
+            identity,
+            cache,
+            records,
+            hosts,
+            local_ttl,
             forward,
             default: d,
+            query_types,
+            client_forward,
+            verify,
+            client_subnet,
+            fake_ip_pool,
+            learn_domains,
+            query_log,
+            stats_interval,
+            dnssec,
         })
     }
 
+    pub fn uses_fake_ip(&self) -> bool {
+        let is_fake = |t: &DnsTarget| matches!(t, DnsTarget::FakeIp);
+        is_fake(&self.default)
+            || self.forward.values().any(is_fake)
+            || self
+                .client_forward
+                .values()
+                .flat_map(|t| t.values())
+                .any(is_fake)
+            || self.query_types.iter().any(|r| is_fake(&r.target))
+    }
+
+    /// paths in the config are relative to the config directory
+    pub fn locate_files(&mut self, dir: &Path) {
+        if let Some(ref mut i) = self.identity {
+            i.cert = dir.join(&i.cert);
+            i.key = dir.join(&i.key);
+        }
+        for h in &mut self.hosts {
+            *h = dir.join(&h);
+        }
+        if let Some(QueryLog::File(ref mut p)) = self.query_log {
+            *p = dir.join(&p);
+        }
+        if let Some(CacheConf {
+            file: Some(ref mut p),
+            ..
+        }) = self.cache
+        {
+            *p = dir.join(&p);
+        }
+        if let Some(DnssecConf {
+            trust_anchor: Some(ref mut p),
+            ..
+        }) = self.dnssec
+        {
+            *p = dir.join(&p);
+        }
+    }
+
     /// replace named gateways with actual values
     pub fn deref_route(&mut self, gw: &BTreeMap<Bytes, Egress>) -> Result<(), Error> {
-        for ns in &mut self.forward.values_mut() {
-            if let Some(ref mut e) = ns.egress {
-                e.insert_value(gw).map_err(|e| {
-                    format_err!(
-                        "Error in dns proxy configuration: \
-                         {} is not a defined egress",
-                        BsDisp::new(&e)
-                    )
-                })?;
+        let tables = self
+            .client_forward
+            .values_mut()
+            .flat_map(|t| t.values_mut());
+        let types = self.query_types.iter_mut().map(|r| &mut r.target);
+        for t in self.forward.values_mut().chain(tables).chain(types) {
+            t.deref_route(gw)?;
+        }
+        for v in self.verify.values_mut() {
+            for ns in &mut v.retry.servers {
+                ns.deref_route(gw)?;
             }
         }
-        if let Some(ref mut e) = self.default.egress {
+        self.default.deref_route(gw)
+    }
+
+    /// the IP zones of clients, of reverse lookups and
+    /// those answers are checked against must be defined
+    pub fn check_zones(&self, ip: &IpMatcher) -> Result<(), Error> {
+        let checks = self.verify.values().map(|v| match v.check {
+            AnswerCheck::Expect(ref z) | AnswerCheck::Reject(ref z) => z,
+        });
+        let reverse = self
+            .query_types
+            .iter()
+            .filter(|r| r.query_type == RecordType::PTR)
+            .filter_map(|r| r.zone.as_ref());
+        let zones = checks.chain(self.client_forward.keys()).chain(reverse);
+        for z in zones {
+            if !ip.has_zone(z) {
+                return Err(format_err!("IP zone {} is not defined", BsDisp::new(z)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DnsTarget {
+    fn deref_route(&mut self, gw: &BTreeMap<Bytes, Egress>) -> Result<(), Error> {
+        if let DnsTarget::Forward(ref mut g) = self {
+            for ns in &mut g.servers {
+                ns.deref_route(gw)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl NameServer {
+    fn deref_route(&mut self, gw: &BTreeMap<Bytes, Egress>) -> Result<(), Error> {
+        if let Some(ref mut e) = self.egress {
             e.insert_value(gw).map_err(|e| {
                 format_err!(
                     "Error in dns proxy configuration: \
@@ -64,6 +496,25 @@ impl DnsProxy {
         Ok(())
     }
 }
+impl ClientSubnet {
+    pub fn inject(addr: IpAddr, prefix: u8) -> Result<ClientSubnet, Error> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err(format_err!("Prefix of client subnet {} is too long", addr));
+        }
+        Ok(ClientSubnet::Inject(addr, prefix))
+    }
+}
+
+impl From<NameServer> for NameServerGroup {
+    fn from(ns: NameServer) -> NameServerGroup {
+        NameServerGroup {
+            strategy: Strategy::Sequential,
+            servers: vec![ns],
+        }
+    }
+}
+
 impl NameServerRemote {
     pub fn new(proto: &str, addr: SocketAddr) -> NameServerRemote {
         match proto {
@@ -74,9 +525,58 @@ impl NameServerRemote {
     }
 }
 
+impl DohServer {
+    /// The uri may be a template ending in `{?dns}`, which means queries
+    /// should be sent as GET requests.
+    /// The bootstrap address is needed unless the host is an ip address.
+    pub fn new(template: &str, bootstrap: Option<IpAddr>) -> Result<DohServer, Error> {
+        let (u, get) = if template.ends_with("{?dns}") {
+            (template.trim_end_matches("{?dns}"), true)
+        } else {
+            (template, false)
+        };
+        let uri = Uri::from_str(u)?;
+        if uri.scheme_str() != Some("https") {
+            return Err(format_err!("Dns over https server {} isn't https", u));
+        }
+        let host = uri.host().ok_or_else(|| format_err!("No host in {}", u))?;
+        let ip = match bootstrap {
+            Some(i) => i,
+            None => IpAddr::from_str(host.trim_start_matches('[').trim_end_matches(']'))
+                .map_err(|_e| format_err!("Bootstrap address of {} is required", host))?,
+        };
+        let port = uri.port_u16().unwrap_or(443);
+        Ok(DohServer {
+            addr: SocketAddr::new(ip, port),
+            uri,
+            get,
+        })
+    }
+
+    pub fn host(&self) -> &str {
+        self.uri.host().unwrap_or("")
+    }
+}
+
+impl fmt::Display for NameServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self.egress {
+            Some(RefVal::Ref(ref n)) => write!(f, "{}|", BsDisp::new(n))?,
+            Some(RefVal::Val(ref e)) => write!(f, "{}|", BsDisp::new(&e.name))?,
+            None => {}
+        }
+        match self.remote {
+            NameServerRemote::Udp(a) => write!(f, "udp {}", a),
+            NameServerRemote::Tcp(a) => write!(f, "tcp {}", a),
+            NameServerRemote::Tls(a, ref n) => write!(f, "tls {} {}", a, n),
+            NameServerRemote::Https(ref s) => write!(f, "doh {}", s.uri),
+        }
+    }
+}
+
 impl fmt::Display for DnsProxy {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "Dns proxy listening on on {:?}", self.listen)?;
+        write!(f, "Dns proxy listening on {:?}", self.listen)?;
         Ok(())
     }
 }
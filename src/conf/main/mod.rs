@@ -1,13 +1,13 @@
 use failure::Error;
 use std::collections::BTreeMap;
 
-use super::decision_tree::RoutingBranch;
+use super::decision_tree::{RoutingBranch, RuleNames, Schedule};
 use super::Egress;
 use bytes::Bytes;
 use core::fmt;
 use std::fmt::Debug;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 mod dns;
 mod parse;
@@ -18,7 +18,11 @@ use self::parse::{conf_items, Item};
 pub use self::util::RefVal;
 use super::util::all_comments_or_space;
 use crate::conf;
-pub use crate::conf::main::dns::{DnsProxy, NameServer, NameServerRemote};
+pub use crate::conf::main::dns::{AnswerCheck, BlockMode, CacheConf, CacheItem, ClientSubnet};
+pub use crate::conf::main::dns::{DnsItem, DnsListen, DnsProxy, DnsTarget, DnsVerify, DnssecConf};
+pub use crate::conf::main::dns::{DnssecItem, DohServer, LocalData, NameServer, NameServerGroup};
+pub use crate::conf::main::dns::{NameServerRemote, QueryLog, Strategy, TlsIdentity};
+pub use crate::conf::main::dns::{QueryTypeRule, DEFAULT_TIMEOUT};
 pub use crate::conf::main::relay::{Relay, RelayProto};
 use crate::util::BsDisp;
 use std::sync::Arc;
@@ -32,31 +36,20 @@ pub struct MainConf {
 
 pub fn load_conf<P: AsRef<Path>>(p: P) -> Result<MainConf, Error> {
     let p = p.as_ref();
-    let f = fs::read(p.join("config"))?;
-    let (remain, is) = conf_items(&f).map_err(|e| format_err!("error parsing config: {:?}", e))?;
-    if !all_comments_or_space(remain) {
-        if let Some(l) = is.last() {
-            error!(
-                "Only some of the configurations are successfully parsed, the last one is {}",
-                l
-            );
-        } else {
-            error!("Error parsing config, nothing is successfully parsed");
-        }
-        let r: Vec<u8> = remain.iter().take(20).copied().collect();
-        error!(
-            "Failed to parse the rest of the config, starting with: \"{}\"...",
-            BsDisp::new(&r)
-        );
-    }
+    let is = read_items(p, Path::new("config"), &mut vec![])?;
     let mut rules = BTreeMap::new();
     let mut dns = None;
     let mut relays = vec![];
     let mut egresses = BTreeMap::new();
-    for it in is {
+    let mut names = RuleNames::default();
+    for (at, it) in is {
         match it {
             Item::Rule(r) => {
-                rules.insert(r.name, r.branch);
+                if let Some((_, a)) = rules.get(&r.name) {
+                    let n = BsDisp::new(&r.name);
+                    return Err(format_err!("Rule {} is defined at {} and {}", n, a, at));
+                }
+                rules.insert(r.name, (r.branch, at));
             }
             Item::Dns(x) => {
                 info!("Loaded dns proxy configuration: {}", x);
@@ -64,11 +57,29 @@ pub fn load_conf<P: AsRef<Path>>(p: P) -> Result<MainConf, Error> {
             }
             Item::Relay(x) => {
                 info!("Loaded relay configuration: {:?}", x);
+                if let Some(ref n) = x.name {
+                    if relays.iter().any(|r: &Relay| r.name.as_ref() == Some(n)) {
+                        let n = BsDisp::new(n);
+                        return Err(format_err!("There are multiple relays named {}", n));
+                    }
+                }
                 relays.push(x);
             }
             Item::Egress(x) => {
                 egresses.insert(x.name.clone(), x);
             }
+            Item::Ports(n, x) => {
+                names.ports.insert(n, x);
+            }
+            Item::Schedule(n, x) => {
+                if Schedule::is_keyword(&n) {
+                    let n = BsDisp::new(&n);
+                    return Err(format_err!("{} can't be the name of a schedule", n));
+                }
+                names.schedules.insert(n, x);
+            }
+            // already replaced by the items in the files
+            Item::Include(_) => {}
         };
     }
     if dns.is_none() && relays.is_empty() {
@@ -82,10 +93,23 @@ pub fn load_conf<P: AsRef<Path>>(p: P) -> Result<MainConf, Error> {
         check_var_name(ks)?;
         let es: Vec<&Bytes> = egresses.keys().collect();
         check_var_name(es)?;
+        let ps: Vec<&Bytes> = names.ports.keys().collect();
+        check_var_name(ps)?;
+        let ss: Vec<&Bytes> = names.schedules.keys().collect();
+        check_var_name(ss)?;
     }
-    for rule in &mut rules.values_mut() {
-        rule.insert_gateways(&egresses)?;
+    let mut resolved = BTreeMap::new();
+    for n in rules.keys() {
+        resolve_rule(n, &rules, &mut resolved, &mut vec![])?;
     }
+    for (n, rule) in &mut resolved {
+        let at = &rules[n].1;
+        let n = BsDisp::new(n);
+        rule.insert_gateways(&egresses)
+            .and_then(|_| rule.insert_names(&names))
+            .map_err(|e| format_err!("{} in rule {} at {}", e, n, at))?;
+    }
+    let rules = resolved;
     for relay in &mut relays {
         relay
             .rule
@@ -100,8 +124,12 @@ pub fn load_conf<P: AsRef<Path>>(p: P) -> Result<MainConf, Error> {
     }
     if let Some(ref mut d) = dns {
         d.deref_route(&egresses)?;
+        d.locate_files(p);
     }
     let ip_matcher = Arc::new(conf::IpMatcher::new(p)?);
+    if let Some(ref d) = dns {
+        d.check_zones(&ip_matcher)?;
+    }
     let d = Arc::new(conf::DomainMatcher::new(p)?);
     Ok(MainConf {
         dns,
@@ -111,6 +139,119 @@ pub fn load_conf<P: AsRef<Path>>(p: P) -> Result<MainConf, Error> {
     })
 }
 
+/// Items in a file and the files it includes, in order
+fn read_items(
+    dir: &Path,
+    file: &Path,
+    including: &mut Vec<PathBuf>,
+) -> Result<Vec<(Origin, Item)>, Error> {
+    if including.iter().any(|f| f == file) {
+        return Err(format_err!("{} includes itself", file.display()));
+    }
+    let f = fs::read(dir.join(file))
+        .map_err(|e| format_err!("error reading {}: {}", file.display(), e))?;
+    let (remain, is) =
+        conf_items(&f).map_err(|e| format_err!("error parsing {}: {:?}", file.display(), e))?;
+    let origin = |rest: usize| Origin {
+        file: file.into(),
+        line: f[..f.len() - rest].iter().filter(|&&c| c == b'\n').count() + 1,
+    };
+    if !all_comments_or_space(remain) {
+        if let Some((_, l)) = is.last() {
+            error!(
+                "Only some of the configurations are successfully parsed, the last one is {}",
+                l
+            );
+        } else {
+            error!("Error parsing config, nothing is successfully parsed");
+        }
+        let r: Vec<u8> = remain.iter().take(20).copied().collect();
+        error!(
+            "Failed to parse the rest of the config from {}, starting with: \"{}\"...",
+            origin(remain.len()),
+            BsDisp::new(&r)
+        );
+    }
+    including.push(file.into());
+    let mut items = vec![];
+    for (rest, it) in is {
+        let at = origin(rest);
+        if let Item::Include(ref pattern) = it {
+            let g = dir.join(pattern);
+            let files = glob::glob(&g.to_string_lossy())
+                .map_err(|e| format_err!("Bad pattern {} at {}: {}", pattern, at, e))?;
+            let mut found = false;
+            for path in files {
+                let path = path?;
+                let path = path.strip_prefix(dir).unwrap_or(&path);
+                items.extend(read_items(dir, path, including)?);
+                found = true;
+            }
+            if !found {
+                warn!("No files match {} included at {}", pattern, at);
+            }
+        } else {
+            items.push((at, it));
+        }
+    }
+    including.pop();
+    Ok(items)
+}
+
+/// where an item is written
+#[derive(Clone, Debug)]
+struct Origin {
+    file: PathBuf,
+    line: usize,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{} line {}", self.file.display(), self.line)
+    }
+}
+
+/// The rules it uses are resolved first
+fn resolve_rule(
+    name: &Bytes,
+    rules: &BTreeMap<Bytes, (RoutingBranch, Origin)>,
+    done: &mut BTreeMap<Bytes, RoutingBranch>,
+    using: &mut Vec<Bytes>,
+) -> Result<(), Error> {
+    if done.contains_key(name) {
+        return Ok(());
+    }
+    let (b, at) = &rules[name];
+    let mut b = b.clone();
+    using.push(name.clone());
+    while let Err(n) = b.insert_rules(done) {
+        if let Some(i) = using.iter().position(|u| u == &n) {
+            let cycle: Vec<String> = using[i..]
+                .iter()
+                .chain(Some(&n))
+                .map(|u| BsDisp::new(u).to_string())
+                .collect();
+            return Err(format_err!(
+                "Rules used in a cycle: {}, at {}",
+                cycle.join(" -> "),
+                at
+            ));
+        }
+        if !rules.contains_key(&n) {
+            return Err(format_err!(
+                "Rule {} is not defined, but used by rule {} at {}",
+                BsDisp::new(&n),
+                BsDisp::new(name),
+                at
+            ));
+        }
+        resolve_rule(&n, rules, done, using)?;
+    }
+    using.pop();
+    done.insert(name.clone(), b);
+    Ok(())
+}
+
 pub struct Rule {
     name: Bytes,
     branch: RoutingBranch,
@@ -143,7 +284,10 @@ impl fmt::Debug for MainConf {
 }
 
 fn check_var_name(ns: Vec<&Bytes>) -> Result<(), Error> {
-    let reserved = vec!["bind", "else", "socks5", "direct", "reset", "any", "cond"];
+    let reserved = vec![
+        "bind", "else", "socks5", "direct", "reset", "any", "cond", "not", "all", "use", "block",
+        "fake-ip",
+    ];
     for n in ns {
         for r in &reserved {
             if n == r.as_bytes() {
@@ -159,7 +303,10 @@ fn check_var_name(ns: Vec<&Bytes>) -> Result<(), Error> {
 
 #[cfg(test)]
 mod tests {
-    use super::load_conf;
+    use super::{load_conf, read_items, resolve_rule, Item};
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::Path;
     #[test]
     fn test() {
         let conf = load_conf("config");
@@ -168,4 +315,58 @@ mod tests {
             Err(x) => println!("err {:?}", x),
         };
     }
+
+    #[test]
+    fn included_rules() {
+        let dir = std::env::temp_dir().join(format!("reflow-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("rules")).unwrap();
+        let files = [
+            (
+                "config",
+                "include \"rules/*.conf\"\nrule main = any [\n  use common\n  direct\n]\n",
+            ),
+            ("rules/a.conf", "rule common = cond domain ads => reset\n"),
+            (
+                "rules/b.conf",
+                "\nrule loop_a = use loop_b\nrule loop_b = use loop_a\nrule lost = use nothing\n",
+            ),
+        ];
+        for (f, text) in &files {
+            fs::write(dir.join(f), text).unwrap();
+        }
+        let items = read_items(&dir, Path::new("config"), &mut vec![]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let mut rules = BTreeMap::new();
+        for (at, it) in items {
+            if let Item::Rule(r) = it {
+                rules.insert(r.name, (r.branch, at));
+            }
+        }
+        let at: Vec<String> = rules.values().map(|(_, at)| at.to_string()).collect();
+        assert_eq!(
+            at,
+            [
+                "rules/a.conf line 1",
+                "rules/b.conf line 2",
+                "rules/b.conf line 3",
+                "rules/b.conf line 4",
+                "config line 2"
+            ]
+        );
+        let mut done = BTreeMap::new();
+        resolve_rule(&"main".into(), &rules, &mut done, &mut vec![]).unwrap();
+        assert_eq!(
+            done[&b"main"[..]].to_string(),
+            "[\ncond domain ads => reset\ndirect\n]"
+        );
+        let e = resolve_rule(&"loop_a".into(), &rules, &mut done, &mut vec![]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Rules used in a cycle: loop_a -> loop_b -> loop_a, at rules/b.conf line 3"
+        );
+        let e = resolve_rule(&"lost".into(), &rules, &mut done, &mut vec![]).unwrap_err();
+        assert!(e
+            .to_string()
+            .ends_with("used by rule lost at rules/b.conf line 4"));
+    }
 }
@@ -1,27 +1,44 @@
 use super::super::decision_tree::read_branch;
 use super::super::decision_tree::var_name;
+use super::super::decision_tree::{read_port_set, read_schedule, PortSet, Schedule};
 use super::super::util::{line_sep, opt_line_sep};
 use super::super::EgressAddr;
 use super::Egress;
-use super::{DnsProxy, NameServer, NameServerRemote, RefVal, Relay, RelayProto, Rule};
+use super::{AnswerCheck, BlockMode, CacheConf, CacheItem, ClientSubnet, DnsItem, DnsListen};
+use super::{DnsProxy, DnssecConf, DnssecItem};
+use super::{DnsTarget, DnsVerify, DohServer, LocalData};
+use super::{NameServer, NameServerGroup, NameServerRemote, RefVal, Relay, RelayProto, Rule};
+use super::{QueryLog, QueryTypeRule, Strategy, DEFAULT_TIMEOUT};
+use crate::util::BsDisp;
 use bytes::Bytes;
-use nom::{space0, space1};
+use failure::Error;
+use nom::{digit1, rest_len, space0, space1};
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fmt::Formatter;
 use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::str;
+use std::str::FromStr;
+use std::time::Duration;
+use trust_dns::rr::RecordType;
 
 pub enum Item {
     Egress(Egress),
     Relay(Relay),
     Dns(DnsProxy),
     Rule(Rule),
+    Ports(Bytes, PortSet),
+    Schedule(Bytes, Schedule),
+    /// a pattern of files, relative to the config directory
+    Include(String),
 }
 
-named!(pub conf_items<&[u8], Vec<Item>>,
+// each with the length of the input left, to tell where it is
+named!(pub conf_items<&[u8], Vec<(usize, Item)>>,
     do_parse!(
       opt_line_sep >>
-      items: separated_list_complete!(line_sep, conf_item) >>
+      items: separated_list_complete!(line_sep, pair!(rest_len, conf_item)) >>
       ( items )
    )
 );
@@ -33,7 +50,13 @@ named!(conf_item<&[u8], Item>,
             b"egress" => map!(read_egress, |m| Item::Egress(m)) |
             b"relay" => map!(relay_conf, |x| Item::Relay(x)) |
             b"dns" => map!(dns_conf, |x| Item::Dns(x)) |
-            b"rule" => map!(rule_conf, |x| Item::Rule(x))
+            b"rule" => map!(rule_conf, |x| Item::Rule(x)) |
+            b"ports" => call!(ports_conf) |
+            b"schedule" => call!(schedule_conf) |
+            b"include" => map!(
+                map_res!(delimited!(char!('"'), take_while!(|c| c != b'"'), char!('"')), str::from_utf8),
+                |p| Item::Include(p.into())
+            )
           ) >>
         ( d )
     )
@@ -80,6 +103,24 @@ named!(rule_conf<&[u8], Rule>,
         } )
     )
 );
+named!(ports_conf<&[u8], Item>,
+    do_parse!(
+        name: var_name >>
+        equals >>
+        set: read_port_set >>
+        ( Item::Ports(name.into(), set) )
+    )
+);
+
+named!(schedule_conf<&[u8], Item>,
+    do_parse!(
+        name: var_name >>
+        equals >>
+        s: read_schedule >>
+        ( Item::Schedule(name.into(), s) )
+    )
+);
+
 named!(nameserver_value<&[u8], NameServer >,
     do_parse!(
         egress: opt!(do_parse!(
@@ -87,17 +128,83 @@ named!(nameserver_value<&[u8], NameServer >,
                          tag!("|") >>
                          ( n )
                      )) >>
-        proto: map_res!( alt!(tag!("tcp")|tag!("udp")), str::from_utf8) >>
-        space1 >>
-        a: socket_addr >>
+        remote: alt!(nameserver_plain|nameserver_tls|nameserver_https) >>
+        timeout: opt!(preceded!(
+            delimited!(space1, tag!("timeout"), space1),
+            duration
+        )) >>
         ( NameServer {
             egress: egress.map(|e| RefVal::Ref(e.into())),
-            remote: NameServerRemote::new(proto, a),
+            remote,
+            timeout: timeout.unwrap_or(DEFAULT_TIMEOUT),
         } )
     )
 );
+
+// a single nameserver, or a strategy followed by nameservers in braces
+named!(nameserver_group<&[u8], NameServerGroup>,
+    alt!(
+        do_parse!(
+            strategy: alt!(
+                value!(Strategy::Race, tag!("race")) |
+                value!(Strategy::Sequential, tag!("sequential")) |
+                value!(Strategy::Random, tag!("random"))
+            ) >>
+            space0 >> char!('{') >> opt_line_sep >>
+            servers: separated_nonempty_list!(line_sep, nameserver_value) >>
+            opt_line_sep >> char!('}') >>
+            ( NameServerGroup { strategy, servers } )
+        ) |
+        map!(nameserver_value, NameServerGroup::from)
+    )
+);
+
+// e.g. 500ms or 2s
+named!(duration<&[u8], Duration>,
+    do_parse!(
+        n: read_u32 >>
+        d: alt!(
+            value!(Duration::from_millis(u64::from(n)), tag!("ms")) |
+            value!(Duration::from_secs(u64::from(n)), tag!("s"))
+        ) >>
+        ( d )
+    )
+);
+
+named!(nameserver_plain<&[u8], NameServerRemote>,
+    do_parse!(
+        proto: map_res!( alt!(tag!("tcp")|tag!("udp")), str::from_utf8) >>
+        space1 >>
+        a: socket_addr >>
+        ( NameServerRemote::new(proto, a) )
+    )
+);
+
+named!(nameserver_tls<&[u8], NameServerRemote>,
+    do_parse!(
+        tag!("tls") >>
+        space1 >>
+        a: socket_addr >>
+        space1 >>
+        n: map_res!(host_name, str::from_utf8) >>
+        ( NameServerRemote::Tls(a, n.into()) )
+    )
+);
+
+// the uri, optionally followed by the address of the server
+named!(nameserver_https<&[u8], NameServerRemote>,
+    do_parse!(
+        tag!("doh") >>
+        space1 >>
+        u: map_res!(take_till1!(|c: u8| c.is_ascii_whitespace()), str::from_utf8) >>
+        b: opt!(preceded!(space1, ip_addr)) >>
+        s: map_res!(value!(()), |_| DohServer::new(u, b)) >>
+        ( NameServerRemote::Https(s) )
+    )
+);
 named!(relay_conf<&[u8], Relay >,
     do_parse!(
+        name: opt!(terminated!(verify!(var_name, |n: &[u8]| !n.is_empty()), space0)) >>
         char!('{') >>
         opt_line_sep >>
         conf: permutation!(
@@ -123,54 +230,338 @@ named!(relay_conf<&[u8], Relay >,
                 n: var_name >>
                 line_sep >>
                 ( n )
-            )
+            ),
+            do_parse!(
+                tag!("users") >>
+                equals >>
+                u: relay_users >>
+                line_sep >>
+                ( u )
+            )?
         ) >>
         char!('}') >>
         ( Relay {
+             name: name.map(Bytes::from),
              resolver: conf.0,
              listen: conf.1,
              rule: RefVal::Ref(conf.2.into()),
+             users: conf.3.unwrap_or_default(),
         } )
     )
 );
 
+// usernames and passwords
+named!(relay_users<&[u8], BTreeMap<Bytes, Bytes> >,
+    do_parse!(
+        char!('{') >> opt_line_sep >>
+        users: separated_nonempty_list!(line_sep, do_parse!(
+            user: verify!(var_name, |n: &[u8]| !n.is_empty()) >>
+            equals >>
+            password: is_not!(" \t\r\n") >>
+            ( (user.into(), password.into()) )
+        )) >>
+        opt_line_sep >> char!('}') >>
+        ( users.into_iter().collect() )
+    )
+);
+
 named!(dns_conf<&[u8], DnsProxy >,
     do_parse!(
         char!('{') >> opt_line_sep >>
-        conf: map_res!( permutation!(
+        conf: map_res!(
+            many1!(terminated!(dns_item, line_sep)),
+            DnsProxy::new1
+        ) >>
+        char!('}') >>
+        ( conf )
+    )
+);
+
+named!(dns_item<&[u8], DnsItem>,
+    alt!(
+        do_parse!(
+            tag!("listen") >>
+            equals >>
+            v: alt!(
+                preceded!(terminated!(tag!("udp"), space1), socket_addr) =>
+                    { DnsListen::Udp } |
+                preceded!(terminated!(tag!("tcp"), space1), socket_addr) =>
+                    { DnsListen::Tcp } |
+                preceded!(terminated!(tag!("tls"), space1), socket_addr) =>
+                    { DnsListen::Tls } |
+                preceded!(terminated!(tag!("https"), space1), socket_addr) =>
+                    { DnsListen::Https }
+            ) >>
+            ( DnsItem::Listen(v) )
+        ) |
+        do_parse!(
+            tag!("certificate") >>
+            equals >>
+            p: file_path >>
+            ( DnsItem::Certificate(p) )
+        ) |
+        do_parse!(
+            tag!("key") >>
+            equals >>
+            p: file_path >>
+            ( DnsItem::Key(p) )
+        ) |
+        do_parse!(
+            tag!("cache") >> equals >> char!('{') >> opt_line_sep >>
+            c: map_res!(
+                separated_list!(line_sep, alt!(
+                    preceded!(terminated!(tag!("file"), equals), file_path) =>
+                        { CacheItem::File } |
+                    number_entry => { |(k, v)| CacheItem::Number(k, v) }
+                )),
+                CacheConf::new
+            ) >>
+            opt_line_sep >> char!('}') >>
+            ( DnsItem::Cache(c) )
+        ) |
+        do_parse!(
+            tag!("records") >> equals >> char!('{') >> opt_line_sep >>
+            r: separated_nonempty_list!(line_sep, local_record) >>
+            opt_line_sep >> char!('}') >>
+            ( DnsItem::Records(r) )
+        ) |
+        do_parse!(
+            tag!("hosts") >>
+            equals >>
+            p: file_path >>
+            ( DnsItem::Hosts(p) )
+        ) |
+        do_parse!(
+            tag!("local-ttl") >>
+            equals >>
+            t: read_u32 >>
+            ( DnsItem::LocalTtl(t) )
+        ) |
+        do_parse!(
+            tag!("verify") >> equals >> char!('{') >> opt_line_sep >>
+            v: separated_nonempty_list!(line_sep, verify_entry) >>
+            opt_line_sep >> char!('}') >>
+            ( DnsItem::Verify(v) )
+        ) |
+        do_parse!(
+            tag!("client-subnet") >> equals >> char!('{') >> opt_line_sep >>
+            v: separated_nonempty_list!(line_sep, client_subnet_entry) >>
+            opt_line_sep >> char!('}') >>
+            ( DnsItem::ClientSubnet(v) )
+        ) |
+        do_parse!(
+            tag!("fake-ip-pool") >>
+            equals >>
+            a: map_res!(ip_addr, |a| match a {
+                IpAddr::V4(a) => Ok(a),
+                IpAddr::V6(_) => Err(format_err!("Only ipv4 fake-ip pools are supported")),
+            }) >>
+            char!('/') >>
+            p: verify!(read_u32, |p| p > 0 && p < 31) >>
+            ( DnsItem::FakeIpPool(a, p as u8) )
+        ) |
+        do_parse!(
+            tag!("learn-domains") >>
+            equals >>
+            n: verify!(read_u32, |n| n > 0) >>
+            ( DnsItem::LearnDomains(n as usize) )
+        ) |
+        do_parse!(
+            tag!("query-types") >> equals >> char!('{') >> opt_line_sep >>
+            r: separated_nonempty_list!(line_sep, query_type_rule) >>
+            opt_line_sep >> char!('}') >>
+            ( DnsItem::QueryTypes(r) )
+        ) |
+        do_parse!(
+            tag!("query-log") >>
+            equals >>
+            l: alt!(
+                value!(QueryLog::Stderr, tag!("stderr")) |
+                map!(file_path, QueryLog::File)
+            ) >>
+            ( DnsItem::QueryLog(l) )
+        ) |
+        do_parse!(
+            tag!("dnssec") >> equals >> char!('{') >> opt_line_sep >>
+            d: map_res!(
+                separated_nonempty_list!(line_sep, alt!(
+                    preceded!(terminated!(tag!("validate"), equals), separated_nonempty_list!(
+                        space1,
+                        verify!(var_name, |n: &[u8]| !n.is_empty())
+                    )) => { |z: Vec<&[u8]>| DnssecItem::Validate(
+                        z.into_iter().map(Bytes::from).collect()
+                    ) } |
+                    preceded!(terminated!(tag!("trust-anchor"), equals), file_path) =>
+                        { DnssecItem::TrustAnchor }
+                )),
+                DnssecConf::new
+            ) >>
+            opt_line_sep >> char!('}') >>
+            ( DnsItem::Dnssec(d) )
+        ) |
+        do_parse!(
+            tag!("stats-interval") >>
+            equals >>
+            d: verify!(duration, |d: Duration| d.as_millis() > 0) >>
+            ( DnsItem::StatsInterval(d) )
+        ) |
+        do_parse!(
+            tag!("forward") >>
+            client: opt!(preceded!(
+                delimited!(space1, tag!("for"), space1),
+                var_name
+            )) >>
+            equals >> char!('{') >> opt_line_sep >>
+            m: do_parse!(
+                 entries: separated_nonempty_list!(line_sep, read_map_entry) >>
+                ( entries )
+            ) >>
+            opt_line_sep >> char!('}') >>
+            ( match client {
+                Some(c) => DnsItem::ClientForward(c.into(), m),
+                None => DnsItem::Forward(m),
+            } )
+        )
+    )
+);
+
+named!(number_entry<&[u8], (&[u8], u32)>,
+    do_parse!(
+        k: var_name >>
+        equals >>
+        v: read_u32 >>
+        ( (k, v) )
+    )
+);
+
+named!(read_u32<&[u8], u32>,
+    map_res!(map_res!(digit1, str::from_utf8), str::FromStr::from_str)
+);
+
+named!(local_record<&[u8], (String, LocalData)>,
+    do_parse!(
+        n: map_res!(host_name, str::from_utf8) >>
+        space0 >> tag!("=>") >> space0 >>
+        d: alt!(
+            map!(ip_addr, LocalData::Addr) |
             do_parse!(
-                tag!("listen") >>
-                equals >>
-                tag!("udp") >>
+                tag!("cname") >>
                 space1 >>
-                v: socket_addr >>
-                line_sep >>
-                ( v   )
-            ),
-            do_parse!(
-                tag!("forward") >> equals >> char!('{') >> opt_line_sep >>
-                m: do_parse!(
-                     entries: separated_nonempty_list!(line_sep, read_map_entry) >>
-                    ( entries )
-                ) >>
-                opt_line_sep >> char!('}') >> line_sep >>
-                ( m )
+                c: map_res!(host_name, str::from_utf8) >>
+                ( LocalData::Cname(c.into()) )
             )
-        ), |(l, f)| DnsProxy::new1(l, f) ) >>
-        char!('}') >>
-        ( conf )
+        ) >>
+        ( (n.into(), d) )
     )
 );
 
-named!(read_map_entry<&[u8], (Bytes, NameServer)>,
+named!(read_map_entry<&[u8], (Bytes, DnsTarget)>,
     do_parse!(
         keyword: var_name >>
         space0 >> tag!("=>") >> space0 >>
-        value: nameserver_value >>
+        value: dns_target >>
         ( (keyword.into(), value) )
     )
 );
 
+named!(dns_target<&[u8], DnsTarget>,
+    alt!(
+        dns_block |
+        value!(DnsTarget::FakeIp, verify!(var_name, |n: &[u8]| n == b"fake-ip")) |
+        map!(nameserver_group, DnsTarget::Forward)
+    )
+);
+
+// e.g. `AAAA cn => block empty`
+named!(query_type_rule<&[u8], QueryTypeRule>,
+    do_parse!(
+        query_type: map_res!(
+            map_res!(take_while1!(|c: u8| c.is_ascii_alphanumeric()), str::from_utf8),
+            record_type
+        ) >>
+        zone: opt!(preceded!(space1, verify!(var_name, |n: &[u8]| !n.is_empty()))) >>
+        space0 >> tag!("=>") >> space0 >>
+        target: dns_target >>
+        ( QueryTypeRule {
+            query_type,
+            zone: zone.map(Bytes::from),
+            target,
+        } )
+    )
+);
+
+/// a record type, also the unknown ones as TYPE65, RFC 3597
+fn record_type(s: &str) -> Result<RecordType, Error> {
+    let t = match s {
+        "SVCB" => RecordType::Unknown(64),
+        "HTTPS" => RecordType::Unknown(65),
+        _ => match s.strip_prefix("TYPE") {
+            Some(n) => RecordType::from(n.parse::<u16>()?),
+            None => RecordType::from_str(s)?,
+        },
+    };
+    Ok(t)
+}
+
+// e.g. `cn => expect cn retry proxy|tcp 8.8.8.8:53`
+named!(verify_entry<&[u8], (Bytes, DnsVerify)>,
+    do_parse!(
+        zone: var_name >>
+        space0 >> tag!("=>") >> space0 >>
+        check: alt!(
+            preceded!(terminated!(tag!("expect"), space1), var_name) =>
+                { |z: &[u8]| AnswerCheck::Expect(z.into()) } |
+            preceded!(terminated!(tag!("reject"), space1), var_name) =>
+                { |z: &[u8]| AnswerCheck::Reject(z.into()) }
+        ) >>
+        space1 >> tag!("retry") >> space1 >>
+        retry: nameserver_group >>
+        ( (zone.into(), DnsVerify { check, retry }) )
+    )
+);
+
+// e.g. `else => 203.0.113.0/24`
+named!(client_subnet_entry<&[u8], (Bytes, ClientSubnet)>,
+    do_parse!(
+        zone: var_name >>
+        space0 >> tag!("=>") >> space0 >>
+        c: alt!(
+            value!(ClientSubnet::Strip, tag!("strip")) |
+            value!(ClientSubnet::Pass, tag!("pass")) |
+            map_res!(
+                separated_pair!(ip_addr, char!('/'), map_res!(
+                    map_res!(digit1, str::from_utf8), str::FromStr::from_str
+                )),
+                |(a, p)| ClientSubnet::inject(a, p)
+            )
+        ) >>
+        ( (zone.into(), c) )
+    )
+);
+
+named!(dns_block<&[u8], DnsTarget>,
+    do_parse!(
+        verify!(var_name, |n: &[u8]| n == b"block") >>
+        m: opt!(preceded!(space1, alt!(
+            value!(BlockMode::NxDomain, tag!("nxdomain")) |
+            value!(BlockMode::Zero, tag!("zero")) |
+            value!(BlockMode::Empty, tag!("empty"))
+        ))) >>
+        ( DnsTarget::Block(m.unwrap_or(BlockMode::NxDomain)) )
+    )
+);
+
+named!(file_path<&[u8], PathBuf>,
+    map!(
+        map_res!(take_till1!(|c: u8| c.is_ascii_whitespace()), str::from_utf8),
+        PathBuf::from
+    )
+);
+
+named!(host_name<&[u8], &[u8]>,
+    take_while1!(|c: u8| c.is_ascii_alphanumeric() || c == b'.' || c == b'-')
+);
+
 named!(ip_addr<&[u8], IpAddr>,
   map_res!(map_res!(
      take_while!( |c: u8| -> bool {
@@ -189,9 +580,273 @@ named!(socket_addr<&[u8], SocketAddr>,
 
 #[cfg(test)]
 mod tests {
-    use super::conf_items;
+    use super::DEFAULT_TIMEOUT;
+    use super::{conf_items, dns_conf, nameserver_value, relay_conf};
+    use crate::conf::{AnswerCheck, BlockMode, ClientSubnet, DnsListen, DnsProxy, DnsTarget};
+    use crate::conf::{IpMatcher, NameServerRemote, QueryLog, Strategy};
     use bytes::Bytes;
     use std::fs;
+    use std::net::SocketAddr;
+    use std::path::Path;
+    use std::time::Duration;
+    use trust_dns::rr::RecordType;
+
+    #[test]
+    fn named_relay_with_users() {
+        let c = b"lan-socks {
+            listen = socks5 127.0.0.1:1080
+            users = {
+              alice = s3cr3t!
+              ci-bots = token
+            }
+            rule = main
+        }
+";
+        let (_, r) = relay_conf(c).unwrap();
+        assert_eq!(r.name, Some("lan-socks".into()));
+        assert_eq!(r.users.len(), 2);
+        assert_eq!(r.users[&Bytes::from("alice")], "s3cr3t!");
+        let (_, r) = relay_conf(b"{\n listen = socks5 127.0.0.1:1080\n rule = main\n}\n").unwrap();
+        assert!(r.name.is_none() && r.users.is_empty());
+    }
+
+    #[test]
+    fn encrypted_nameservers() {
+        let (_, ns) = nameserver_value(b"tls 1.1.1.1:853 cloudflare-dns.com\n").unwrap();
+        match ns.remote {
+            NameServerRemote::Tls(a, n) => {
+                assert_eq!(a, "1.1.1.1:853".parse::<SocketAddr>().unwrap());
+                assert_eq!(n, "cloudflare-dns.com");
+            }
+            x => panic!("wrong remote {:?}", x),
+        }
+        let (_, ns) =
+            nameserver_value(b"proxy|doh https://dns.google/dns-query{?dns} 8.8.4.4\n").unwrap();
+        assert_eq!(ns.egress.and_then(|e| e.get_ref()), Some("proxy".into()));
+        match ns.remote {
+            NameServerRemote::Https(s) => {
+                assert_eq!(s.addr, "8.8.4.4:443".parse::<SocketAddr>().unwrap());
+                assert_eq!(s.host(), "dns.google");
+                assert!(s.get);
+            }
+            x => panic!("wrong remote {:?}", x),
+        }
+        let (_, ns) = nameserver_value(b"doh https://1.1.1.1:8443/dns-query\n").unwrap();
+        match ns.remote {
+            NameServerRemote::Https(s) => {
+                assert_eq!(s.addr, "1.1.1.1:8443".parse::<SocketAddr>().unwrap());
+                assert!(!s.get);
+            }
+            x => panic!("wrong remote {:?}", x),
+        }
+        // the host needs to be resolved without a bootstrap address
+        assert!(nameserver_value(b"doh https://dns.google/dns-query\n").is_err());
+    }
+
+    #[test]
+    fn dns_listen_both() {
+        let c = b"{
+            listen = udp 127.0.0.1:53
+            listen = tcp 127.0.0.1:53
+            cache = {
+              size = 100
+              min-ttl = 5
+              file = dns-cache
+              prefetch = 3
+            }
+            forward = {
+              else => udp 8.8.8.8:53
+            }
+        }";
+        let (_, d) = dns_conf(c).unwrap();
+        match d.listen.as_slice() {
+            [DnsListen::Udp(_), DnsListen::Tcp(_)] => {}
+            x => panic!("wrong listen addresses {:?}", x),
+        }
+        let c = d.cache.unwrap();
+        assert_eq!((c.size, c.min_ttl, c.max_ttl), (100, 5, 86400));
+        assert_eq!(c.file, Some("dns-cache".into()));
+        assert_eq!((c.save_interval, c.prefetch), (300, 3));
+    }
+
+    /// a dns proxy with the items, listening and forwarding somewhere
+    fn dns_with(items: &str) -> DnsProxy {
+        let c = format!(
+            "{{\nlisten = udp 127.0.0.1:53\nforward = {{\nelse => udp 1.1.1.1:53\n}}\n{}}}",
+            items
+        );
+        let (_, d) = dns_conf(c.as_bytes()).unwrap();
+        d
+    }
+
+    #[test]
+    fn dns_local_records() {
+        let d = dns_with(
+            "records = {
+              router.lan => 192.168.1.1
+              www.lan => cname router.lan
+            }
+            hosts = /etc/hosts
+            local-ttl = 60
+",
+        );
+        assert_eq!(d.records.len(), 2);
+        assert_eq!(d.hosts.len(), 1);
+        assert_eq!(d.local_ttl, 60);
+    }
+
+    #[test]
+    fn dns_forward_and_block() {
+        let d = dns_with(
+            "forward = {
+              ads => block
+              trackers => block zero
+              lan => udp 192.168.1.1:53 timeout 500ms
+              cdn => fake-ip
+              else => race {
+                udp 8.8.8.8:53 timeout 2s
+                proxy|tls 1.1.1.1:853 cloudflare-dns.com
+              }
+            }
+",
+        );
+        let mode = |z: &str| match d.forward[&Bytes::from(z)] {
+            DnsTarget::Block(m) => m,
+            ref x => panic!("{} not blocked: {:?}", z, x),
+        };
+        assert_eq!(mode("ads"), BlockMode::NxDomain);
+        assert_eq!(mode("trackers"), BlockMode::Zero);
+        assert!(d.uses_fake_ip());
+        match d.default {
+            DnsTarget::Forward(ref g) => {
+                assert_eq!(g.strategy, Strategy::Race);
+                let ts: Vec<_> = g.servers.iter().map(|s| s.timeout).collect();
+                assert_eq!(ts, vec![Duration::from_secs(2), DEFAULT_TIMEOUT]);
+            }
+            ref x => panic!("wrong default {:?}", x),
+        }
+        match d.forward[&Bytes::from("lan")] {
+            DnsTarget::Forward(ref g) => {
+                assert_eq!(g.servers[0].timeout, Duration::from_millis(500))
+            }
+            ref x => panic!("wrong lan nameserver {:?}", x),
+        }
+    }
+
+    #[test]
+    fn dns_client_forward() {
+        let mut d = dns_with(
+            "forward for uccu = {
+              adult => block
+            }
+            forward for cs = {
+              ads => udp 8.8.8.8:53
+              else => udp 1.1.1.1:53
+            }
+",
+        );
+        let kids = &d.client_forward[&Bytes::from("uccu")];
+        assert!(matches!(kids[&Bytes::from("adult")], DnsTarget::Block(_)));
+        assert_eq!(d.client_forward[&Bytes::from("cs")].len(), 2);
+        let ip = IpMatcher::new(Path::new("test/conf.d")).unwrap();
+        assert!(d.check_zones(&ip).is_ok());
+        let servers = d.client_forward.remove(&Bytes::from("cs")).unwrap();
+        d.client_forward.insert("servers".into(), servers);
+        let e = d.check_zones(&ip).unwrap_err();
+        assert_eq!(e.to_string(), "IP zone servers is not defined");
+    }
+
+    #[test]
+    fn dns_verify() {
+        let mut d = dns_with(
+            "verify = {
+              else => reject bogus retry proxy|tcp 8.8.8.8:53
+            }
+",
+        );
+        let v = &d.verify[&Bytes::from("else")];
+        assert!(matches!(v.check, AnswerCheck::Reject(ref z) if z == "bogus"));
+        assert!(v.retry.servers[0].egress.is_some());
+        let ip = IpMatcher::new(Path::new("test/conf.d")).unwrap();
+        let e = d.check_zones(&ip).unwrap_err();
+        assert_eq!(e.to_string(), "IP zone bogus is not defined");
+        for v in d.verify.values_mut() {
+            v.check = AnswerCheck::Expect("cs".into());
+        }
+        assert!(d.check_zones(&ip).is_ok());
+    }
+
+    #[test]
+    fn dns_client_subnet() {
+        let d = dns_with(
+            "client-subnet = {
+              lan => strip
+              else => 203.0.113.7/24
+            }
+",
+        );
+        let c: Vec<_> = d.client_subnet.values().cloned().collect();
+        let a = "203.0.113.7".parse().unwrap();
+        assert_eq!(c, vec![ClientSubnet::Inject(a, 24), ClientSubnet::Strip]);
+    }
+
+    #[test]
+    fn dns_query_types() {
+        let mut d = dns_with(
+            "query-types = {
+              AAAA cn => block empty
+              HTTPS => block empty
+              TYPE64 => block empty
+              PTR uccu => udp 192.168.1.1:53
+            }
+",
+        );
+        let types: Vec<_> = d.query_types.iter().map(|r| r.query_type).collect();
+        let (https, svcb) = (RecordType::Unknown(65), RecordType::Unknown(64));
+        assert_eq!(types, [RecordType::AAAA, https, svcb, RecordType::PTR]);
+        assert_eq!(d.query_types[0].zone, Some("cn".into()));
+        assert!(matches!(
+            d.query_types[0].target,
+            DnsTarget::Block(BlockMode::Empty)
+        ));
+        assert!(d.query_types[1].zone.is_none());
+        // only the zones of reverse lookups are of addresses
+        let ip = IpMatcher::new(Path::new("test/conf.d")).unwrap();
+        assert!(d.check_zones(&ip).is_ok());
+        d.query_types[3].zone = Some("lan".into());
+        let e = d.check_zones(&ip).unwrap_err();
+        assert_eq!(e.to_string(), "IP zone lan is not defined");
+    }
+
+    #[test]
+    fn dns_logs_and_learning() {
+        let d = dns_with(
+            "learn-domains = 4096
+            query-log = queries.log
+            stats-interval = 600s
+",
+        );
+        assert_eq!(d.learn_domains, Some(4096));
+        assert_eq!(d.query_log, Some(QueryLog::File("queries.log".into())));
+        assert_eq!(d.stats_interval, Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn dns_dnssec() {
+        let d = dns_with(
+            "dnssec = {
+              validate = abroad else
+              trust-anchor = root-anchors.txt
+            }
+",
+        );
+        let dnssec = d.dnssec.as_ref().unwrap();
+        assert_eq!(
+            dnssec.zones,
+            vec![Bytes::from("abroad"), Bytes::from("else")]
+        );
+        assert_eq!(dnssec.trust_anchor, Some("root-anchors.txt".into()));
+    }
 
     #[allow(dead_code)]
     fn test() {
@@ -214,6 +869,9 @@ impl fmt::Debug for Item {
             Item::Relay(x) => write!(f, "Item {:?}", x),
             Item::Dns(x) => write!(f, "Item {:?}", x),
             Item::Rule(x) => write!(f, "Item {:?}", x),
+            Item::Ports(n, x) => write!(f, "Item ports {:?} = {}", n, x),
+            Item::Schedule(n, x) => write!(f, "Item schedule {:?} = {}", n, x),
+            Item::Include(p) => write!(f, "Item include {:?}", p),
         }
     }
 }
@@ -225,6 +883,9 @@ impl fmt::Display for Item {
             Item::Relay(x) => write!(f, "{:?}", x),
             Item::Dns(x) => write!(f, "{}", x),
             Item::Rule(x) => write!(f, "{}", x),
+            Item::Ports(n, x) => write!(f, "ports {} = {}", BsDisp::new(n), x),
+            Item::Schedule(n, x) => write!(f, "schedule {} = {}", BsDisp::new(n), x),
+            Item::Include(p) => write!(f, "include \"{}\"", p),
         }
     }
 }
@@ -1,28 +1,40 @@
-use crate::conf::main::dns::NameServer;
+use crate::conf::main::dns::{NameServer, DEFAULT_TIMEOUT};
 use crate::conf::main::util::RefVal;
 use crate::conf::NameServerRemote;
 use crate::conf::RoutingBranch;
+use crate::util::BsDisp;
+use bytes::Bytes;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::net::IpAddr;
 use std::net::SocketAddr;
 
 pub struct Relay {
+    /// for rules to tell relays apart
+    pub name: Option<Bytes>,
     pub resolver: Option<NameServer>,
     pub listen: RelayProto,
     pub rule: RefVal<RoutingBranch>,
+    /// passwords by usernames, authentication is required if there are any
+    pub users: BTreeMap<Bytes, Bytes>,
 }
 impl fmt::Display for Relay {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "Relay on {:?}", self.listen)?;
+        write!(f, "Relay")?;
+        if let Some(ref n) = self.name {
+            write!(f, " {}", BsDisp::new(n))?;
+        }
+        write!(f, " on {:?}", self.listen)?;
         Ok(())
     }
 }
 
 impl fmt::Debug for Relay {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "Relay on {:?},", self.listen)?;
+        write!(f, "Relay {:?} on {:?},", self.name, self.listen)?;
         write!(f, "resolver: {:?},", self.resolver)?;
         write!(f, "rule: {:?},", self.rule)?;
+        write!(f, "users: {:?},", self.users.keys().collect::<Vec<_>>())?;
         Ok(())
     }
 }
@@ -38,6 +50,7 @@ impl Relay {
             None => NameServer {
                 egress: None,
                 remote: NameServerRemote::Udp(SocketAddr::new(IpAddr::from([8, 8, 8, 8]), 53)),
+                timeout: DEFAULT_TIMEOUT,
             },
         }
     }
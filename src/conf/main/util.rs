@@ -1,5 +1,7 @@
+use crate::util::BsDisp;
 use bytes::Bytes;
 use std::collections::BTreeMap;
+use std::fmt;
 use std::mem;
 
 #[derive(Clone, Debug)]
@@ -23,6 +25,15 @@ impl<T: Clone> RefVal<T> {
         Ok(())
     }
 }
+impl<T: fmt::Display> fmt::Display for RefVal<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            RefVal::Ref(n) => write!(f, "{}", BsDisp::new(n)),
+            RefVal::Val(v) => write!(f, "{}", v),
+        }
+    }
+}
+
 impl<T> RefVal<T> {
     pub fn val(&self) -> &T {
         match self {
@@ -6,7 +6,9 @@ mod util;
 pub use self::decision_tree::RoutingAction;
 pub use self::decision_tree::RoutingBranch;
 pub use self::main::{load_conf, MainConf, Relay, RelayProto};
-pub use self::main::{DnsProxy, NameServer, NameServerRemote};
+pub use self::main::{AnswerCheck, BlockMode, CacheConf, DnsTarget, LocalData, TlsIdentity};
+pub use self::main::{ClientSubnet, NameServerGroup, QueryLog, Strategy};
+pub use self::main::{DnsListen, DnsProxy, DnssecConf, DohServer, NameServer, NameServerRemote};
 pub use self::prefix_match::domain_name::DomainMatcher;
 pub use self::prefix_match::ip_addr::IpMatcher;
 use crate::util::BsDisp;
@@ -11,6 +11,7 @@ use super::util::lines_without_comments;
 use crate::util::BsDisp;
 use bytes::Bytes;
 use failure::Error;
+use std::collections::BTreeSet;
 use std::net::IpAddr;
 use std::net::Ipv6Addr;
 use std::path;
@@ -20,6 +21,8 @@ use treebitmap::IpLookupTable;
 pub struct IpMatcher {
     ip4_table: IpLookupTable<Ipv4Addr, Bytes>,
     ip6_table: IpLookupTable<Ipv6Addr, Bytes>,
+    /// including those with no addresses
+    zones: BTreeSet<Bytes>,
 }
 
 impl IpMatcher {
@@ -30,6 +33,7 @@ impl IpMatcher {
 
         let mut i4table = IpLookupTable::new();
         let mut i6table = IpLookupTable::new();
+        let zones = regions.keys().cloned().collect();
         for (region, conf) in regions {
             for entry in conf.iter() {
                 let contents = fs::read(entry.path())?;
@@ -51,6 +55,7 @@ impl IpMatcher {
         Ok(IpMatcher {
             ip4_table: i4table,
             ip6_table: i6table,
+            zones,
         })
     }
 
@@ -62,6 +67,10 @@ impl IpMatcher {
         }
     }
 
+    pub fn has_zone(&self, zone: &[u8]) -> bool {
+        self.zones.contains(zone)
+    }
+
     pub fn match_ip(&self, ip: IpAddr) -> Option<Bytes> {
         match ip {
             IpAddr::V4(i) => self.ip4_table.longest_match(i).map(|(_i, _m, v)| v.clone()),
@@ -25,12 +25,7 @@ pub mod util;
 
 use crate::conf::load_conf;
 use crate::relay::run_with_conf;
-
-use futures::task::Context;
-
-use futures::Future;
-use std::pin::Pin;
-use std::task::Poll;
+use crate::resolver::DomainHints;
 
 pub fn run() -> Result<(), i32> {
     env_logger::Builder::from_default_env()
@@ -53,31 +48,51 @@ pub fn run() -> Result<(), i32> {
     let mut rt = tokio::runtime::Runtime::new().unwrap();
     rt.block_on(async move {
         let dm = conf.domain_matcher.clone();
+        let im = conf.ip_matcher.clone();
         let dns = conf.dns.clone();
+        let hints = DomainHints::new(dns.as_ref());
         for r in conf.relays {
             info!("Starting {}", r);
 
-            if let Err(e) = run_with_conf(r, conf.domain_matcher.clone(), conf.ip_matcher.clone()) {
+            let d = conf.domain_matcher.clone();
+            if let Err(e) = run_with_conf(r, d, conf.ip_matcher.clone(), hints.clone()) {
                 error!("Relay error: {:?}", e);
             }
         }
+        let mut dns_handler = None;
         if let Some(dns) = dns {
             info!("Starting dns proxy");
-            let ds = resolver::serve(dns, dm);
-            if let Err(e) = ds {
-                error!("Dns server error: {:?}", e);
+            match resolver::serve(dns, dm, im, hints) {
+                Ok(h) => dns_handler = Some(h),
+                Err(e) => error!("Dns server error: {:?}", e),
             }
         }
-        Forever().await
+        shutdown_signal().await;
+        info!("Shutting down");
+        if let Some(h) = dns_handler {
+            h.save_cache();
+        }
     });
     Ok(())
 }
 
-struct Forever();
-impl Future for Forever {
-    type Output = ();
-
-    fn poll(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Self::Output> {
-        Poll::Pending
+/// Ctrl-C, or SIGTERM on unix
+async fn shutdown_signal() {
+    let ctrl_c = Box::pin(tokio::signal::ctrl_c());
+    #[cfg(unix)]
+    {
+        use futures::future::select;
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                let term = Box::pin(async move { term.recv().await });
+                select(ctrl_c, term).await;
+                return;
+            }
+            Err(e) => warn!("Can't listen for SIGTERM: {}", e),
+        }
+    }
+    if let Err(e) = ctrl_c.await {
+        error!("Can't listen for Ctrl-C: {}", e);
     }
 }
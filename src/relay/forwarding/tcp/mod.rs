@@ -13,58 +13,100 @@ use crate::conf::EgressAddr;
 use crate::conf::RoutingAction;
 use crate::relay::inspect::parse_first_packet;
 use crate::relay::inspect::TcpProtocol;
+use crate::relay::route::TcpClient;
 use crate::relay::TcpRouter;
+use crate::resolver::AsyncResolver;
 use bytes::Bytes;
 use std::net;
 use std::net::IpAddr;
 use std::sync::Arc;
 
-use asocks5::connect_socks_socket_addr;
+use asocks5::socks::Address;
+use asocks5::{connect_socks_socket_addr, connect_socks_to};
 
 use tokio::io::split;
 use tokio::prelude::*;
 
 pub async fn handle_incoming_tcp(
     mut client_stream: TcpStream,
+    client: TcpClient,
     a: SocketAddr,
     router: Arc<TcpRouter>,
+    resolver: Arc<AsyncResolver>,
 ) -> Result<(), Error> {
     let tcp = parse_first_packet(&mut client_stream).await?;
-    if let Some(r) = router.route(a, &tcp.protocol) {
+    let fake = router.fake_domain(a);
+    if let Some(r) = router.route(&client, a, fake.as_deref(), &tcp.protocol) {
+        let target = Target {
+            addr: a,
+            fake_domain: fake,
+            resolver,
+        };
         carry_out(
             tcp.bytes.freeze(),
-            a,
+            target,
             r.clone(),
             client_stream,
             tcp.protocol,
         )
         .await?;
     } else {
-        let p = client_stream.peer_addr();
         return Err(format_err!(
             "No matching rule for protocol {:?} from client {:?} to addr {:?}",
             &tcp.protocol,
-            p,
+            client.addr,
             a
         ));
     }
     Ok(())
 }
 
+/// Where the client connects to
+struct Target {
+    addr: SocketAddr,
+    /// The real destination if the address is fake
+    fake_domain: Option<String>,
+    resolver: Arc<AsyncResolver>,
+}
+
+impl Target {
+    /// the real address to connect to
+    async fn resolve(&self) -> Result<SocketAddr, Error> {
+        let d = match self.fake_domain {
+            Some(ref d) => d,
+            None => return Ok(self.addr),
+        };
+        let ip = self
+            .resolver
+            .resolve(d)
+            .await
+            .map_err(|e| format_err!("Error resolving {}: {}", d, e))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| format_err!("No address found for domain {}", d))?;
+        Ok(SocketAddr::new(ip, self.addr.port()))
+    }
+}
+
 async fn carry_out(
     data: Bytes,
-    a: SocketAddr,
+    target: Target,
     r: RoutingAction,
     mut client_stream: TcpStream,
     pr: TcpProtocol,
 ) -> Result<(), Error> {
+    let a = target.addr;
     let mut s = match r {
         RoutingAction::Reset => return Ok(()),
-        RoutingAction::Direct => tokio::net::TcpStream::connect(&a)
-            .await
-            .map_err(|e| format_err!("Error making direct {:?} connection to {:?}: {}", &pr, a, e)),
+        RoutingAction::Direct => {
+            let a = target.resolve().await?;
+            tokio::net::TcpStream::connect(&a).await.map_err(|e| {
+                format_err!("Error making direct {:?} connection to {:?}: {}", &pr, a, e)
+            })
+        }
         RoutingAction::Named(ref g) => match g.val().addr() {
             EgressAddr::From(ip) => {
+                let a = target.resolve().await?;
                 let x = bind_tcp_socket(ip)?;
                 tokio::net::TcpStream::connect_std(x, &a)
                     .await
@@ -80,7 +122,14 @@ async fn carry_out(
             }
             EgressAddr::Socks5(x) => {
                 let mut s = TcpStream::connect(&x).await?;
-                connect_socks_socket_addr(&mut s, a).await?;
+                match target.fake_domain {
+                    Some(d) => {
+                        connect_socks_to(&mut s, Address::DomainNameAddress(d, a.port())).await?;
+                    }
+                    None => {
+                        connect_socks_socket_addr(&mut s, a).await?;
+                    }
+                }
                 Ok(s)
             }
         },
@@ -4,7 +4,7 @@ use bytes::BytesMut;
 mod codec;
 mod parse;
 pub use self::codec::parse_first_packet;
-pub use self::parse::TcpProtocol;
+pub use self::parse::{HttpInfo, TcpProtocol, TlsVersion, TlsWithSni};
 
 pub struct InspectedTcp {
     /// bytes read
@@ -4,7 +4,8 @@ use httparse;
 
 mod tls;
 
-use self::tls::{parse_tls_sni, TlsWithSni};
+use self::tls::parse_tls_sni;
+pub use self::tls::{TlsVersion, TlsWithSni};
 
 #[derive(Clone, Debug)]
 pub enum TcpProtocol {
@@ -30,7 +31,7 @@ impl TcpProtocol {
         use self::TcpProtocol::*;
         match &self {
             PlainHttp(x) => Some(&x.host),
-            Tls(x) => Some(&x.sni),
+            Tls(x) => x.sni.as_ref().map(Bytes::as_ref),
             _ => None,
         }
     }
@@ -38,13 +39,17 @@ impl TcpProtocol {
 
 #[derive(Clone, Debug)]
 pub struct HttpInfo {
+    pub method: Bytes,
+    pub path: Bytes,
     host: Bytes,
     pub user_agent: Option<Bytes>,
 }
 
 impl HttpInfo {
-    pub fn new(h: &[u8], ua: Option<&[u8]>) -> HttpInfo {
+    pub fn new(method: &[u8], path: &[u8], h: &[u8], ua: Option<&[u8]>) -> HttpInfo {
         HttpInfo {
+            method: BytesMut::from(method).freeze(),
+            path: BytesMut::from(path).freeze(),
             host: BytesMut::from(h).freeze(),
             user_agent: ua.map(|b| BytesMut::from(b).freeze()),
         }
@@ -78,11 +83,14 @@ fn guess_http(bytes: &BytesMut) -> Option<HttpInfo> {
             ua = Some(header.value);
         }
     }
+    // method and path are known once the request line is parsed
+    let method = req.method?.as_bytes();
+    let path = req.path?.as_bytes();
     match host {
         None => None,
         Some(h) => {
             if ua.is_some() || status.is_complete() {
-                Some(HttpInfo::new(h, ua))
+                Some(HttpInfo::new(method, path, h, ua))
             } else {
                 None
             }
@@ -97,3 +105,29 @@ fn ascii_stc_eq_ignore_case(a: &str, b: &str) -> bool {
             .zip(b.as_bytes().iter())
             .all(|(ac, bc)| ac.eq_ignore_ascii_case(bc))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::guess_http;
+    use bytes::BytesMut;
+
+    #[test]
+    fn http_request() {
+        let r = b"GET /path?q=1 HTTP/1.1\r\nHost: example.com\r\nUser-Agent: curl/7.68.0\r\n\r\n";
+        let h = guess_http(&BytesMut::from(&r[..])).unwrap();
+        assert_eq!(h.method, "GET");
+        assert_eq!(h.path, "/path?q=1");
+        assert_eq!(h.host, "example.com");
+        assert_eq!(h.user_agent.unwrap(), "curl/7.68.0");
+        // more headers may follow
+        let r = b"POST /api HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n";
+        assert!(guess_http(&BytesMut::from(&r[..])).is_none());
+        let r = b"POST /api HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let h = guess_http(&BytesMut::from(&r[..])).unwrap();
+        assert_eq!(
+            (h.method.as_ref(), h.path.as_ref()),
+            (&b"POST"[..], &b"/api"[..])
+        );
+        assert_eq!(h.user_agent, None);
+    }
+}
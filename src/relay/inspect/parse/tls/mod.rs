@@ -11,14 +11,94 @@ use bytes::Bytes;
 pub struct TlsWithSni {
     /// only possibly useful fields are included here
     pub version: TlsVersion,
-    pub sni: Bytes,
+    /// left out when connecting to a bare address
+    pub sni: Option<Bytes>,
+    /// protocols offered by ALPN, like "h2"
+    pub alpn: Vec<Bytes>,
+    /// from the supported_versions extension, without GREASE values
+    pub versions: Vec<TlsVersion>,
+    pub ciphers: Vec<u16>,
+    /// JA3 fingerprint, md5 in lowercase hex
+    pub ja3: Bytes,
+}
+
+impl TlsWithSni {
+    /// The highest version offered, TLS 1.3 is only offered in supported_versions
+    pub fn max_version(&self) -> TlsVersion {
+        let v = self.versions.iter().map(|v| v.0).max();
+        TlsVersion(v.unwrap_or(0).max(self.version.0))
+    }
 }
 
 pub fn parse_tls_sni(bs: &[u8]) -> Option<TlsWithSni> {
     let x = parse_tls_plaintext(bs).ok().map(|y| y.1)?;
-    let v = x.version;
-    let n = x.get_sni()?;
-    Some(TlsWithSni { version: v, sni: n })
+    let ja3 = format!("{:x}", md5::compute(ja3_text(&x)));
+    let mut alpn = vec![];
+    let mut versions = vec![];
+    for e in &x.ext {
+        match e {
+            TlsExtension::Alpn(a) => alpn = a.clone(),
+            TlsExtension::SupportedVersions(v) => {
+                versions = v
+                    .iter()
+                    .filter(|&&v| !is_grease(v))
+                    .map(|&v| TlsVersion(v))
+                    .collect()
+            }
+            _ => {}
+        }
+    }
+    Some(TlsWithSni {
+        version: x.version,
+        ciphers: x.cipher_list(),
+        sni: x.get_sni(),
+        alpn,
+        versions,
+        ja3: ja3.into(),
+    })
+}
+
+/// Reserved values sent to keep servers tolerant of unknown ones, like 0x0a0a and 0x1a1a
+fn is_grease(v: u16) -> bool {
+    v & 0x0f0f == 0x0a0a && v >> 8 == v & 0xff
+}
+
+/// Version, ciphers, extensions, groups and point formats,
+/// numbers in decimal joined by "-", GREASE values left out
+fn ja3_text(x: &TlsClientHello) -> String {
+    let join = |l: &mut dyn Iterator<Item = u16>| {
+        let l: Vec<String> = l
+            .filter(|&v| !is_grease(v))
+            .map(|v| v.to_string())
+            .collect();
+        l.join("-")
+    };
+    let ext_data = |t| {
+        x.ext.iter().find_map(|e| match e {
+            TlsExtension::Unknown(u, d) if *u == t => Some(*d),
+            _ => None,
+        })
+    };
+    // supported_groups, a list of u16 with a u16 length
+    let groups = ext_data(0x000a)
+        .filter(|d| d.len() >= 2 && u16::from_be_bytes([d[0], d[1]]) as usize == d.len() - 2)
+        .map_or(&[][..], |d| &d[2..]);
+    // ec_point_formats, a list of u8 with a u8 length
+    let formats = ext_data(0x000b)
+        .filter(|d| !d.is_empty() && d[0] as usize == d.len() - 1)
+        .map_or(&[][..], |d| &d[1..]);
+    format!(
+        "{},{},{},{},{}",
+        x.version.0,
+        join(&mut x.cipher_list().into_iter()),
+        join(&mut x.ext.iter().map(TlsExtension::ext_type)),
+        join(
+            &mut groups
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+        ),
+        join(&mut formats.iter().map(|&f| u16::from(f))),
+    )
 }
 /// Content type, as defined in IANA TLS ContentType registry
 const TLS_RECORD_TYPE_HANDSHAKE: u8 = 0x16;
@@ -51,6 +131,25 @@ impl TlsVersion {
     pub const Tls13Draft21: TlsVersion = TlsVersion(0x7f15);
     pub const Tls13Draft22: TlsVersion = TlsVersion(0x7f16);
     pub const Tls13Draft23: TlsVersion = TlsVersion(0x7f17);
+
+    const NUMBERS: [(TlsVersion, &'static str); 5] = [
+        (TlsVersion::Ssl30, "3.0"),
+        (TlsVersion::Tls10, "1.0"),
+        (TlsVersion::Tls11, "1.1"),
+        (TlsVersion::Tls12, "1.2"),
+        (TlsVersion::Tls13, "1.3"),
+    ];
+
+    /// as written in rules, like "1.3"
+    pub fn from_number(n: &[u8]) -> Option<TlsVersion> {
+        let v = TlsVersion::NUMBERS.iter().find(|(_, s)| s.as_bytes() == n);
+        v.map(|(v, _)| *v)
+    }
+
+    pub fn number(self) -> Option<&'static str> {
+        let v = TlsVersion::NUMBERS.iter().find(|(v, _)| *v == self);
+        v.map(|(_, s)| *s)
+    }
 }
 
 impl From<TlsVersion> for u16 {
@@ -123,14 +222,20 @@ impl<'a> TlsClientHello<'a> {
         }
     }
 
-    #[allow(dead_code)]
-    pub fn get_sni(self) -> Option<Bytes> {
-        for e in self.ext {
+    pub fn cipher_list(&self) -> Vec<u16> {
+        self.ciphers
+            .chunks_exact(2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]))
+            .collect()
+    }
+
+    pub fn get_sni(&self) -> Option<Bytes> {
+        for e in &self.ext {
             match e {
                 TlsExtension::SNI(names) => {
                     for (nt, nb) in names {
-                        if nt == 0 {
-                            return Some(nb);
+                        if *nt == 0 {
+                            return Some(nb.clone());
                         }
                     }
                 }
@@ -277,4 +382,50 @@ mod tests {
         let res = parse_tls_plaintext(&bytes);
         assert_eq!(res, Ok((empty, expected)));
     }
+
+    #[test]
+    fn client_hello_fingerprint() {
+        let ext = |t: u16, data: &[u8]| {
+            let mut e = t.to_be_bytes().to_vec();
+            e.extend(&(data.len() as u16).to_be_bytes());
+            e.extend(data);
+            e
+        };
+        let client_hello = |sni: bool| {
+            let mut exts = vec![];
+            if sni {
+                exts.extend(ext(0x0000, b"\x00\x0e\x00\x00\x0bexample.com"));
+            }
+            exts.extend(ext(0x0a0a, b""));
+            exts.extend(ext(0x0010, b"\x00\x0c\x02h2\x08http/1.1"));
+            exts.extend(ext(0x000a, &[0, 6, 0x1a, 0x1a, 0, 0x1d, 0, 0x17]));
+            exts.extend(ext(0x000b, &[1, 0]));
+            exts.extend(ext(0x002b, &[6, 0x2a, 0x2a, 3, 4, 3, 3]));
+            let mut hello = vec![3, 3];
+            hello.extend(&[0; 32]);
+            hello.extend(&[0, 0, 6, 0x0a, 0x0a, 0x13, 0x01, 0xc0, 0x2f, 1, 0]);
+            hello.extend(&(exts.len() as u16).to_be_bytes());
+            hello.extend(exts);
+            let mut bytes = vec![0x16, 3, 1, 0, 0, 1, 0];
+            bytes.extend(&(hello.len() as u16).to_be_bytes());
+            bytes.extend(hello);
+            bytes
+        };
+        let bytes = client_hello(true);
+
+        let (_, x) = parse_tls_plaintext(&bytes).unwrap();
+        assert_eq!(ja3_text(&x), "771,4865-49199,0-16-10-11-43,29-23,0");
+        let t = parse_tls_sni(&bytes).unwrap();
+        assert_eq!(t.sni, Some("example.com".into()));
+        assert_eq!(t.alpn, vec![Bytes::from("h2"), Bytes::from("http/1.1")]);
+        assert_eq!(t.max_version(), TlsVersion::Tls13);
+        assert_eq!(t.ciphers, vec![0x0a0a, 0x1301, 0xc02f]);
+        let md5 = format!("{:x}", md5::compute("771,4865-49199,0-16-10-11-43,29-23,0"));
+        assert_eq!(t.ja3, md5);
+
+        // to a bare address
+        let t = parse_tls_sni(&client_hello(false)).unwrap();
+        assert_eq!(t.sni, None);
+        assert_eq!(t.alpn, vec![Bytes::from("h2"), Bytes::from("http/1.1")]);
+    }
 }
@@ -6,9 +6,22 @@ use nom::{be_u16, be_u8, IResult};
 #[derive(Clone, Debug, PartialEq)]
 pub enum TlsExtension<'a> {
     SNI(Vec<(u8, Bytes)>),
+    Alpn(Vec<Bytes>),
+    SupportedVersions(Vec<u16>),
     Unknown(u16, &'a [u8]),
 }
 
+impl<'a> TlsExtension<'a> {
+    pub fn ext_type(&self) -> u16 {
+        match self {
+            TlsExtension::SNI(_) => 0x0000,
+            TlsExtension::Alpn(_) => 0x0010,
+            TlsExtension::SupportedVersions(_) => 0x002b,
+            TlsExtension::Unknown(t, _) => *t,
+        }
+    }
+}
+
 named!(pub parse_tls_extension_sni_hostname<(u8, Bytes)>,
     pair!(be_u8,
           map!(length_bytes!(be_u16), |bs: &[u8]| bs.into())
@@ -25,6 +38,24 @@ named!(pub parse_tls_extension_sni_content<TlsExtension>,
     )
 );
 
+named!(pub parse_tls_extension_alpn_content<TlsExtension>,
+    do_parse!(
+        list_len: be_u16 >>
+        v: flat_map!(take!(list_len),
+            many0!(complete!(map!(length_bytes!(be_u8), |bs: &[u8]| bs.into())))
+        ) >>
+        ( TlsExtension::Alpn(v) )
+    )
+);
+
+named!(pub parse_tls_extension_supported_versions_content<TlsExtension>,
+    do_parse!(
+        list_len: be_u8 >>
+        v: flat_map!(take!(list_len), many0!(complete!(be_u16))) >>
+        ( TlsExtension::SupportedVersions(v) )
+    )
+);
+
 fn parse_tls_extension_with_type(
     i: &[u8],
     ext_type: u16,
@@ -32,6 +63,8 @@ fn parse_tls_extension_with_type(
 ) -> IResult<&[u8], TlsExtension> {
     match ext_type {
         0x0000 => parse_tls_extension_sni_content(i),
+        0x0010 => parse_tls_extension_alpn_content(i),
+        0x002b => parse_tls_extension_supported_versions_content(i),
         _ => map!(i, take!(ext_len), |ext_data| {
             TlsExtension::Unknown(ext_type, ext_data)
         }),
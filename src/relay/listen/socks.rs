@@ -5,12 +5,15 @@ use std::sync::Arc;
 use tokio;
 
 use crate::relay::forwarding::handle_incoming_tcp;
+use crate::relay::route::TcpClient;
 use crate::relay::TcpRouter;
-use asocks5::listen::handle_socks_handshake;
+use asocks5::listen::{handle_socks_handshake, handle_socks_handshake_auth, password_eq};
 use asocks5::socks::Address;
 use asocks5::socks::SocksError;
 use asocks5::socks::TcpRequestHeader;
 use asocks5::Command;
+use bytes::Bytes;
+use std::collections::BTreeMap;
 use tokio::net::TcpStream;
 
 use futures::future::ready;
@@ -18,21 +21,31 @@ use futures::future::ready;
 use crate::resolver::AsyncResolver;
 use tokio::prelude::*;
 
+/// Clients are authenticated if there are any users
 pub async fn listen_socks(
     addr: &SocketAddr,
+    name: Option<Bytes>,
+    users: BTreeMap<Bytes, Bytes>,
     resolver: Arc<AsyncResolver>,
     router: Arc<TcpRouter>,
 ) -> Result<(), Error> {
     let mut l = tokio::net::TcpListener::bind(addr).await?;
+    let users = Arc::new(users);
     tokio::spawn(async move {
         loop {
             let s = l.accept().await;
             match s {
-                Ok((s, _addr)) => {
+                Ok((s, addr)) => {
                     let r1 = resolver.clone();
                     let rt1 = router.clone();
+                    let client = TcpClient {
+                        addr,
+                        inbound: name.clone(),
+                        user: None,
+                    };
+                    let u1 = users.clone();
                     tokio::spawn(async move {
-                        let _r = handle_client(s, r1, rt1).await.map_err(|e| {
+                        let _r = handle_client(s, client, u1, r1, rt1).await.map_err(|e| {
                             error!("error handling client {}", e);
                         });
                     });
@@ -46,12 +59,21 @@ pub async fn listen_socks(
 
 async fn handle_client(
     s: TcpStream,
+    mut client: TcpClient,
+    users: Arc<BTreeMap<Bytes, Bytes>>,
     res: Arc<AsyncResolver>,
     rt: Arc<TcpRouter>,
 ) -> Result<(), Error> {
-    let (s, req) = handle_socks_handshake(s).await?;
+    let (s, req) = if users.is_empty() {
+        handle_socks_handshake(s).await?
+    } else {
+        let check = |u: &[u8], p: &[u8]| users.get(u).is_some_and(|x| password_eq(p, x));
+        let (s, req, user) = handle_socks_handshake_auth(s, check).await?;
+        client.user = Some(user.into());
+        (s, req)
+    };
     let a = read_address(req, res.clone()).await?;
-    handle_incoming_tcp(s, a, rt).await
+    handle_incoming_tcp(s, client, a, rt, res).await
 }
 
 async fn read_address(
@@ -1,4 +1,5 @@
 use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use std::net::SocketAddr;
 use std::sync::Arc;
 
@@ -6,18 +7,60 @@ use crate::conf::RoutingAction;
 use crate::conf::RoutingBranch;
 use crate::conf::{DomainMatcher, IpMatcher};
 use crate::relay::inspect::TcpProtocol;
+use crate::resolver::DomainHints;
 use crate::util::BsDisp;
 use std::fmt;
 
+/// Who makes the connection, and through which relay
+#[derive(Clone, Debug)]
+pub struct TcpClient {
+    pub addr: SocketAddr,
+    /// the name of the relay
+    pub inbound: Option<Bytes>,
+    /// authenticated by the relay
+    pub user: Option<Bytes>,
+}
+
 #[derive(Debug)]
 pub struct TcpTrafficInfo<'a> {
     addr: SocketAddr,
     protocol: &'a TcpProtocol,
-    pub domain_region: Option<Bytes>,
+    /// from the protocol, or the address
+    domain: Option<Bytes>,
+    domain_region: Option<Bytes>,
     ip_region: Option<Bytes>,
+    client: &'a TcpClient,
+    source_region: Option<Bytes>,
+    /// when the connection is made
+    now: DateTime<Utc>,
 }
 
 impl<'a> TcpTrafficInfo<'a> {
+    #[cfg(test)]
+    pub fn new(
+        addr: SocketAddr,
+        protocol: &'a TcpProtocol,
+        client: &'a TcpClient,
+    ) -> TcpTrafficInfo<'a> {
+        TcpTrafficInfo {
+            addr,
+            protocol,
+            domain: None,
+            domain_region: None,
+            ip_region: None,
+            client,
+            source_region: None,
+            now: Utc::now(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn regions(mut self, domain: Option<&str>, source: Option<&str>) -> TcpTrafficInfo<'a> {
+        self.domain_region = domain.map(Bytes::from);
+        self.source_region = source.map(Bytes::from);
+        self
+    }
+
     pub fn domain_region(&self) -> Option<&[u8]> {
         if let Some(ref x) = self.domain_region {
             Some(x)
@@ -34,6 +77,22 @@ impl<'a> TcpTrafficInfo<'a> {
         }
     }
 
+    pub fn source_region(&self) -> Option<&[u8]> {
+        self.source_region.as_ref().map(Bytes::as_ref)
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.now
+    }
+
+    pub fn user(&self) -> Option<&[u8]> {
+        self.client.user.as_ref().map(Bytes::as_ref)
+    }
+
+    pub fn inbound(&self) -> Option<&[u8]> {
+        self.client.inbound.as_ref().map(Bytes::as_ref)
+    }
+
     pub fn addr(&self) -> SocketAddr {
         self.addr
     }
@@ -43,37 +102,83 @@ impl<'a> TcpTrafficInfo<'a> {
     }
 }
 
+/// The time that schedules in rules are matched against
+pub type Clock = Box<dyn Fn() -> DateTime<Utc> + Send + Sync>;
+
 pub struct TcpRouter {
     domain_match: Arc<DomainMatcher>,
     ip_match: Arc<IpMatcher>,
+    hints: DomainHints,
     rules: RoutingBranch,
+    clock: Clock,
 }
 
 impl TcpRouter {
     pub fn new(
         domain_match: Arc<DomainMatcher>,
         ip_match: Arc<IpMatcher>,
+        hints: DomainHints,
         rules: RoutingBranch,
     ) -> TcpRouter {
         TcpRouter {
             domain_match,
             ip_match,
+            hints,
             rules,
+            clock: Box::new(Utc::now),
         }
     }
 
-    pub fn route(&self, addr: SocketAddr, protocol: &TcpProtocol) -> Option<RoutingAction> {
-        let domain = protocol.get_domain().and_then(|x| {
+    /// Routes at the time given by the clock instead of the system time
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Clock) -> TcpRouter {
+        self.clock = clock;
+        self
+    }
+
+    /// The domain name if the address is a fake one given by the dns proxy
+    pub fn fake_domain(&self, addr: SocketAddr) -> Option<String> {
+        self.hints.fake_ip.as_ref()?.domain_of(addr.ip())
+    }
+
+    /// The domain name of fake addresses should be given
+    pub fn route(
+        &self,
+        client: &TcpClient,
+        addr: SocketAddr,
+        fake_domain: Option<&str>,
+        protocol: &TcpProtocol,
+    ) -> Option<RoutingAction> {
+        // learned domain names are only guesses,
+        // since an address can be shared by domains
+        let d: Option<Bytes> = protocol
+            .get_domain()
+            .map(Bytes::from)
+            .or_else(|| fake_domain.map(Bytes::from))
+            .or_else(|| {
+                let l = self.hints.learned.as_ref()?;
+                l.domain_of(addr.ip()).map(Bytes::from)
+            });
+        let domain = d.as_ref().and_then(|x| {
             let x: Vec<&[u8]> = x.split(|&y| y == b'.').rev().collect();
             let x = x.join(&b'.');
             self.domain_match.rule_domain(&x)
         });
-        let ip = self.ip_match.match_ip(addr.ip());
+        // fake addresses don't tell where the traffic goes
+        let ip = if fake_domain.is_some() {
+            None
+        } else {
+            self.ip_match.match_ip(addr.ip())
+        };
         let i = TcpTrafficInfo {
             addr,
             protocol: &protocol,
+            domain: d,
             domain_region: domain,
             ip_region: ip,
+            client,
+            source_region: self.ip_match.match_ip(client.addr.ip()),
+            now: (self.clock)(),
         };
         let d = self.rules.decision(&i);
         info!("{}", RouteAndTraffic::new(&d, i));
@@ -109,7 +214,7 @@ impl<'a> fmt::Display for RouteAndTraffic<'a> {
 impl<'a> fmt::Display for TcpTrafficInfo<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         write!(f, "{} ", BsDisp::new(self.protocol.name()))?;
-        if let Some(d) = self.protocol.get_domain() {
+        if let Some(ref d) = self.domain {
             write!(f, "{}", BsDisp::new(&d))?;
             if let Some(ref r) = self.domain_region {
                 write!(f, "({})", BsDisp::new(&r))?;
@@ -119,10 +224,20 @@ impl<'a> fmt::Display for TcpTrafficInfo<'a> {
         if let Some(ref r) = self.ip_region {
             write!(f, "({})", BsDisp::new(&r))?;
         }
+        write!(f, " from={}", self.client.addr)?;
+        if let Some(ref r) = self.source_region {
+            write!(f, "({})", BsDisp::new(&r))?;
+        }
+        if let Some(ref u) = self.client.user {
+            write!(f, " user={}", BsDisp::new(u))?;
+        }
+        if let Some(ref i) = self.client.inbound {
+            write!(f, " via {}", BsDisp::new(i))?;
+        }
         match self.protocol {
             TcpProtocol::PlainHttp(h) => {
                 if let Some(ref u) = h.user_agent {
-                    write!(f, " ua={}", BsDisp::new(&u))?;
+                    write!(f, " ua={}", BsDisp::new(u))?;
                 }
             }
             _ => {}
@@ -0,0 +1,535 @@
+//! Cache responses until their ttls expire
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use failure::Error;
+use lru::LruCache;
+use trust_dns::op::{Message, Query, ResponseCode};
+use trust_dns::rr::{DNSClass, LowerName, RData, Record, RecordType};
+
+use crate::conf::CacheConf;
+
+/// Responses may differ by the zone and the clients, since they decide the upstream,
+/// and by the client subnet sent upstream
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct CacheKey {
+    name: LowerName,
+    query_type: RecordType,
+    query_class: DNSClass,
+    zone: Option<Bytes>,
+    /// The address zone of clients, if they have their own forwarding table entry
+    clients: Option<Bytes>,
+    subnet: Option<Vec<u8>>,
+}
+
+impl CacheKey {
+    pub fn new(
+        q: &Query,
+        zone: Option<Bytes>,
+        clients: Option<Bytes>,
+        subnet: Option<Vec<u8>>,
+    ) -> CacheKey {
+        CacheKey {
+            name: LowerName::new(q.name()),
+            query_type: q.query_type(),
+            query_class: q.query_class(),
+            zone,
+            clients,
+            subnet,
+        }
+    }
+}
+
+/// The query a response is for, so that it can be asked again
+#[derive(Clone, Debug)]
+pub struct Origin {
+    pub request: Vec<u8>,
+    pub client: IpAddr,
+}
+
+struct CacheEntry {
+    message: Message,
+    stored: Instant,
+    ttl: Duration,
+    origin: Option<Origin>,
+    /// Times it's been used
+    hits: u32,
+    /// Whether it's being refreshed
+    prefetching: bool,
+}
+
+/// Responses with shorter ttls aren't worth refreshing
+const MIN_PREFETCH_TTL: Duration = Duration::from_secs(10);
+
+pub struct DnsCache {
+    conf: CacheConf,
+    entries: Mutex<LruCache<CacheKey, CacheEntry>>,
+}
+
+impl DnsCache {
+    pub fn new(conf: &CacheConf) -> DnsCache {
+        let c = DnsCache {
+            conf: conf.clone(),
+            entries: Mutex::new(LruCache::new(conf.size)),
+        };
+        if let Some(ref f) = conf.file {
+            match c.load() {
+                Ok(n) => info!("Loaded {} dns responses from {:?}", n, f),
+                Err(e) => warn!("Error loading dns cache from {:?}: {}", f, e),
+            }
+        }
+        c
+    }
+
+    /// A cached response with the given id, if it's not expired
+    pub fn get(&self, key: &CacheKey, id: u16) -> Option<Vec<u8>> {
+        self.get_at(key, id, Instant::now())
+    }
+
+    fn get_at(&self, key: &CacheKey, id: u16, now: Instant) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap();
+        let fresh = {
+            let e = entries.get_mut(key)?;
+            let elapsed = now.duration_since(e.stored);
+            if elapsed < e.ttl {
+                e.hits += 1;
+                Some((e.message.clone(), elapsed))
+            } else {
+                None
+            }
+        };
+        let (mut message, elapsed) = match fresh {
+            Some(x) => x,
+            None => {
+                entries.pop(key);
+                return None;
+            }
+        };
+        drop(entries);
+        message.set_id(id);
+        age_message(&mut message, elapsed.as_secs() as u32);
+        message.to_vec().ok()
+    }
+
+    /// Only cacheable responses are stored
+    pub fn insert(&self, key: CacheKey, mut message: Message, origin: Option<Origin>) {
+        if message.truncated() {
+            return;
+        }
+        let ttl = match message.response_code() {
+            ResponseCode::NoError if !message.answers().is_empty() => {
+                let answers = self.clamp_records(message.take_answers());
+                let ttl = answers.iter().map(Record::ttl).min();
+                message.insert_answers(answers);
+                let ns = self.clamp_records(message.take_name_servers());
+                message.insert_name_servers(ns);
+                let additionals = self.clamp_records(message.take_additionals());
+                message.insert_additionals(additionals);
+                ttl
+            }
+            ResponseCode::NoError | ResponseCode::NXDomain => self.negative_ttl(&mut message),
+            _ => None,
+        };
+        let ttl = match ttl {
+            Some(t) if t > 0 => t,
+            _ => return,
+        };
+        let e = CacheEntry {
+            message,
+            stored: Instant::now(),
+            ttl: Duration::from_secs(u64::from(ttl)),
+            origin,
+            hits: 0,
+            prefetching: false,
+        };
+        self.entries.lock().unwrap().put(key, e);
+    }
+
+    /// Queries of popular responses about to expire, each is given once
+    pub fn prefetch_due(&self) -> Vec<Origin> {
+        self.prefetch_due_at(Instant::now())
+    }
+
+    fn prefetch_due_at(&self, now: Instant) -> Vec<Origin> {
+        if self.conf.prefetch == 0 {
+            return vec![];
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries
+            .iter_mut()
+            .filter_map(|(_, e)| {
+                if e.prefetching || e.hits < self.conf.prefetch || e.ttl < MIN_PREFETCH_TTL {
+                    return None;
+                }
+                let left = e.ttl.checked_sub(now.duration_since(e.stored))?;
+                if left > e.ttl / 10 {
+                    return None;
+                }
+                let o = e.origin.clone()?;
+                e.prefetching = true;
+                Some(o)
+            })
+            .collect()
+    }
+
+    /// Write unexpired responses to the file, least recently used first,
+    /// one in a line of: time saved, seconds left, zone, clients, subnet,
+    /// client address, query and response
+    pub fn save(&self) -> Result<usize, Error> {
+        let path = match self.conf.file {
+            Some(ref p) => p,
+            None => return Ok(0),
+        };
+        let now = Instant::now();
+        let saved = unix_time(SystemTime::now());
+        // encoded after the lock is released, so lookups aren't blocked
+        let snapshot: Vec<_> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter_map(|(k, e)| {
+                let elapsed = now.duration_since(e.stored);
+                match e.ttl.checked_sub(elapsed) {
+                    Some(l) if l.as_secs() > 0 => {
+                        let x = (k.clone(), e.message.clone(), e.origin.clone());
+                        Some((x, elapsed, l.as_secs()))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        let mut lines = String::new();
+        let mut n = 0;
+        for ((k, mut m, origin), elapsed, left) in snapshot {
+            age_message(&mut m, elapsed.as_secs() as u32);
+            let text = |b: &Option<Bytes>| match b {
+                Some(b) => String::from_utf8_lossy(b).into_owned(),
+                None => "-".to_string(),
+            };
+            let (client, request) = match origin {
+                Some(ref o) => (o.client.to_string(), base64::encode(&o.request)),
+                None => ("-".to_string(), "-".to_string()),
+            };
+            lines += &format!(
+                "{} {} {} {} {} {} {} {}\n",
+                saved,
+                left,
+                text(&k.zone),
+                text(&k.clients),
+                k.subnet.as_ref().map_or("-".to_string(), base64::encode),
+                client,
+                request,
+                base64::encode(&m.to_vec()?)
+            );
+            n += 1;
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, lines)?;
+        fs::rename(&tmp, path)?;
+        Ok(n)
+    }
+
+    /// Read responses saved before, skipping expired ones
+    fn load(&self) -> Result<usize, Error> {
+        let path = match self.conf.file {
+            Some(ref p) => p,
+            None => return Ok(0),
+        };
+        let text = match fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let now = unix_time(SystemTime::now());
+        let mut entries = self.entries.lock().unwrap();
+        let mut n = 0;
+        for l in text.lines() {
+            match read_entry(l, now) {
+                Ok(Some((k, e))) => {
+                    entries.put(k, e);
+                    n += 1;
+                }
+                Ok(None) => {}
+                Err(e) => debug!("Skipping saved dns response: {}", e),
+            }
+        }
+        Ok(n)
+    }
+
+    fn clamp_records(&self, records: Vec<Record>) -> Vec<Record> {
+        records
+            .into_iter()
+            .map(|mut r| {
+                let t = r.ttl().max(self.conf.min_ttl).min(self.conf.max_ttl);
+                r.set_ttl(t);
+                r
+            })
+            .collect()
+    }
+
+    /// RFC 2308 section 5, the ttl is taken from the soa record in the authority section,
+    /// and without it the response isn't cached
+    fn negative_ttl(&self, message: &mut Message) -> Option<u32> {
+        let mut ns = message.take_name_servers();
+        let mut ttl = None;
+        for r in &mut ns {
+            if let RData::SOA(ref soa) = *r.rdata() {
+                let t = r.ttl().min(soa.minimum());
+                let t = t.max(self.conf.min_ttl).min(self.conf.negative_ttl);
+                r.set_ttl(t);
+                ttl = Some(t);
+            }
+        }
+        message.insert_name_servers(ns);
+        ttl
+    }
+}
+
+/// A line written by `DnsCache::save`, if it's not expired
+fn read_entry(line: &str, now: u64) -> Result<Option<(CacheKey, CacheEntry)>, Error> {
+    let f: Vec<&str> = line.split(' ').collect();
+    if f.len() != 8 {
+        return Err(format_err!("Wrong number of fields"));
+    }
+    let saved: u64 = f[0].parse()?;
+    let left: u64 = f[1].parse()?;
+    let since = now.saturating_sub(saved);
+    if since >= left {
+        return Ok(None);
+    }
+    let zone = field(f[2]).map(Bytes::from);
+    let clients = field(f[3]).map(Bytes::from);
+    let subnet = match field(f[4]) {
+        Some(s) => Some(base64::decode(s)?),
+        None => None,
+    };
+    let origin = match (field(f[5]), field(f[6])) {
+        (Some(c), Some(r)) => Some(Origin {
+            client: c.parse()?,
+            request: base64::decode(r)?,
+        }),
+        _ => None,
+    };
+    let mut message = Message::from_vec(&base64::decode(f[7])?)?;
+    age_message(&mut message, since as u32);
+    let q = message
+        .queries()
+        .first()
+        .cloned()
+        .ok_or_else(|| format_err!("No query"))?;
+    let e = CacheEntry {
+        message,
+        stored: Instant::now(),
+        ttl: Duration::from_secs(left - since),
+        origin,
+        hits: 0,
+        prefetching: false,
+    };
+    Ok(Some((CacheKey::new(&q, zone, clients, subnet), e)))
+}
+
+/// `-` means none
+fn field(s: &str) -> Option<&str> {
+    if s == "-" {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+fn unix_time(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn age_message(message: &mut Message, elapsed: u32) {
+    let answers = age_records(message.take_answers(), elapsed);
+    message.insert_answers(answers);
+    let ns = age_records(message.take_name_servers(), elapsed);
+    message.insert_name_servers(ns);
+    let additionals = age_records(message.take_additionals(), elapsed);
+    message.insert_additionals(additionals);
+}
+
+fn age_records(records: Vec<Record>, elapsed: u32) -> Vec<Record> {
+    records
+        .into_iter()
+        .map(|mut r| {
+            let t = r.ttl().saturating_sub(elapsed);
+            r.set_ttl(t);
+            r
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheKey, DnsCache, Origin};
+    use crate::conf::CacheConf;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+    use trust_dns::op::{Message, MessageType, Query, ResponseCode};
+    use trust_dns::rr::rdata::SOA;
+    use trust_dns::rr::{Name, RData, Record, RecordType};
+
+    fn response(q: &Query, code: ResponseCode, answers: Vec<Record>) -> Message {
+        let mut m = Message::new();
+        m.set_id(1)
+            .set_message_type(MessageType::Response)
+            .set_response_code(code)
+            .add_query(q.clone());
+        m.insert_answers(answers);
+        m
+    }
+
+    #[test]
+    fn positive_answers_age() {
+        let conf = CacheConf {
+            min_ttl: 10,
+            max_ttl: 100,
+            ..CacheConf::default()
+        };
+        let cache = DnsCache::new(&conf);
+        let name = Name::from_str("www.example.com.").unwrap();
+        let q = Query::query(name.clone(), RecordType::A);
+        let a = RData::A(Ipv4Addr::new(127, 0, 0, 1));
+        let answers = vec![
+            Record::from_rdata(name.clone(), 300, a.clone()),
+            Record::from_rdata(name.clone(), 1, a),
+        ];
+        let key = CacheKey::new(&q, None, None, None);
+        cache.insert(
+            key.clone(),
+            response(&q, ResponseCode::NoError, answers),
+            None,
+        );
+        // the zone is part of the key
+        assert!(cache
+            .get(&CacheKey::new(&q, Some("z".into()), None, None), 7)
+            .is_none());
+        assert!(cache
+            .get(&CacheKey::new(&q, None, Some("kids".into()), None), 7)
+            .is_none());
+
+        let later = Instant::now() + Duration::from_secs(4);
+        let r = cache.get_at(&key, 7, later).unwrap();
+        let r = Message::from_vec(&r).unwrap();
+        assert_eq!(r.id(), 7);
+        let ttls: Vec<u32> = r.answers().iter().map(Record::ttl).collect();
+        assert_eq!(ttls, vec![96, 6]);
+
+        let expired = Instant::now() + Duration::from_secs(11);
+        assert!(cache.get_at(&key, 7, expired).is_none());
+        assert!(cache.get(&key, 7).is_none());
+    }
+
+    #[test]
+    fn negative_answers() {
+        let cache = DnsCache::new(&CacheConf::default());
+        let name = Name::from_str("nx.example.com.").unwrap();
+        let q = Query::query(name, RecordType::AAAA);
+        let zone = Name::from_str("example.com.").unwrap();
+        let soa = SOA::new(zone.clone(), zone.clone(), 1, 7200, 3600, 1_209_600, 60);
+        let mut m = response(&q, ResponseCode::NXDomain, vec![]);
+        m.add_name_server(Record::from_rdata(zone, 900, RData::SOA(soa)));
+        let key = CacheKey::new(&q, None, None, None);
+        cache.insert(key.clone(), m, None);
+        let r = cache.get(&key, 2).unwrap();
+        let r = Message::from_vec(&r).unwrap();
+        assert_eq!(r.response_code(), ResponseCode::NXDomain);
+        assert_eq!(r.name_servers()[0].ttl(), 60);
+
+        // without soa, there's no way to know how long it should be cached
+        let q = Query::query(Name::from_str("nx2.example.com.").unwrap(), RecordType::A);
+        let key = CacheKey::new(&q, None, None, None);
+        cache.insert(
+            key.clone(),
+            response(&q, ResponseCode::NXDomain, vec![]),
+            None,
+        );
+        assert!(cache.get(&key, 2).is_none());
+        let key = CacheKey::new(&q, Some("z".into()), None, None);
+        cache.insert(
+            key.clone(),
+            response(&q, ResponseCode::ServFail, vec![]),
+            None,
+        );
+        assert!(cache.get(&key, 2).is_none());
+    }
+
+    #[test]
+    fn saved_and_loaded() {
+        let path = std::env::temp_dir().join(format!("reflow-cache-{}", std::process::id()));
+        let conf = CacheConf {
+            file: Some(path.clone()),
+            ..CacheConf::default()
+        };
+        let cache = DnsCache::new(&conf);
+        let name = Name::from_str("www.example.com.").unwrap();
+        let a = RData::A(Ipv4Addr::new(127, 0, 0, 1));
+        let q = Query::query(name.clone(), RecordType::A);
+        let answers = vec![Record::from_rdata(name.clone(), 300, a.clone())];
+        let key = CacheKey::new(&q, Some("z".into()), None, Some(vec![1, 2]));
+        let origin = Origin {
+            request: vec![3, 4],
+            client: [10, 0, 0, 1].into(),
+        };
+        let m = response(&q, ResponseCode::NoError, answers);
+        cache.insert(key.clone(), m, Some(origin));
+        let q6 = Query::query(name.clone(), RecordType::AAAA);
+        let answers = vec![Record::from_rdata(name, 0, a)];
+        let m = response(&q6, ResponseCode::NoError, answers);
+        cache.insert(CacheKey::new(&q6, None, None, None), m, None);
+        assert_eq!(cache.save().unwrap(), 1);
+
+        let loaded = DnsCache::new(&conf);
+        std::fs::remove_file(&path).unwrap();
+        let r = Message::from_vec(&loaded.get(&key, 7).unwrap()).unwrap();
+        assert!(r.answers()[0].ttl() > 290);
+        let entries = loaded.entries.lock().unwrap();
+        let o = entries.peek(&key).unwrap().origin.as_ref().unwrap();
+        assert_eq!(o.request, vec![3, 4]);
+        assert_eq!(o.client, IpAddr::from([10, 0, 0, 1]));
+    }
+
+    #[test]
+    fn popular_responses_prefetched() {
+        let conf = CacheConf {
+            prefetch: 2,
+            ..CacheConf::default()
+        };
+        let cache = DnsCache::new(&conf);
+        let name = Name::from_str("www.example.com.").unwrap();
+        let a = RData::A(Ipv4Addr::new(127, 0, 0, 1));
+        let origin = Origin {
+            request: vec![1],
+            client: [10, 0, 0, 1].into(),
+        };
+        let mut keys = vec![];
+        for t in &[RecordType::A, RecordType::AAAA] {
+            let q = Query::query(name.clone(), *t);
+            let answers = vec![Record::from_rdata(name.clone(), 100, a.clone())];
+            let key = CacheKey::new(&q, None, None, None);
+            let m = response(&q, ResponseCode::NoError, answers);
+            cache.insert(key.clone(), m, Some(origin.clone()));
+            keys.push(key);
+        }
+        for _ in 0..2 {
+            cache.get(&keys[0], 1).unwrap();
+        }
+        cache.get(&keys[1], 1).unwrap();
+
+        let now = Instant::now();
+        assert!(cache.prefetch_due_at(now).is_empty());
+        let expiring = now + Duration::from_secs(95);
+        assert_eq!(cache.prefetch_due_at(expiring).len(), 1);
+        // refreshing already
+        assert!(cache.prefetch_due_at(expiring).is_empty());
+    }
+}
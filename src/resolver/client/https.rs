@@ -0,0 +1,223 @@
+//! Dns over https, RFC 8484
+use std::fmt;
+use std::io::Cursor;
+use std::sync::Mutex;
+
+use failure::Error;
+use h2::client::SendRequest;
+use http::header::{ACCEPT, CONTENT_TYPE};
+use http::{Method, Request, StatusCode};
+use tokio_rustls::TlsConnector;
+
+use super::tls::{tls_connect, tls_connector};
+use crate::conf::DohServer;
+use crate::conf::EgressAddr;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+type H2Sender = SendRequest<Cursor<Vec<u8>>>;
+
+/// Sends all queries over a single http/2 connection,
+/// which is opened again when it's closed or fails
+pub struct DohClient {
+    server: DohServer,
+    egress: Option<EgressAddr>,
+    connector: TlsConnector,
+    conn: Mutex<Option<H2Sender>>,
+}
+
+impl fmt::Debug for DohClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Https({}, {:?})", self.server.uri, self.server.addr)?;
+        if let Some(e) = self.egress {
+            write!(f, " via {:?}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl DohClient {
+    pub fn new(server: DohServer, egress: Option<EgressAddr>) -> DohClient {
+        Self::with_connector(server, egress, tls_connector(vec![b"h2".to_vec()]))
+    }
+
+    /// The connector should negotiate http/2 using alpn
+    pub fn with_connector(
+        server: DohServer,
+        egress: Option<EgressAddr>,
+        connector: TlsConnector,
+    ) -> DohClient {
+        DohClient {
+            server,
+            egress,
+            connector,
+            conn: Mutex::new(None),
+        }
+    }
+
+    pub async fn get(&self, mut data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if data.len() < 2 {
+            return Err(format_err!("Dns message too short"));
+        }
+        // the id should be 0 to be friendly to http caches
+        let id = [data[0], data[1]];
+        data[0] = 0;
+        data[1] = 0;
+        let pooled = self.conn.lock().unwrap().clone();
+        let mut res = match pooled {
+            Some(s) => match self.send_ready(s, data.clone()).await {
+                // the server may have closed it before we noticed
+                Err(e) if e.downcast_ref::<h2::Error>().is_some() => {
+                    debug!("Http/2 connection to {} failed: {}", self.server.uri, e);
+                    self.conn.lock().unwrap().take();
+                    let s = self.new_connection().await?;
+                    self.send(s, data).await?
+                }
+                r => r?,
+            },
+            None => {
+                let s = self.new_connection().await?;
+                self.send(s, data).await?
+            }
+        };
+        if res.len() < 2 {
+            return Err(format_err!("Dns message too short"));
+        }
+        res[0] = id[0];
+        res[1] = id[1];
+        Ok(res)
+    }
+
+    async fn new_connection(&self) -> Result<H2Sender, Error> {
+        let s = tls_connect(
+            &self.connector,
+            self.egress,
+            self.server.addr,
+            self.server.host(),
+        )
+        .await?;
+        let (sender, conn) = h2::client::Builder::new().handshake(s).await?;
+        let uri = self.server.uri.clone();
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                debug!("Http/2 connection to {} ended: {}", uri, e);
+            }
+        });
+        let sender = sender.ready().await?;
+        *self.conn.lock().unwrap() = Some(sender.clone());
+        Ok(sender)
+    }
+
+    async fn send_ready(&self, sender: H2Sender, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let sender = sender.ready().await?;
+        self.send(sender, data).await
+    }
+
+    async fn send(&self, mut sender: H2Sender, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let req = Request::builder().header(ACCEPT, DNS_MESSAGE);
+        let (res, body) = if self.server.get {
+            let q = base64::encode_config(&data, base64::URL_SAFE_NO_PAD);
+            let uri = format!("{}?dns={}", self.server.uri, q);
+            let req = req.method(Method::GET).uri(uri).body(())?;
+            sender.send_request(req, true)?
+        } else {
+            let req = req
+                .method(Method::POST)
+                .uri(self.server.uri.clone())
+                .header(CONTENT_TYPE, DNS_MESSAGE)
+                .body(())?;
+            let (res, mut body) = sender.send_request(req, false)?;
+            body.send_data(Cursor::new(data), true)?;
+            (res, body)
+        };
+        drop(body);
+        let res = res.await?;
+        if res.status() != StatusCode::OK {
+            return Err(format_err!(
+                "Dns over https server {} responded {}",
+                self.server.uri,
+                res.status()
+            ));
+        }
+        let mut body = res.into_body();
+        let mut buf = vec![];
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            buf.extend_from_slice(&chunk);
+            let _ = body.flow_control().release_capacity(chunk.len());
+        }
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DohClient;
+    use crate::conf::{DohServer, TlsIdentity};
+    use crate::resolver::serve::tcp::tests::{query, unused_addr};
+    use crate::resolver::serve::tls::tls_acceptor;
+    use http::{Response, StatusCode};
+    use std::fs::File;
+    use std::io::{BufReader, Cursor};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::TlsConnector;
+
+    #[test]
+    fn retries_failed_connections() {
+        let id = TlsIdentity {
+            cert: "test/tls/cert.pem".into(),
+            key: "test/tls/key.pem".into(),
+        };
+        let acceptor = tls_acceptor(&id, vec![b"h2".to_vec()]).unwrap();
+        let mut config = ClientConfig::new();
+        let mut ca = BufReader::new(File::open("test/tls/ca.pem").unwrap());
+        config.root_store.add_pem_file(&mut ca).unwrap();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let addr = unused_addr();
+        let url = format!("https://dns.test:{}/dns-query", addr.port());
+        let server = DohServer::new(&url, Some([127, 0, 0, 1].into())).unwrap();
+        let c = DohClient::with_connector(server, None, TlsConnector::from(Arc::new(config)));
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let a = accepted.clone();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let mut l = TcpListener::bind(&addr).await.unwrap();
+            // answers the first query on each connection, and refuses the rest
+            tokio::spawn(async move {
+                while let Ok((s, _)) = l.accept().await {
+                    a.fetch_add(1, Ordering::SeqCst);
+                    let s = acceptor.accept(s).await.unwrap();
+                    let mut conn = h2::server::Builder::new()
+                        .handshake::<_, Cursor<Vec<u8>>>(s)
+                        .await
+                        .unwrap();
+                    tokio::spawn(async move {
+                        let mut answered = false;
+                        while let Some(Ok((req, mut respond))) = conn.accept().await {
+                            if answered {
+                                respond.send_reset(h2::Reason::REFUSED_STREAM);
+                                continue;
+                            }
+                            answered = true;
+                            let mut body = req.into_body();
+                            let mut q = vec![];
+                            while let Some(Ok(chunk)) = body.data().await {
+                                q.extend_from_slice(&chunk);
+                            }
+                            let r = Response::builder().status(StatusCode::OK).body(());
+                            let mut b = respond.send_response(r.unwrap(), false).unwrap();
+                            b.send_data(Cursor::new(q), true).unwrap();
+                        }
+                    });
+                }
+            });
+            for i in 1..=3 {
+                assert_eq!(c.get(query(i)).await.unwrap(), query(i));
+            }
+        });
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+    }
+}
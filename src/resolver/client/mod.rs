@@ -1,4 +1,5 @@
+pub mod https;
 pub mod socks;
+pub mod tcp;
+pub mod tls;
 pub mod udp;
-
-pub const TIMEOUT: u64 = 10;
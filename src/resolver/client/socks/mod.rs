@@ -1,21 +1,31 @@
 use std::fmt;
 use std::io;
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::BigEndian;
 
 use tokio::net::TcpStream;
 
 use crate::conf::NameServerRemote;
-use asocks5::socks::Address;
 use asocks5::socks::SocksError;
 use asocks5::{connect_socks_socket_addr, Socks5Datagram};
-use tokio::prelude::*;
 
-/// Do dns queries through a socks5 proxy
+mod pipeline;
+
+use self::pipeline::Pipeline;
+
+/// Connections kept open to a nameserver
+const POOL_SIZE: usize = 4;
+/// Queries in flight over a connection before opening another
+const MAX_INFLIGHT: usize = 32;
+
+/// Do dns queries through a socks5 proxy,
+/// pipelined over long-lived connections or udp associations
 pub struct SockGetterAsync {
     proxy: SocketAddr,
     addr: NameServerRemote,
+    pool: Mutex<Vec<Arc<Pipeline>>>,
 }
 
 impl fmt::Debug for SockGetterAsync {
@@ -29,58 +39,54 @@ impl SockGetterAsync {
         SockGetterAsync {
             proxy,
             addr: remote,
+            pool: Mutex::new(vec![]),
         }
     }
 
     pub async fn get(&self, message: Vec<u8>) -> Result<Vec<u8>, SocksError> {
-        match self.addr {
-            NameServerRemote::Tcp(_a) => self.get_tcp(message).await,
-            NameServerRemote::Udp(_a) => self.get_udp(message).await,
+        let (p, reused) = self.pipeline().await?;
+        match p.query(&message).await {
+            // the proxy or the server may have closed it while idle
+            Err(e) if reused && p.is_closed() => {
+                debug!("Retrying dns query through {:?}: {}", self, e);
+                let (p, _) = self.pipeline().await?;
+                p.query(&message).await
+            }
+            r => r,
         }
     }
 
-    pub async fn get_udp(&self, data: Vec<u8>) -> Result<Vec<u8>, SocksError> {
-        use std::str::FromStr;
-        let la = SocketAddr::from_str("0.0.0.0:0").unwrap();
-        let socks5 = Socks5Datagram::bind(self.proxy, la).await?;
-        let addr = ns_sock_addr(&self.addr);
-        let socks5 = socks5.send_to(&data, Address::SocketAddress(addr)).await?;
-
-        let mut buf = vec![0; 998];
-        let (addr, n) = socks5.recv_from(&mut buf).await?;
-        debug!("receive {} from {:?}", n, addr);
-        buf.truncate(n);
-        Ok(buf)
+    /// The least busy open connection, or a new one if they're all busy
+    async fn pipeline(&self) -> Result<(Arc<Pipeline>, bool), SocksError> {
+        {
+            let mut pool = self.pool.lock().unwrap();
+            pool.retain(|p| !p.is_closed());
+            if let Some(p) = pool.iter().min_by_key(|p| p.len()) {
+                if p.len() < MAX_INFLIGHT || pool.len() >= POOL_SIZE {
+                    return Ok((p.clone(), true));
+                }
+            }
+        }
+        let p = Arc::new(self.connect().await?);
+        self.pool.lock().unwrap().push(p.clone());
+        Ok((p, false))
     }
 
-    pub async fn get_tcp(&self, data: Vec<u8>) -> Result<Vec<u8>, SocksError> {
+    async fn connect(&self) -> Result<Pipeline, SocksError> {
         let target = ns_sock_addr(&self.addr);
-
-        let mut stream = TcpStream::connect(&self.proxy).await?;
-        connect_socks_socket_addr(&mut stream, target).await?;
-
-        let len = data.len() as u16;
-        let mut lens = [0u8; 2];
-        lens.as_mut()
-            .write_u16::<BigEndian>(len)
-            .expect("byteorder");
-        trace!("Sending length {}, {:?}", len, lens);
-        stream.write_all(&lens).await?;
-        stream.write_all(&data).await?;
-        let mut b = [0u8; 2];
-        stream.read_exact(&mut b).await?;
-        trace!("Read reply length {:?}", b);
-        let mut rdr = io::Cursor::new(b);
-        let len = rdr.read_u16be().expect("read u16");
-        trace!("Reply length is {}", len);
-        let mut buf = data;
-        buf.resize(len as usize, 0);
-        trace!("Resized buf size to {}", buf.len());
-        if let Err(e) = stream.read_exact(&mut buf).await {
-            warn!("Error reading {} bytes: {:?}", len, e);
-            return Err(e.into());
+        debug!("Connecting to {:?} through {:?}", target, self.proxy);
+        match self.addr {
+            NameServerRemote::Udp(_a) => {
+                let la = SocketAddr::from(([0, 0, 0, 0], 0));
+                let socks5 = Socks5Datagram::bind(self.proxy, la).await?;
+                Ok(Pipeline::udp(socks5, target))
+            }
+            _ => {
+                let mut stream = TcpStream::connect(&self.proxy).await?;
+                connect_socks_socket_addr(&mut stream, target).await?;
+                Ok(Pipeline::tcp(stream))
+            }
         }
-        Ok(buf)
     }
 }
 
@@ -88,6 +94,8 @@ fn ns_sock_addr(ns: &NameServerRemote) -> SocketAddr {
     match ns {
         NameServerRemote::Udp(a) => *a,
         NameServerRemote::Tcp(a) => *a,
+        NameServerRemote::Tls(a, _) => *a,
+        NameServerRemote::Https(s) => s.addr,
     }
 }
 
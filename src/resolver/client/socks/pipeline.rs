@@ -0,0 +1,324 @@
+//! Many queries in flight over one tunneled connection, matched to responses by id
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use futures::future::select;
+use tokio::io::{split, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+use tokio::sync::{oneshot, Mutex as AsyncMutex};
+
+use super::super::tcp::{read_message, send_message};
+use asocks5::socks::{Address, SocksError};
+use asocks5::{Socks5Datagram, Socks5SendHalf};
+
+/// Queries given up on in a row before the connection is taken as dead,
+/// like a udp association the proxy forgot, or a half-open connection
+const MAX_ABANDONED: u32 = 3;
+
+/// Queries waiting for responses, by the id they were sent with
+#[derive(Default)]
+struct Inflight {
+    queries: HashMap<u16, oneshot::Sender<Vec<u8>>>,
+    closed: bool,
+    /// Queries timed out or cancelled since anything was received
+    abandoned: u32,
+    /// Stops the task receiving responses
+    stop: Option<oneshot::Sender<()>>,
+}
+
+type Shared = Arc<Mutex<Inflight>>;
+
+enum Sender {
+    Tcp(WriteHalf<TcpStream>),
+    Udp(Socks5SendHalf, Address),
+}
+
+pub struct Pipeline {
+    sender: AsyncMutex<Sender>,
+    inflight: Shared,
+}
+
+/// Forgets a query when it's answered, failed or abandoned
+struct Waiting<'a> {
+    inflight: &'a Shared,
+    id: u16,
+}
+
+impl Pipeline {
+    /// Over a tcp connection through the proxy
+    pub fn tcp(stream: TcpStream) -> Pipeline {
+        let (mut r, w) = split(stream);
+        let (inflight, stop) = new_inflight();
+        let i = inflight.clone();
+        tokio::spawn(async move {
+            let receive = async {
+                loop {
+                    match read_message(&mut r).await {
+                        Ok(m) => answer(&i, m),
+                        Err(e) => {
+                            debug!("Tunneled dns connection closed: {}", e);
+                            break;
+                        }
+                    }
+                }
+            };
+            select(Box::pin(receive), stop).await;
+            close(&i);
+        });
+        Pipeline {
+            sender: AsyncMutex::new(Sender::Tcp(w)),
+            inflight,
+        }
+    }
+
+    /// Over a udp association, which lasts as long as its tcp connection
+    pub fn udp(association: Socks5Datagram, target: SocketAddr) -> Pipeline {
+        let (mut recv, send, mut control) = association.split();
+        let (inflight, stop) = new_inflight();
+        let i = inflight.clone();
+        tokio::spawn(async move {
+            let receive = async {
+                let mut buf = vec![0; 65535];
+                loop {
+                    match recv.recv_from(&mut buf).await {
+                        Ok((_a, n)) => answer(&i, buf[..n].to_vec()),
+                        Err(e) => {
+                            debug!("Error receiving tunneled dns response: {}", e);
+                            break;
+                        }
+                    }
+                }
+            };
+            // the proxy closes the connection when the association ends
+            let mut b = [0u8; 1];
+            let closing = control.read(&mut b);
+            select(select(Box::pin(receive), closing), stop).await;
+            debug!("Udp association for dns closed");
+            close(&i);
+        });
+        Pipeline {
+            sender: AsyncMutex::new(Sender::Udp(send, Address::SocketAddress(target))),
+            inflight,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inflight.lock().unwrap().closed
+    }
+
+    /// Queries waiting for responses
+    pub fn len(&self) -> usize {
+        self.inflight.lock().unwrap().queries.len()
+    }
+
+    pub async fn query(&self, data: &[u8]) -> Result<Vec<u8>, SocksError> {
+        if data.len() < 2 {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidInput, "Dns message too short").into(),
+            );
+        }
+        let (tx, rx) = oneshot::channel();
+        let waiting = Waiting::new(&self.inflight, tx)?;
+        let mut message = data.to_vec();
+        message[..2].copy_from_slice(&waiting.id.to_be_bytes());
+        let sent = match *self.sender.lock().await {
+            Sender::Tcp(ref mut w) => send_message(w, &message).await,
+            Sender::Udp(ref mut s, ref a) => s.send_to(&message, a).await,
+        };
+        if let Err(e) = sent {
+            close(&self.inflight);
+            return Err(e.into());
+        }
+        let mut r = rx.await.map_err(|_e| closed())?;
+        if r.len() < 2 {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidData, "Dns response too short").into(),
+            );
+        }
+        r[..2].copy_from_slice(&data[..2]);
+        Ok(r)
+    }
+}
+
+impl<'a> Waiting<'a> {
+    fn new(inflight: &'a Shared, tx: oneshot::Sender<Vec<u8>>) -> io::Result<Waiting<'a>> {
+        let mut i = inflight.lock().unwrap();
+        if i.closed {
+            return Err(closed());
+        }
+        if i.queries.len() > usize::from(u16::MAX) {
+            return Err(io::Error::other("Too many dns queries in flight"));
+        }
+        let mut id = rand::random();
+        while i.queries.contains_key(&id) {
+            id = rand::random();
+        }
+        i.queries.insert(id, tx);
+        Ok(Waiting { inflight, id })
+    }
+}
+
+impl<'a> Drop for Waiting<'a> {
+    fn drop(&mut self) {
+        let mut i = self.inflight.lock().unwrap();
+        // still there if it's not answered
+        if i.queries.remove(&self.id).is_some() {
+            i.abandoned += 1;
+            if i.abandoned >= MAX_ABANDONED {
+                debug!("No responses over tunneled dns connection, closing it");
+                close_locked(&mut i);
+            }
+        }
+    }
+}
+
+fn new_inflight() -> (Shared, oneshot::Receiver<()>) {
+    let (tx, rx) = oneshot::channel();
+    let i = Inflight {
+        stop: Some(tx),
+        ..Inflight::default()
+    };
+    (Arc::new(Mutex::new(i)), rx)
+}
+
+fn answer(inflight: &Shared, response: Vec<u8>) {
+    if response.len() < 2 {
+        return;
+    }
+    let id = u16::from_be_bytes([response[0], response[1]]);
+    let mut i = inflight.lock().unwrap();
+    // even a late response shows the connection still works
+    i.abandoned = 0;
+    match i.queries.remove(&id) {
+        Some(tx) => {
+            let _ = tx.send(response);
+        }
+        None => debug!("Dns response {} is late or unexpected", id),
+    }
+}
+
+/// Fail the queries in flight, and the ones after
+fn close(inflight: &Shared) {
+    close_locked(&mut inflight.lock().unwrap());
+}
+
+fn close_locked(i: &mut Inflight) {
+    i.closed = true;
+    i.queries.clear();
+    if let Some(s) = i.stop.take() {
+        let _ = s.send(());
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "Tunneled dns connection closed",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pipeline, MAX_ABANDONED};
+    use crate::resolver::client::tcp::{read_message, send_message};
+    use futures::future::join_all;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time::{delay_for, timeout};
+
+    #[test]
+    fn responses_matched_by_id() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let mut l = TcpListener::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+                .await
+                .unwrap();
+            let addr = l.local_addr().unwrap();
+            // answers three queries in reverse order, then hangs up
+            tokio::spawn(async move {
+                let (mut s, _a) = l.accept().await.unwrap();
+                let mut queries = vec![];
+                for _ in 0..3 {
+                    queries.push(read_message(&mut s).await.unwrap());
+                }
+                for mut q in queries.into_iter().rev() {
+                    q[2] |= 0x80;
+                    send_message(&mut s, &q).await.unwrap();
+                }
+            });
+            let p = Pipeline::tcp(TcpStream::connect(&addr).await.unwrap());
+            let queries: Vec<_> = (1..=3u8).map(|n| vec![0, 9, 1, 0, n]).collect();
+            let responses = join_all(queries.iter().map(|q| p.query(q))).await;
+            for (n, r) in (1..=3u8).zip(responses) {
+                assert_eq!(r.unwrap(), vec![0, 9, 0x81, 0, n]);
+            }
+            assert!(p.query(&queries[0]).await.is_err());
+            assert!(p.is_closed());
+        });
+    }
+
+    #[test]
+    fn stops_answering() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let mut l = TcpListener::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+                .await
+                .unwrap();
+            let addr = l.local_addr().unwrap();
+            // answers the first query, then keeps the connection open in silence
+            tokio::spawn(async move {
+                let (mut s, _a) = l.accept().await.unwrap();
+                let mut q = read_message(&mut s).await.unwrap();
+                q[2] |= 0x80;
+                send_message(&mut s, &q).await.unwrap();
+                while read_message(&mut s).await.is_ok() {}
+            });
+            let p = Pipeline::tcp(TcpStream::connect(&addr).await.unwrap());
+            let q = vec![0, 9, 1, 0];
+            let wait = Duration::from_millis(100);
+            assert!(timeout(wait, p.query(&q)).await.unwrap().is_ok());
+            for _ in 0..MAX_ABANDONED {
+                assert!(!p.is_closed());
+                assert!(timeout(wait, p.query(&q)).await.is_err());
+                assert_eq!(p.len(), 0);
+            }
+            assert!(p.is_closed());
+            assert!(p.query(&q).await.is_err());
+        });
+    }
+
+    #[test]
+    fn answers_late() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let mut l = TcpListener::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+                .await
+                .unwrap();
+            let addr = l.local_addr().unwrap();
+            // answers every query, but slower than the client waits
+            tokio::spawn(async move {
+                let (mut s, _a) = l.accept().await.unwrap();
+                while let Ok(mut q) = read_message(&mut s).await {
+                    delay_for(Duration::from_millis(50)).await;
+                    q[2] |= 0x80;
+                    send_message(&mut s, &q).await.unwrap();
+                }
+            });
+            let p = Pipeline::tcp(TcpStream::connect(&addr).await.unwrap());
+            let q = vec![0, 9, 1, 0];
+            // like queries cancelled when another nameserver answers first
+            for _ in 0..MAX_ABANDONED * 2 {
+                assert!(timeout(Duration::from_millis(10), p.query(&q))
+                    .await
+                    .is_err());
+                delay_for(Duration::from_millis(100)).await;
+                assert!(!p.is_closed());
+            }
+            assert!(p.query(&q).await.is_ok());
+        });
+    }
+}
@@ -0,0 +1,85 @@
+//! Dns messages over a stream, each prefixed with a two byte length
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+
+use byteorder::{BigEndian, WriteBytesExt};
+use failure::Error;
+use net2::TcpBuilder;
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+use super::socks::CursorRead;
+use crate::conf::EgressAddr;
+use asocks5::connect_socks_socket_addr;
+
+/// Connect to a server, through the egress if there is one
+pub async fn connect(egress: Option<EgressAddr>, addr: SocketAddr) -> Result<TcpStream, Error> {
+    let s = match egress {
+        None => TcpStream::connect(&addr).await?,
+        Some(EgressAddr::From(ip)) => {
+            let builder = if ip.is_ipv4() {
+                TcpBuilder::new_v4()
+            } else {
+                TcpBuilder::new_v6()
+            }?;
+            let s = builder.bind((ip, 0))?.to_tcp_stream()?;
+            TcpStream::connect_std(s, &addr).await?
+        }
+        Some(EgressAddr::Socks5(p)) => {
+            let mut s = TcpStream::connect(&p).await?;
+            connect_socks_socket_addr(&mut s, addr).await?;
+            s
+        }
+    };
+    Ok(s)
+}
+
+pub async fn send_message<S: AsyncWrite + Unpin>(stream: &mut S, data: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(data.len()).map_err(|_e| {
+        let e = format!("Dns message of {} bytes is too long", data.len());
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    })?;
+    let mut lens = [0u8; 2];
+    lens.as_mut()
+        .write_u16::<BigEndian>(len)
+        .expect("byteorder");
+    trace!("Sending length {}, {:?}", len, lens);
+    let mut buf = Vec::with_capacity(data.len() + 2);
+    buf.extend_from_slice(&lens);
+    buf.extend_from_slice(data);
+    stream.write_all(&buf).await
+}
+
+pub async fn read_message<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut b = [0u8; 2];
+    stream.read_exact(&mut b).await?;
+    trace!("Read reply length {:?}", b);
+    let mut rdr = io::Cursor::new(b);
+    let len = rdr.read_u16be().expect("read u16");
+    trace!("Reply length is {}", len);
+    let mut buf = vec![0; len as usize];
+    if let Err(e) = stream.read_exact(&mut buf).await {
+        warn!("Error reading {} bytes: {:?}", len, e);
+        return Err(e);
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_message, send_message};
+
+    #[test]
+    fn message_lengths() {
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut buf = vec![];
+            send_message(&mut buf, &[7; 65535]).await.unwrap();
+            assert_eq!(&buf[..3], &[0xff, 0xff, 7]);
+            let m = read_message(&mut &buf[..]).await.unwrap();
+            assert_eq!(m.len(), 65535);
+            assert!(send_message(&mut vec![], &[0; 65536]).await.is_err());
+        });
+    }
+}
@@ -0,0 +1,166 @@
+//! Dns over tls, RFC 7858
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use failure::Error;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+use super::tcp::{connect, read_message, send_message};
+use crate::conf::EgressAddr;
+
+/// Connections kept open after answering
+const MAX_IDLE: usize = 2;
+
+pub struct TlsClient {
+    addr: SocketAddr,
+    name: String,
+    egress: Option<EgressAddr>,
+    connector: TlsConnector,
+    /// each is used for one query at a time
+    idle: Mutex<Vec<TlsStream<TcpStream>>>,
+}
+
+impl fmt::Debug for TlsClient {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "Tls({:?}, {})", self.addr, self.name)?;
+        if let Some(e) = self.egress {
+            write!(f, " via {:?}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl TlsClient {
+    pub fn new(addr: SocketAddr, name: &str, egress: Option<EgressAddr>) -> TlsClient {
+        TlsClient {
+            addr,
+            name: name.into(),
+            egress,
+            connector: tls_connector(vec![]),
+            idle: Mutex::new(vec![]),
+        }
+    }
+
+    #[cfg(test)]
+    fn with_connector(mut self, connector: TlsConnector) -> TlsClient {
+        self.connector = connector;
+        self
+    }
+
+    pub async fn get(&self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let reused = self.idle.lock().unwrap().pop();
+        if let Some(mut s) = reused {
+            match exchange(&mut s, &data).await {
+                Ok(r) => {
+                    self.keep(s);
+                    return Ok(r);
+                }
+                // the server may have closed it while idle
+                Err(e) => debug!("Reconnecting to {:?}: {}", self, e),
+            }
+        }
+        let mut s = tls_connect(&self.connector, self.egress, self.addr, &self.name).await?;
+        let r = exchange(&mut s, &data).await?;
+        self.keep(s);
+        Ok(r)
+    }
+
+    /// only after a complete answer, so no answer is left unread
+    fn keep(&self, s: TlsStream<TcpStream>) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE {
+            idle.push(s);
+        }
+    }
+}
+
+async fn exchange(s: &mut TlsStream<TcpStream>, data: &[u8]) -> Result<Vec<u8>, Error> {
+    send_message(s, data).await?;
+    let r = read_message(s).await?;
+    Ok(r)
+}
+
+/// Verify servers using the bundled Mozilla roots
+pub fn tls_connector(alpn: Vec<Vec<u8>>) -> TlsConnector {
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    config.alpn_protocols = alpn;
+    TlsConnector::from(Arc::new(config))
+}
+
+pub async fn tls_connect(
+    connector: &TlsConnector,
+    egress: Option<EgressAddr>,
+    addr: SocketAddr,
+    name: &str,
+) -> Result<TlsStream<TcpStream>, Error> {
+    let n = DNSNameRef::try_from_ascii_str(name)
+        .map_err(|_e| format_err!("{} is not a valid dns name for tls", name))?;
+    let s = connect(egress, addr).await?;
+    let s = connector
+        .connect(n, s)
+        .await
+        .map_err(|e| format_err!("Tls error connecting to {} at {:?}: {}", name, addr, e))?;
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TlsClient;
+    use crate::conf::TlsIdentity;
+    use crate::resolver::client::tcp::{read_message, send_message};
+    use crate::resolver::serve::tcp::tests::{query, unused_addr};
+    use crate::resolver::serve::tls::tls_acceptor;
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::TlsConnector;
+
+    #[test]
+    fn reuses_connections() {
+        let id = TlsIdentity {
+            cert: "test/tls/cert.pem".into(),
+            key: "test/tls/key.pem".into(),
+        };
+        let acceptor = tls_acceptor(&id, vec![]).unwrap();
+        let mut config = ClientConfig::new();
+        let mut ca = BufReader::new(File::open("test/tls/ca.pem").unwrap());
+        config.root_store.add_pem_file(&mut ca).unwrap();
+        let connector = TlsConnector::from(Arc::new(config));
+        let addr = unused_addr();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let a = accepted.clone();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let mut l = TcpListener::bind(&addr).await.unwrap();
+            // echoes two queries on each connection, then closes it
+            tokio::spawn(async move {
+                while let Ok((s, _)) = l.accept().await {
+                    a.fetch_add(1, Ordering::SeqCst);
+                    let mut s = acceptor.accept(s).await.unwrap();
+                    tokio::spawn(async move {
+                        for _ in 0..2 {
+                            let m = read_message(&mut s).await.unwrap();
+                            send_message(&mut s, &m).await.unwrap();
+                        }
+                    });
+                }
+            });
+            let c = TlsClient::new(addr, "dns.test", None).with_connector(connector);
+            for id in 1..=3 {
+                assert_eq!(c.get(query(id)).await.unwrap(), query(id));
+            }
+        });
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+}
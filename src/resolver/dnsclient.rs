@@ -1,17 +1,18 @@
 use std::net::SocketAddr;
 
+use super::client::https::DohClient;
 use super::client::socks::SockGetterAsync;
+use super::client::tcp::{connect, read_message, send_message};
+use super::client::tls::TlsClient;
 use super::client::udp::udp_get;
 use crate::conf::EgressAddr;
 use crate::conf::NameServer;
 use crate::conf::NameServerRemote;
-use crate::resolver::client::TIMEOUT;
-use asocks5::socks::SocksError;
+use failure::Error;
 
 use std::io;
 use std::net::IpAddr;
 use std::net::UdpSocket as StdUdpSocket;
-use std::time::Duration;
 use tokio::net::UdpSocket;
 use tokio_net::driver::Handle;
 
@@ -19,11 +20,28 @@ use tokio_net::driver::Handle;
 pub enum DnsClient {
     Direct(SocketAddr),
     DirectBind(SocketAddr, IpAddr),
+    /// A new connection for each query, from the address if there is one
+    Tcp(SocketAddr, Option<EgressAddr>),
     ViaSocks5(SockGetterAsync),
+    /// These can be used with any kind of egress
+    Tls(TlsClient),
+    Https(DohClient),
 }
 
 impl DnsClient {
     pub fn new(up: &NameServer) -> DnsClient {
+        let egress = up.egress.as_ref().map(|e| e.val().addr());
+        match up.remote {
+            NameServerRemote::Tls(a, ref n) => return DnsClient::Tls(TlsClient::new(a, n, egress)),
+            NameServerRemote::Https(ref s) => {
+                return DnsClient::Https(DohClient::new(s.clone(), egress))
+            }
+            // socks5 proxies get a pipeline of their own
+            NameServerRemote::Tcp(a) if !matches!(egress, Some(EgressAddr::Socks5(_))) => {
+                return DnsClient::Tcp(a, egress)
+            }
+            _ => {}
+        }
         if let Some(ref e) = up.egress {
             let e = e.val();
             match e.addr() {
@@ -40,10 +58,16 @@ impl DnsClient {
         }
     }
 
-    pub async fn resolve(&self, data: Vec<u8>) -> Result<Vec<u8>, SocksError> {
+    pub async fn resolve(&self, data: Vec<u8>) -> Result<Vec<u8>, Error> {
         match self {
-            DnsClient::ViaSocks5(s) => s.get(data).await,
-            // FIXME these always use udp even when tcp is configured
+            DnsClient::ViaSocks5(s) => Ok(s.get(data).await?),
+            DnsClient::Tls(s) => s.get(data).await,
+            DnsClient::Https(s) => s.get(data).await,
+            DnsClient::Tcp(a, e) => {
+                let mut s = connect(*e, *a).await?;
+                send_message(&mut s, &data).await?;
+                Ok(read_message(&mut s).await?)
+            }
             DnsClient::Direct(s) => {
                 let vec = udp_get(s, data).await?;
                 Ok(vec)
@@ -59,19 +83,44 @@ impl DnsClient {
 fn ns_sock_addr(ns: &NameServerRemote) -> SocketAddr {
     match ns {
         NameServerRemote::Udp(a) => *a,
-        NameServerRemote::Tcp(a) => {
-            warn!("Dns over TCP isn't support yet even though it's configured");
-            *a
-        }
+        NameServerRemote::Tcp(a) => *a,
+        NameServerRemote::Tls(a, _) => *a,
+        NameServerRemote::Https(s) => s.addr,
     }
 }
 
 pub async fn udp_bind_get(addr: SocketAddr, ip: IpAddr, data: Vec<u8>) -> io::Result<Vec<u8>> {
     let s = StdUdpSocket::bind(SocketAddr::from((ip, 0)))?;
-    s.set_read_timeout(Some(Duration::from_secs(TIMEOUT)))?;
     let mut s = UdpSocket::from_std(s)?;
     s.send_to(&data, &addr).await?;
-    let mut buf = vec![0; 998];
+    let mut buf = vec![0; 65535];
     let (nb, _a) = s.recv_from(&mut buf).await?;
     Ok(buf[..nb].into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::DnsClient;
+    use crate::conf::main::DEFAULT_TIMEOUT;
+    use crate::conf::{NameServer, NameServerRemote};
+    use crate::resolver::serve::tcp::serve_tcp;
+    use crate::resolver::serve::tcp::tests::{echo_resolver, query, unused_addr};
+
+    #[test]
+    fn tcp_nameserver() {
+        let addr = unused_addr();
+        let c = DnsClient::new(&NameServer {
+            egress: None,
+            remote: NameServerRemote::Tcp(addr),
+            timeout: DEFAULT_TIMEOUT,
+        });
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let r = rt.block_on(async move {
+            // nothing listens for udp at the address
+            serve_tcp(addr, echo_resolver()).unwrap();
+            c.resolve(query(5)).await.unwrap()
+        });
+        assert_eq!(r[1], 5);
+        assert!(r[2] & 0x80 != 0);
+    }
+}
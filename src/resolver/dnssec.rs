@@ -0,0 +1,1096 @@
+//! Validate answers with DNSSEC, RFC 4035,
+//! following the chain of trust from the root with DS and DNSKEY queries
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use data_encoding::BASE32HEX_NOPAD;
+use failure::Error;
+use lru::LruCache;
+use trust_dns::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns::rr::dnssec::{Algorithm, DigestType, TrustAnchor, Verifier};
+use trust_dns::rr::rdata::{DNSSECRData, DNSSECRecordType, DNSKEY, DS, SIG};
+use trust_dns::rr::rdata::{NSEC as NsecData, NSEC3 as Nsec3Data};
+use trust_dns::rr::{DNSClass, Name, RData, Record, RecordType};
+
+use super::ecs;
+use super::group::ClientGroup;
+use crate::conf::DnssecConf;
+
+const RRSIG: RecordType = RecordType::DNSSEC(DNSSECRecordType::RRSIG);
+const NSEC: RecordType = RecordType::DNSSEC(DNSSECRecordType::NSEC);
+const NSEC3: RecordType = RecordType::DNSSEC(DNSSECRecordType::NSEC3);
+const DS_TYPE: RecordType = RecordType::DNSSEC(DNSSECRecordType::DS);
+const DNSKEY_TYPE: RecordType = RecordType::DNSSEC(DNSSECRecordType::DNSKEY);
+
+/// Zone keys are trusted this long before they're fetched again
+const KEYS_TTL: Duration = Duration::from_secs(3600);
+/// Names whose zone keys are remembered
+const KEYS_CACHE_SIZE: usize = 4096;
+
+/// An answer failed validation
+#[derive(Debug, Fail)]
+#[fail(display = "Bogus dnssec answer: {}", _0)]
+pub struct Bogus(pub String);
+
+/// The keys of a zone, proven by the chain of trust
+#[derive(Clone, Debug)]
+enum ZoneKeys {
+    Secure(Vec<DNSKEY>),
+    /// The zone, or one above it, is proven to be unsigned
+    Insecure,
+}
+
+/// What the DS records of a name say about it
+enum Delegation {
+    Signed(Vec<DS>),
+    Unsigned,
+    /// No proof of a zone cut without DS records, so the name is assumed to
+    /// be in the zone above, whose keys must then sign its answers
+    NotACut,
+}
+
+pub struct Validator {
+    zones: Vec<Bytes>,
+    anchor: TrustAnchor,
+    /// Of the root zone
+    anchor_ds: Vec<DS>,
+    /// By name, the zone it's in and its keys
+    keys: Mutex<LruCache<Name, (Name, ZoneKeys, Instant)>>,
+}
+
+impl Validator {
+    pub fn new(conf: &DnssecConf) -> Result<Validator, Error> {
+        let v = match conf.trust_anchor {
+            Some(ref p) => Validator::with_anchors(conf.zones.clone(), read_anchors(p)?),
+            None => Validator {
+                anchor: TrustAnchor::default(),
+                ..Validator::with_anchors(conf.zones.clone(), vec![])
+            },
+        };
+        Ok(v)
+    }
+
+    fn with_anchors(zones: Vec<Bytes>, anchor_ds: Vec<DS>) -> Validator {
+        Validator {
+            zones,
+            anchor: TrustAnchor::new(),
+            anchor_ds,
+            keys: Mutex::new(LruCache::new(KEYS_CACHE_SIZE)),
+        }
+    }
+
+    /// whether answers in the domain zone, or `else`, are validated
+    pub fn covers(&self, zone: &[u8]) -> bool {
+        self.zones.iter().any(|z| z == zone)
+    }
+
+    /// Whether the response is secure, or an error if it's bogus.
+    /// The records needed are asked from the group.
+    pub async fn validate(&self, response: &Message, group: &ClientGroup) -> Result<bool, Error> {
+        match response.response_code() {
+            ResponseCode::NoError | ResponseCode::NXDomain => {}
+            _ => return Ok(false),
+        }
+        if response.truncated() {
+            return Err(Bogus("truncated answers can't be validated".into()).into());
+        }
+        let query = match response.queries().first() {
+            Some(q) => q,
+            None => return Err(Bogus("an answer without the question".into()).into()),
+        };
+        let now = unix_time();
+        let mut sets: Vec<(&[Record], &Name, RecordType)> = vec![];
+        let sections = [response.answers(), response.name_servers()];
+        for (i, section) in sections.iter().enumerate() {
+            for r in section.iter() {
+                let t = r.rr_type();
+                // only denials are checked in the authority section
+                let checked = i == 0 || t == RecordType::SOA || t == NSEC || t == NSEC3;
+                if checked && t != RRSIG && !sets.iter().any(|s| s.1 == r.name() && s.2 == t) {
+                    sets.push((section, r.name(), t));
+                }
+            }
+        }
+        if sets.is_empty() {
+            let name = query.name();
+            return match self.zone_keys(name, group).await?.1 {
+                ZoneKeys::Insecure => Ok(false),
+                ZoneKeys::Secure(_) => Err(Bogus(format!("{} has no signed denial", name)).into()),
+            };
+        }
+        let mut secure = true;
+        // rrsets expanded from wildcards, and the labels of the wildcards
+        let mut expanded = vec![];
+        for (section, name, t) in sets {
+            let sigs = rrsigs(section, name, t);
+            let signer = match sigs.first() {
+                Some(s) => s.signer_name().clone(),
+                None => match self.zone_keys(name, group).await?.1 {
+                    ZoneKeys::Insecure => {
+                        secure = false;
+                        continue;
+                    }
+                    ZoneKeys::Secure(_) => {
+                        return Err(Bogus(format!("{} {} is not signed", name, t)).into())
+                    }
+                },
+            };
+            if !signer.zone_of(name) {
+                return Err(Bogus(format!("{} signed {} {}", signer, name, t)).into());
+            }
+            match self.zone_keys(&signer, group).await? {
+                (_, ZoneKeys::Insecure) => secure = false,
+                (z, ZoneKeys::Secure(ref keys)) if z == signer => {
+                    let sigs: Vec<_> = sigs
+                        .into_iter()
+                        .filter(|s| *s.signer_name() == signer)
+                        .collect();
+                    // verified over the wildcard name if the signature has fewer labels
+                    let sig = verify_rrset(keys, name, t, section, &sigs, now)?;
+                    if sig.num_labels() < name.num_labels() {
+                        expanded.push((name, sig.num_labels()));
+                    }
+                }
+                _ => return Err(Bogus(format!("{} is not a signed zone", signer)).into()),
+            }
+        }
+        if secure {
+            let (name, t) = (answered_name(response, query), query.query_type());
+            let answered = response
+                .answers()
+                .iter()
+                .any(|r| r.name() == &name && r.rr_type() == t);
+            let nxdomain = response.response_code() == ResponseCode::NXDomain;
+            if !answered && !proves_denial(response.name_servers(), &name, t, nxdomain) {
+                let e = format!("No proof that {} {} doesn't exist", name, t);
+                return Err(Bogus(e).into());
+            }
+            for (name, labels) in expanded {
+                if !proves_expansion(response.name_servers(), name, labels) {
+                    let e = format!("No proof that {} doesn't exist to match a wildcard", name);
+                    return Err(Bogus(e).into());
+                }
+            }
+        }
+        Ok(secure)
+    }
+
+    /// The zone the name is in and its keys,
+    /// going down from the deepest zone remembered
+    async fn zone_keys(&self, name: &Name, group: &ClientGroup) -> Result<(Name, ZoneKeys), Error> {
+        let labels = usize::from(name.num_labels());
+        let mut depth = labels;
+        let (mut zone, mut keys) = loop {
+            let n = name.trim_to(depth);
+            if let Some(k) = self.remembered(&n) {
+                break k;
+            }
+            if depth == 0 {
+                let k = (Name::root(), self.root_keys(group).await?);
+                self.remember(&n, &k);
+                break k;
+            }
+            depth -= 1;
+        };
+        while depth < labels {
+            let parent_keys = match keys {
+                ZoneKeys::Secure(ref k) => k,
+                ZoneKeys::Insecure => break,
+            };
+            depth += 1;
+            let child = name.trim_to(depth);
+            match self.delegation(&child, parent_keys, group).await? {
+                Delegation::Signed(ds) => {
+                    keys = self.child_keys(&child, &ds, group).await?;
+                    zone = child.clone();
+                }
+                Delegation::Unsigned => {
+                    keys = ZoneKeys::Insecure;
+                    zone = child.clone();
+                }
+                Delegation::NotACut => {}
+            }
+            self.remember(&child, &(zone.clone(), keys.clone()));
+        }
+        Ok((zone, keys))
+    }
+
+    async fn root_keys(&self, group: &ClientGroup) -> Result<ZoneKeys, Error> {
+        let root = Name::root();
+        let r = fetch(group, &root, DNSKEY_TYPE).await?;
+        let trusted = |k: &DNSKEY| {
+            self.anchor.contains_dnskey_bytes(k.public_key())
+                || self
+                    .anchor_ds
+                    .iter()
+                    .any(|d| d.covers(&root, k).unwrap_or(false))
+        };
+        signed_keys(&root, &r, trusted)
+    }
+
+    async fn child_keys(
+        &self,
+        child: &Name,
+        ds: &[DS],
+        group: &ClientGroup,
+    ) -> Result<ZoneKeys, Error> {
+        let usable: Vec<_> = ds
+            .iter()
+            .filter(|d| supported(*d.algorithm()) && supported_digest(d.digest_type()))
+            .collect();
+        if usable.is_empty() {
+            // RFC 4035, 5.2
+            debug!(
+                "No supported DS records of {}, it's treated as unsigned",
+                child
+            );
+            return Ok(ZoneKeys::Insecure);
+        }
+        let r = fetch(group, child, DNSKEY_TYPE).await?;
+        let trusted = |k: &DNSKEY| usable.iter().any(|d| d.covers(child, k).unwrap_or(false));
+        signed_keys(child, &r, trusted)
+    }
+
+    /// Whether the child is a zone cut, and if it's signed,
+    /// the DS records being signed by the keys of the parent zone
+    async fn delegation(
+        &self,
+        child: &Name,
+        parent_keys: &[DNSKEY],
+        group: &ClientGroup,
+    ) -> Result<Delegation, Error> {
+        let r = fetch(group, child, DS_TYPE).await?;
+        let now = unix_time();
+        let ds: Vec<DS> = r
+            .answers()
+            .iter()
+            .filter(|a| a.name() == child)
+            .filter_map(|a| match a.rdata() {
+                RData::DNSSEC(DNSSECRData::DS(d)) => Some(d.clone()),
+                _ => None,
+            })
+            .collect();
+        if !ds.is_empty() {
+            let sigs = rrsigs(r.answers(), child, DS_TYPE);
+            verify_rrset(parent_keys, child, DS_TYPE, r.answers(), &sigs, now)?;
+            return Ok(Delegation::Signed(ds));
+        }
+        let denials = r.name_servers();
+        for d in denials {
+            let signed = |t| {
+                let sigs = rrsigs(denials, d.name(), t);
+                verify_rrset(parent_keys, d.name(), t, denials, &sigs, now).is_ok()
+            };
+            match d.rdata() {
+                RData::DNSSEC(DNSSECRData::NSEC(n))
+                    if d.name() == child && unsigned_cut(n.type_bit_maps()) && signed(NSEC) =>
+                {
+                    return Ok(Delegation::Unsigned);
+                }
+                RData::DNSSEC(DNSSECRData::NSEC3(n)) if signed(NSEC3) => {
+                    let h = Nsec3 {
+                        owner: nsec3_owner(d.name()),
+                        data: n,
+                    };
+                    if h.matches(child) && unsigned_cut(n.type_bit_maps()) {
+                        return Ok(Delegation::Unsigned);
+                    }
+                    if h.covers(child) && n.opt_out() {
+                        return Ok(Delegation::Unsigned);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Delegation::NotACut)
+    }
+
+    fn remembered(&self, name: &Name) -> Option<(Name, ZoneKeys)> {
+        let mut keys = self.keys.lock().unwrap();
+        match keys.get(&name.to_lowercase()) {
+            Some((z, k, t)) if t.elapsed() < KEYS_TTL => Some((z.clone(), k.clone())),
+            _ => None,
+        }
+    }
+
+    fn remember(&self, name: &Name, k: &(Name, ZoneKeys)) {
+        let v = (k.0.clone(), k.1.clone(), Instant::now());
+        self.keys.lock().unwrap().put(name.to_lowercase(), v);
+    }
+}
+
+/// Ask upstream for the signatures, and for the records unchecked
+pub fn request_signatures(m: &mut Message) {
+    m.set_checking_disabled(true);
+    let e = m.edns_mut();
+    e.set_dnssec_ok(true);
+    // RFC 6891 suggests 1232 to avoid fragmentation
+    if e.max_payload() < 1232 {
+        e.set_max_payload(1232);
+    }
+}
+
+/// Remove the records the client didn't ask for, RFC 4035 3.2.1
+pub fn restore_response(query: &Message, response: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if query.edns().is_some_and(|e| e.dnssec_ok()) {
+        return Ok(response);
+    }
+    let mut r = Message::from_vec(&response)?;
+    let t = query.queries()[0].query_type();
+    let keep = |records: Vec<Record>| -> Vec<Record> {
+        records
+            .into_iter()
+            .filter(|r| r.rr_type() == t || !matches!(r.rr_type(), RRSIG | NSEC | NSEC3))
+            .collect()
+    };
+    let answers = keep(r.take_answers());
+    r.insert_answers(answers);
+    let ns = keep(r.take_name_servers());
+    r.insert_name_servers(ns);
+    let additionals = keep(r.take_additionals());
+    r.insert_additionals(additionals);
+    let r = match query.edns() {
+        Some(_) => {
+            r.edns_mut().set_dnssec_ok(false);
+            r
+        }
+        None => ecs::without_edns(&r),
+    };
+    Ok(r.to_vec()?)
+}
+
+async fn fetch(group: &ClientGroup, name: &Name, t: RecordType) -> Result<Message, Error> {
+    let mut m = Message::new();
+    m.set_id(rand::random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(name.clone(), t));
+    request_signatures(&mut m);
+    let (r, _u) = group.resolve(&m.to_vec()?).await?;
+    let r = Message::from_vec(&r)?;
+    match r.response_code() {
+        ResponseCode::NoError | ResponseCode::NXDomain => Ok(r),
+        c => Err(format_err!("Error getting {} {}: {}", name, t, c)),
+    }
+}
+
+/// The DNSKEY records in the response, which must be signed by a trusted one
+fn signed_keys<F>(zone: &Name, response: &Message, trusted: F) -> Result<ZoneKeys, Error>
+where
+    F: Fn(&DNSKEY) -> bool,
+{
+    let keys: Vec<DNSKEY> = response
+        .answers()
+        .iter()
+        .filter(|a| a.name() == zone)
+        .filter_map(|a| match a.rdata() {
+            RData::DNSSEC(DNSSECRData::DNSKEY(k)) => Some(k.clone()),
+            _ => None,
+        })
+        .collect();
+    let trusted: Vec<DNSKEY> = keys.iter().filter(|k| trusted(k)).cloned().collect();
+    if trusted.is_empty() {
+        return Err(Bogus(format!("No trusted keys of {}", zone)).into());
+    }
+    let sigs = rrsigs(response.answers(), zone, DNSKEY_TYPE);
+    let now = unix_time();
+    verify_rrset(&trusted, zone, DNSKEY_TYPE, response.answers(), &sigs, now)?;
+    let keys = keys
+        .into_iter()
+        .filter(|k| k.zone_key() && !k.revoke())
+        .collect();
+    Ok(ZoneKeys::Secure(keys))
+}
+
+/// The rrset in the records is signed by one of the keys, with the signature
+fn verify_rrset<'a>(
+    keys: &[DNSKEY],
+    name: &Name,
+    t: RecordType,
+    records: &[Record],
+    sigs: &[&'a SIG],
+    now: u32,
+) -> Result<&'a SIG, Bogus> {
+    let class = records
+        .iter()
+        .find(|r| r.rr_type() == t && r.name() == name)
+        .map_or(DNSClass::IN, Record::dns_class);
+    for s in sigs {
+        // serial number arithmetic, RFC 4034 3.1.5
+        let current = now.wrapping_sub(s.sig_inception()) as i32 >= 0
+            && s.sig_expiration().wrapping_sub(now) as i32 >= 0;
+        if !current {
+            continue;
+        }
+        let verified = keys
+            .iter()
+            .filter(|k| k.algorithm() == s.algorithm())
+            .filter(|k| k.calculate_key_tag().ok() == Some(s.key_tag()))
+            .any(|k| k.verify_rrsig(name, class, s, records).is_ok());
+        if verified {
+            return Ok(s);
+        }
+    }
+    Err(Bogus(format!("No valid signature of {} {}", name, t)))
+}
+
+/// The signatures of the rrset in the records
+fn rrsigs<'a>(records: &'a [Record], name: &Name, t: RecordType) -> Vec<&'a SIG> {
+    records
+        .iter()
+        .filter(|r| r.rr_type() == RRSIG && r.name() == name)
+        .filter_map(|r| match r.rdata() {
+            RData::DNSSEC(DNSSECRData::SIG(s)) if s.type_covered() == t => Some(s),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The name the answer is for, at the end of the chain of CNAME records
+fn answered_name(response: &Message, query: &Query) -> Name {
+    let mut name = query.name().clone();
+    if query.query_type() == RecordType::CNAME {
+        return name;
+    }
+    // each step takes one record, so a loop can't go on forever
+    for _ in response.answers() {
+        let target = response.answers().iter().find_map(|r| match r.rdata() {
+            RData::CNAME(t) if r.name() == &name => Some(t.clone()),
+            _ => None,
+        });
+        match target {
+            Some(t) => name = t,
+            None => break,
+        }
+    }
+    name
+}
+
+/// The signed NSEC or NSEC3 records prove that the name doesn't exist,
+/// or that it has no records of the type, RFC 4035 5.4 and RFC 5155 8
+fn proves_denial(records: &[Record], name: &Name, t: RecordType, nxdomain: bool) -> bool {
+    let (nsec, nsec3) = denial_records(records);
+    if !nsec.is_empty() {
+        nsec_denial(&nsec, name, t, nxdomain)
+    } else {
+        nsec3_denial(&nsec3, name, t, nxdomain)
+    }
+}
+
+/// The name doesn't exist, and the wildcard with the labels is the closest
+/// match, so that an answer from it isn't replayed for other names,
+/// RFC 4035 5.3.4 and RFC 5155 8.8
+fn proves_expansion(records: &[Record], name: &Name, labels: u8) -> bool {
+    let (nsec, nsec3) = denial_records(records);
+    let labels = usize::from(labels);
+    if !nsec.is_empty() {
+        nsec.iter().any(|(o, n)| {
+            let next = n.next_domain_name();
+            let encloser = common_labels(name, o).max(common_labels(name, next));
+            covers(*o, next, name) && encloser == labels
+        })
+    } else {
+        let next_closer = name.trim_to(labels + 1);
+        nsec3.iter().any(|h| h.covers(&next_closer))
+    }
+}
+
+fn denial_records(records: &[Record]) -> (Vec<(&Name, &NsecData)>, Vec<Nsec3<'_>>) {
+    let mut nsec = vec![];
+    let mut nsec3 = vec![];
+    for r in records {
+        match r.rdata() {
+            RData::DNSSEC(DNSSECRData::NSEC(n)) => nsec.push((r.name(), n)),
+            RData::DNSSEC(DNSSECRData::NSEC3(n)) => nsec3.push(Nsec3 {
+                owner: nsec3_owner(r.name()),
+                data: n,
+            }),
+            _ => {}
+        }
+    }
+    (nsec, nsec3)
+}
+
+fn nsec_denial(nsec: &[(&Name, &NsecData)], name: &Name, t: RecordType, nxdomain: bool) -> bool {
+    let lacks = |n: &NsecData| no_type(n.type_bit_maps(), t);
+    if !nxdomain && nsec.iter().any(|(o, n)| *o == name && lacks(n)) {
+        return true;
+    }
+    let covering = nsec
+        .iter()
+        .find(|(o, n)| covers(*o, n.next_domain_name(), name));
+    let (owner, n) = match covering {
+        Some(c) => c,
+        None => return false,
+    };
+    // the longest name that exists above it
+    let depth = common_labels(name, owner).max(common_labels(name, n.next_domain_name()));
+    let wildcard = match wildcard_of(&name.trim_to(depth)) {
+        Some(w) => w,
+        None => return false,
+    };
+    if nxdomain {
+        nsec.iter()
+            .any(|(o, n)| covers(*o, n.next_domain_name(), &wildcard))
+    } else {
+        nsec.iter().any(|(o, n)| **o == wildcard && lacks(n))
+    }
+}
+
+fn nsec3_denial(nsec3: &[Nsec3], name: &Name, t: RecordType, nxdomain: bool) -> bool {
+    let lacks = |h: &Nsec3| no_type(h.data.type_bit_maps(), t);
+    if !nxdomain && nsec3.iter().any(|h| h.matches(name) && lacks(h)) {
+        return true;
+    }
+    let (encloser, opt_out) = match closest_encloser(nsec3, name) {
+        Some(x) => x,
+        None => return false,
+    };
+    if !nxdomain && t == DS_TYPE && opt_out {
+        return true;
+    }
+    let wildcard = match wildcard_of(&encloser) {
+        Some(w) => w,
+        None => return false,
+    };
+    if nxdomain {
+        nsec3.iter().any(|h| h.covers(&wildcard))
+    } else {
+        nsec3.iter().any(|h| h.matches(&wildcard) && lacks(h))
+    }
+}
+
+/// The closest encloser proof, RFC 5155 8.3: a name above that exists,
+/// and the one below it on the way to the name that doesn't.
+/// Also whether the NSEC3 record covering the latter has opt-out set.
+fn closest_encloser(nsec3: &[Nsec3], name: &Name) -> Option<(Name, bool)> {
+    let labels = usize::from(name.num_labels());
+    for depth in (0..labels).rev() {
+        let encloser = name.trim_to(depth);
+        if nsec3.iter().any(|h| h.matches(&encloser)) {
+            let next_closer = name.trim_to(depth + 1);
+            let covering = nsec3.iter().find(|h| h.covers(&next_closer))?;
+            return Some((encloser, covering.data.opt_out()));
+        }
+    }
+    None
+}
+
+/// An NSEC3 record, with the hash in its owner name
+struct Nsec3<'a> {
+    owner: String,
+    data: &'a Nsec3Data,
+}
+
+impl<'a> Nsec3<'a> {
+    fn hash(&self, name: &Name) -> Option<String> {
+        let n = self.data;
+        let h = n.hash_algorithm().hash(n.salt(), name, n.iterations());
+        h.ok().map(|h| base32hex(h.as_ref()))
+    }
+
+    fn matches(&self, name: &Name) -> bool {
+        self.hash(name).is_some_and(|h| h == self.owner)
+    }
+
+    fn covers(&self, name: &Name) -> bool {
+        let next = base32hex(self.data.next_hashed_owner_name());
+        self.hash(name)
+            .is_some_and(|h| covers(&self.owner, &next, &h))
+    }
+}
+
+fn nsec3_owner(name: &Name) -> String {
+    let label = name.iter().next().unwrap_or_default();
+    String::from_utf8_lossy(label).to_lowercase()
+}
+
+/// Strictly between the owner and the next name,
+/// the last one in the zone wraps around to the first
+fn covers<T: Ord + ?Sized>(owner: &T, next: &T, name: &T) -> bool {
+    if owner < next {
+        owner < name && name < next
+    } else {
+        name > owner || name < next
+    }
+}
+
+/// The type bitmap proves there's no record of the type. The NSEC records at
+/// a delegation are from the zone above, which only has NS and DS records.
+fn no_type(types: &[RecordType], t: RecordType) -> bool {
+    let cut = types.contains(&RecordType::NS) && !types.contains(&RecordType::SOA);
+    !types.contains(&t) && !types.contains(&RecordType::CNAME) && (!cut || t == DS_TYPE)
+}
+
+/// Labels at the end that are the same
+fn common_labels(a: &Name, b: &Name) -> usize {
+    let same = |(x, y): &(&[u8], &[u8])| x.eq_ignore_ascii_case(y);
+    a.iter().rev().zip(b.iter().rev()).take_while(same).count()
+}
+
+fn wildcard_of(name: &Name) -> Option<Name> {
+    Name::from_ascii("*").ok().map(|w| w.append_domain(name))
+}
+
+/// Types of a delegation without DS records
+fn unsigned_cut(types: &[RecordType]) -> bool {
+    types.contains(&RecordType::NS)
+        && !types.contains(&DS_TYPE)
+        && !types.contains(&RecordType::SOA)
+}
+
+fn supported(a: Algorithm) -> bool {
+    matches!(
+        a,
+        Algorithm::RSASHA256
+            | Algorithm::RSASHA512
+            | Algorithm::ECDSAP256SHA256
+            | Algorithm::ECDSAP384SHA384
+            | Algorithm::ED25519
+    )
+}
+
+fn supported_digest(d: DigestType) -> bool {
+    matches!(
+        d,
+        DigestType::SHA1 | DigestType::SHA256 | DigestType::SHA384
+    )
+}
+
+/// Lowercase base32 with the extended hex alphabet, for NSEC3 hashes, RFC 4648
+fn base32hex(data: &[u8]) -> String {
+    BASE32HEX_NOPAD.encode(data).to_lowercase()
+}
+
+fn unix_time() -> u32 {
+    let t = SystemTime::now().duration_since(UNIX_EPOCH);
+    t.unwrap_or_default().as_secs() as u32
+}
+
+/// DS records of the root zone, as published by IANA, e.g.
+/// `. 172800 IN DS 20326 8 2 E06D44B8...`
+fn read_anchors(path: &Path) -> Result<Vec<DS>, Error> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format_err!("Can't read trust anchor {:?}: {}", path, e))?;
+    let mut anchors = vec![];
+    for l in text.lines() {
+        let l = l.split(';').next().unwrap_or_default().trim();
+        if l.is_empty() {
+            continue;
+        }
+        let ds = parse_ds(l).ok_or_else(|| format_err!("Bad trust anchor {:?}", l))?;
+        anchors.push(ds);
+    }
+    if anchors.is_empty() {
+        return Err(format_err!("No trust anchor in {:?}", path));
+    }
+    Ok(anchors)
+}
+
+fn parse_ds(line: &str) -> Option<DS> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.first() != Some(&".") {
+        return None;
+    }
+    let i = fields.iter().position(|f| *f == "DS")?;
+    let key_tag = fields.get(i + 1)?.parse().ok()?;
+    let algorithm = Algorithm::from_u8(fields.get(i + 2)?.parse().ok()?);
+    let digest_type = DigestType::from_u8(fields.get(i + 3)?.parse().ok()?).ok()?;
+    let hex: String = fields.get(i + 4..)?.concat();
+    if hex.is_empty() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    let digest = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(DS::new(key_tag, algorithm, digest_type, digest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base32hex, parse_ds, restore_response, Bogus, Validator};
+    use super::{NSEC, RRSIG};
+    use crate::conf::{NameServer, NameServerGroup, NameServerRemote, Strategy};
+    use crate::resolver::group::ClientGroup;
+    use std::net::{Ipv4Addr, UdpSocket};
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::Duration;
+    use trust_dns::op::{Edns, Message, MessageType, Query, ResponseCode};
+    use trust_dns::rr::dnssec::tbs::rrset_tbs_with_sig;
+    use trust_dns::rr::dnssec::{Algorithm, DigestType, KeyFormat, KeyPair};
+    use trust_dns::rr::dnssec::{Nsec3HashAlgorithm, Private};
+    use trust_dns::rr::dnssec::{TrustAnchor, Verifier};
+    use trust_dns::rr::rdata::{DNSSECRData, DNSKEY, DS, SIG, SOA};
+    use trust_dns::rr::rdata::{NSEC as NsecData, NSEC3 as Nsec3Data};
+    use trust_dns::rr::{DNSClass, Name, RData, Record, RecordType};
+
+    const ALGORITHM: Algorithm = Algorithm::ED25519;
+
+    struct Zone {
+        name: Name,
+        key: KeyPair<Private>,
+    }
+
+    impl Zone {
+        fn new(name: &str) -> Zone {
+            let pkcs8 = KeyPair::generate_pkcs8(ALGORITHM).unwrap();
+            Zone {
+                name: Name::from_str(name).unwrap(),
+                key: KeyFormat::Pkcs8
+                    .decode_key(&pkcs8, None, ALGORITHM)
+                    .unwrap(),
+            }
+        }
+
+        fn dnskey(&self) -> Record {
+            let k = self.key.to_dnskey(ALGORITHM).unwrap();
+            record(
+                &self.name.to_string(),
+                RData::DNSSEC(DNSSECRData::DNSKEY(k)),
+            )
+        }
+
+        fn ds(&self) -> DS {
+            self.key
+                .to_ds(&self.name, ALGORITHM, DigestType::SHA256)
+                .unwrap()
+        }
+
+        /// the rrset and its signature
+        fn sign(&self, mut rrset: Vec<Record>) -> Vec<Record> {
+            let r = &rrset[0];
+            let now = super::unix_time();
+            let key = self.key.to_dnskey(ALGORITHM).unwrap();
+            let key_tag = key.calculate_key_tag().unwrap();
+            let sig = SIG::new(
+                r.rr_type(),
+                ALGORITHM,
+                r.name().num_labels(),
+                r.ttl(),
+                now + 3600,
+                now - 3600,
+                key_tag,
+                self.name.clone(),
+                vec![],
+            );
+            let tbs = rrset_tbs_with_sig(r.name(), DNSClass::IN, &sig, &rrset).unwrap();
+            let sig = sig.set_sig(self.key.sign(ALGORITHM, &tbs).unwrap());
+            let mut s = Record::from_rdata(
+                r.name().clone(),
+                r.ttl(),
+                RData::DNSSEC(DNSSECRData::SIG(sig)),
+            );
+            s.set_rr_type(RRSIG);
+            rrset.push(s);
+            rrset
+        }
+    }
+
+    fn record(name: &str, rdata: RData) -> Record {
+        Record::from_rdata(Name::from_str(name).unwrap(), 300, rdata)
+    }
+
+    fn a(name: &str, last: u8) -> Record {
+        record(name, RData::A(Ipv4Addr::new(192, 0, 2, last)))
+    }
+
+    /// answers with the records of the name and type,
+    /// or the NSEC records of the name if there are none
+    fn nameserver(data: Vec<Record>) -> NameServerGroup {
+        let s = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = s.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 4096];
+            while let Ok((n, p)) = s.recv_from(&mut buf) {
+                let mut r = Message::from_vec(&buf[..n]).unwrap();
+                let q = r.queries()[0].clone();
+                let of = |t: RecordType| {
+                    data.iter()
+                        .filter(|x| x.name() == q.name())
+                        .filter(|x| match x.rdata() {
+                            RData::DNSSEC(DNSSECRData::SIG(s)) => s.type_covered() == t,
+                            _ => x.rr_type() == t,
+                        })
+                        .cloned()
+                        .collect::<Vec<_>>()
+                };
+                r.set_message_type(MessageType::Response);
+                let answers = of(q.query_type());
+                if answers.is_empty() {
+                    r.insert_name_servers(of(NSEC));
+                }
+                r.insert_answers(answers);
+                s.send_to(&r.to_vec().unwrap(), p).unwrap();
+            }
+        });
+        let ns = NameServer {
+            egress: None,
+            remote: NameServerRemote::Udp(addr),
+            timeout: Duration::from_secs(1),
+        };
+        NameServerGroup {
+            strategy: Strategy::Sequential,
+            servers: vec![ns],
+        }
+    }
+
+    fn response(name: &str, answers: Vec<Record>) -> Message {
+        let mut m = Message::new();
+        m.set_message_type(MessageType::Response)
+            .add_query(Query::query(Name::from_str(name).unwrap(), RecordType::A));
+        m.insert_answers(answers);
+        m
+    }
+
+    /// the signed root and example zones, and the unsigned insecure zone
+    fn zones() -> (Zone, Zone, ClientGroup) {
+        let root = Zone::new(".");
+        let example = Zone::new("example.");
+        let mut data = vec![];
+        data.extend(root.sign(vec![root.dnskey()]));
+        let ds = RData::DNSSEC(DNSSECRData::DS(example.ds()));
+        data.extend(root.sign(vec![record("example.", ds)]));
+        let nsec = NsecData::new(Name::root(), vec![RecordType::NS, NSEC, RRSIG]);
+        let nsec = RData::DNSSEC(DNSSECRData::NSEC(nsec));
+        data.extend(root.sign(vec![record("insecure.", nsec)]));
+        data.extend(example.sign(vec![example.dnskey()]));
+        let group = ClientGroup::new(&nameserver(data));
+        (root, example, group)
+    }
+
+    #[test]
+    fn chain_of_trust() {
+        let (root, example, group) = zones();
+        let validator = Validator::with_anchors(vec!["else".into()], vec![root.ds()]);
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let is_bogus = |r: Result<bool, failure::Error>| r.unwrap_err().downcast::<Bogus>().is_ok();
+        rt.block_on(async {
+            let signed = example.sign(vec![a("www.example.", 1)]);
+            let r = response("www.example.", signed.clone());
+            assert!(validator.validate(&r, &group).await.unwrap());
+            let r = response("www.insecure.", vec![a("www.insecure.", 2)]);
+            assert!(!validator.validate(&r, &group).await.unwrap());
+
+            let mut forged = signed.clone();
+            forged[0] = a("www.example.", 3);
+            let r = response("www.example.", forged);
+            assert!(is_bogus(validator.validate(&r, &group).await));
+            let r = response("www.example.", vec![a("www.example.", 1)]);
+            assert!(is_bogus(validator.validate(&r, &group).await));
+
+            let other = Zone::new(".");
+            let untrusted = Validator::with_anchors(vec!["else".into()], vec![other.ds()]);
+            let r = response("www.example.", signed);
+            assert!(is_bogus(untrusted.validate(&r, &group).await));
+        });
+
+        // signatures are only for clients asking for them
+        let mut q = Message::new();
+        q.add_query(Query::query(
+            Name::from_str("www.example.").unwrap(),
+            RecordType::A,
+        ));
+        let r = response("www.example.", example.sign(vec![a("www.example.", 1)]));
+        let stripped = restore_response(&q, r.to_vec().unwrap()).unwrap();
+        assert_eq!(Message::from_vec(&stripped).unwrap().answers().len(), 1);
+        let mut e = Edns::new();
+        e.set_dnssec_ok(true);
+        q.set_edns(e);
+        let kept = restore_response(&q, r.to_vec().unwrap()).unwrap();
+        assert_eq!(Message::from_vec(&kept).unwrap().answers().len(), 2);
+    }
+
+    #[test]
+    fn denials() {
+        let (root, example, group) = zones();
+        let soa = SOA::new(
+            Name::from_str("ns.example.").unwrap(),
+            Name::from_str("admin.example.").unwrap(),
+            1,
+            3600,
+            600,
+            86400,
+            300,
+        );
+        let soa = example.sign(vec![record("example.", RData::SOA(soa))]);
+        let nsec = |owner: &str, next: &str, types: Vec<RecordType>| {
+            let n = NsecData::new(Name::from_str(next).unwrap(), types);
+            example.sign(vec![record(owner, RData::DNSSEC(DNSSECRData::NSEC(n)))])
+        };
+        let apex = nsec(
+            "example.",
+            "www.example.",
+            vec![RecordType::SOA, RecordType::NS, NSEC, RRSIG],
+        );
+        let www = nsec("www.example.", "example.", vec![RecordType::A, NSEC, RRSIG]);
+        // a chain of one, with only the apex
+        let apex_hash = Nsec3HashAlgorithm::SHA1
+            .hash(&[], &Name::from_str("example.").unwrap(), 0)
+            .unwrap();
+        let n = Nsec3Data::new(
+            Nsec3HashAlgorithm::SHA1,
+            false,
+            0,
+            vec![],
+            apex_hash.as_ref().to_vec(),
+            vec![RecordType::SOA, RecordType::NS, RRSIG],
+        );
+        let owner = format!("{}.example.", base32hex(apex_hash.as_ref()));
+        let nsec3 = example.sign(vec![record(&owner, RData::DNSSEC(DNSSECRData::NSEC3(n)))]);
+
+        let validator = Validator::with_anchors(vec!["else".into()], vec![root.ds()]);
+        let validate = |name: &str, t, code, proof: Vec<&Vec<Record>>| {
+            let mut m = Message::new();
+            m.set_message_type(MessageType::Response)
+                .set_response_code(code)
+                .add_query(Query::query(Name::from_str(name).unwrap(), t));
+            m.insert_name_servers(proof.into_iter().flatten().cloned().collect());
+            let validator = &validator;
+            let group = &group;
+            async move {
+                match validator.validate(&m, group).await {
+                    Ok(secure) => Some(secure),
+                    Err(e) => {
+                        assert!(e.downcast::<Bogus>().is_ok());
+                        None
+                    }
+                }
+            }
+        };
+        let (a, txt, nx, no) = (
+            RecordType::A,
+            RecordType::TXT,
+            ResponseCode::NXDomain,
+            ResponseCode::NoError,
+        );
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let v = validate("nope.example.", a, nx, vec![&soa, &apex]).await;
+            assert_eq!(v, Some(true));
+            // a signed SOA replayed, or records not covering the name
+            assert_eq!(validate("nope.example.", a, nx, vec![&soa]).await, None);
+            assert_eq!(
+                validate("nope.example.", a, nx, vec![&soa, &www]).await,
+                None
+            );
+            let v = validate("www.example.", a, nx, vec![&soa, &apex, &www]).await;
+            assert_eq!(v, None);
+
+            assert_eq!(
+                validate("www.example.", txt, no, vec![&soa, &www]).await,
+                Some(true)
+            );
+            // the type is in the bitmap
+            assert_eq!(
+                validate("www.example.", a, no, vec![&soa, &www]).await,
+                None
+            );
+            assert_eq!(validate("www.example.", txt, no, vec![&soa]).await, None);
+
+            let v = validate("nope.example.", a, nx, vec![&soa, &nsec3]).await;
+            assert_eq!(v, Some(true));
+            assert_eq!(
+                validate("example.", a, no, vec![&soa, &nsec3]).await,
+                Some(true)
+            );
+            let v = validate("example.", RecordType::SOA, no, vec![&soa, &nsec3]).await;
+            assert_eq!(v, None);
+
+            let mut m = Message::new();
+            m.set_message_type(MessageType::Response);
+            m.insert_name_servers(soa.clone());
+            let e = validator.validate(&m, &group).await.unwrap_err();
+            assert!(e.downcast::<Bogus>().is_ok());
+        });
+    }
+
+    #[test]
+    fn wildcards() {
+        let (root, example, group) = zones();
+        let nsec = |owner: &str, next: &str, types: Vec<RecordType>| {
+            let n = NsecData::new(Name::from_str(next).unwrap(), types);
+            example.sign(vec![record(owner, RData::DNSSEC(DNSSECRData::NSEC(n)))])
+        };
+        let apex = nsec(
+            "example.",
+            "www.example.",
+            vec![RecordType::SOA, RecordType::NS, NSEC, RRSIG],
+        );
+        let www = nsec("www.example.", "example.", vec![RecordType::A, NSEC, RRSIG]);
+        let apex_hash = Nsec3HashAlgorithm::SHA1
+            .hash(&[], &Name::from_str("example.").unwrap(), 0)
+            .unwrap();
+        let n = Nsec3Data::new(
+            Nsec3HashAlgorithm::SHA1,
+            false,
+            0,
+            vec![],
+            apex_hash.as_ref().to_vec(),
+            vec![RecordType::SOA, RecordType::NS, RRSIG],
+        );
+        let owner = format!("{}.example.", base32hex(apex_hash.as_ref()));
+        let nsec3 = example.sign(vec![record(&owner, RData::DNSSEC(DNSSECRData::NSEC3(n)))]);
+        // signed once, with the labels of the wildcard
+        let wildcard = example.sign(vec![a("*.example.", 9)]);
+
+        let validator = Validator::with_anchors(vec!["else".into()], vec![root.ds()]);
+        let validate = |name: &str, proof: Vec<&Vec<Record>>| {
+            let mut answers = wildcard.clone();
+            for r in &mut answers {
+                r.set_name(Name::from_str(name).unwrap());
+            }
+            let mut m = response(name, answers);
+            m.insert_name_servers(proof.into_iter().flatten().cloned().collect());
+            let validator = &validator;
+            let group = &group;
+            async move {
+                match validator.validate(&m, group).await {
+                    Ok(secure) => Some(secure),
+                    Err(e) => {
+                        assert!(e.downcast::<Bogus>().is_ok());
+                        None
+                    }
+                }
+            }
+        };
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            assert_eq!(validate("nope.example.", vec![&apex]).await, Some(true));
+            assert_eq!(validate("a.b.example.", vec![&apex]).await, Some(true));
+            assert_eq!(validate("nope.example.", vec![&nsec3]).await, Some(true));
+            // forged for a name that exists, or without a proof
+            assert_eq!(validate("www.example.", vec![&www]).await, None);
+            assert_eq!(validate("nope.example.", vec![]).await, None);
+            // not the closest match, www.example. is closer
+            assert_eq!(validate("a.www.example.", vec![&www]).await, None);
+        });
+    }
+
+    #[test]
+    fn root_trust_anchor() {
+        let ds = parse_ds(
+            ". 172800 IN DS 20326 8 2 \
+             E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D",
+        )
+        .unwrap();
+        assert_eq!(ds.key_tag(), 20326);
+        let key = DNSKEY::new(
+            true,
+            true,
+            false,
+            Algorithm::RSASHA256,
+            TrustAnchor::default().get(1).to_vec(),
+        );
+        assert!(ds.covers(&Name::root(), &key).unwrap());
+        assert_eq!(key.algorithm(), Algorithm::RSASHA256);
+        assert!(parse_ds("example. IN DS 1 8 2 00").is_none());
+        // RFC 4648 test vector, lowercase and unpadded
+        assert_eq!(base32hex(b"foobar"), "cpnmuoj1e8");
+    }
+}
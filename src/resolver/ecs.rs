@@ -0,0 +1,161 @@
+//! Rewrite the EDNS client subnet option, RFC 7871
+use std::net::IpAddr;
+
+use failure::Error;
+use trust_dns::op::{Edns, Message};
+use trust_dns::rr::rdata::opt::{EdnsCode, EdnsOption};
+
+use crate::conf::ClientSubnet;
+
+const FAMILY_IPV4: u8 = 1;
+const FAMILY_IPV6: u8 = 2;
+
+/// Whether the query is changed
+pub fn rewrite_query(m: &mut Message, mode: ClientSubnet) -> bool {
+    match mode {
+        ClientSubnet::Pass => false,
+        ClientSubnet::Strip => match m.edns() {
+            Some(e) if e.option(EdnsCode::Subnet).is_some() => {
+                let e = without_subnet(e);
+                m.set_edns(e);
+                true
+            }
+            _ => false,
+        },
+        ClientSubnet::Inject(addr, prefix) => {
+            // the default max payload of 512 is right for clients without EDNS
+            let e = m.edns_mut();
+            e.set_option(EdnsOption::Unknown(
+                EdnsCode::Subnet.into(),
+                option_data(addr, prefix),
+            ));
+            true
+        }
+    }
+}
+
+/// The subnet sent upstream, which answers may depend on
+pub fn subnet(m: &Message) -> Option<Vec<u8>> {
+    match m.edns()?.option(EdnsCode::Subnet)? {
+        EdnsOption::Unknown(_, d) => Some(d.clone()),
+        #[allow(unreachable_patterns)]
+        _ => None,
+    }
+}
+
+/// Remove what the client didn't ask for from the response
+/// to a rewritten query
+pub fn restore_response(query: &Message, response: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if subnet(query).is_some() {
+        return Ok(response);
+    }
+    let r = Message::from_vec(&response)?;
+    let r = match (query.edns(), r.edns()) {
+        (Some(_), Some(e)) => {
+            let mut r = r.clone();
+            r.set_edns(without_subnet(e));
+            r
+        }
+        (None, Some(_)) => without_edns(&r),
+        (_, None) => return Ok(response),
+    };
+    Ok(r.to_vec()?)
+}
+
+fn without_subnet(e: &Edns) -> Edns {
+    let mut n = Edns::new();
+    n.set_rcode_high(e.rcode_high());
+    n.set_version(e.version());
+    n.set_dnssec_ok(e.dnssec_ok());
+    n.set_max_payload(e.max_payload());
+    for (c, o) in e.options().options() {
+        if *c != EdnsCode::Subnet {
+            n.set_option(o.clone());
+        }
+    }
+    n
+}
+
+pub fn without_edns(m: &Message) -> Message {
+    let mut n = Message::new();
+    n.set_id(m.id())
+        .set_message_type(m.message_type())
+        .set_op_code(m.op_code())
+        .set_authoritative(m.authoritative())
+        .set_truncated(m.truncated())
+        .set_recursion_desired(m.recursion_desired())
+        .set_recursion_available(m.recursion_available())
+        .set_authentic_data(m.authentic_data())
+        .set_checking_disabled(m.checking_disabled())
+        .set_response_code(m.response_code());
+    n.add_queries(m.queries().to_vec());
+    n.insert_answers(m.answers().to_vec());
+    n.insert_name_servers(m.name_servers().to_vec());
+    n.insert_additionals(m.additionals().to_vec());
+    n
+}
+
+/// Only the prefix of the address is sent, with the rest of the bits cleared
+fn option_data(addr: IpAddr, prefix: u8) -> Vec<u8> {
+    let (family, octets) = match addr {
+        IpAddr::V4(a) => (FAMILY_IPV4, a.octets().to_vec()),
+        IpAddr::V6(a) => (FAMILY_IPV6, a.octets().to_vec()),
+    };
+    let n = usize::from(prefix).div_ceil(8);
+    let mut d = vec![0, family, prefix, 0];
+    d.extend_from_slice(&octets[..n]);
+    let unused = (8 - prefix % 8) % 8;
+    if let Some(last) = d[4..].last_mut() {
+        *last &= 0xff << unused;
+    }
+    d
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{restore_response, rewrite_query, subnet};
+    use crate::conf::ClientSubnet;
+    use std::str::FromStr;
+    use trust_dns::op::{Edns, Message, MessageType, Query};
+    use trust_dns::rr::{Name, RecordType};
+
+    fn query(edns: bool) -> Message {
+        let mut m = Message::new();
+        m.add_query(Query::query(
+            Name::from_str("example.com.").unwrap(),
+            RecordType::A,
+        ));
+        if edns {
+            m.set_edns(Edns::new());
+        }
+        m
+    }
+
+    #[test]
+    fn inject_and_strip() {
+        let inject = ClientSubnet::Inject("203.0.113.77".parse().unwrap(), 20);
+        let mut q = query(false);
+        assert!(rewrite_query(&mut q, inject));
+        let s = subnet(&q).unwrap();
+        assert_eq!(s, vec![0, 1, 20, 0, 203, 0, 112]);
+        // the upstream answers with the option, which is removed with EDNS
+        let mut r = q.clone();
+        r.set_message_type(MessageType::Response);
+        let r = restore_response(&query(false), r.to_vec().unwrap()).unwrap();
+        assert!(Message::from_vec(&r).unwrap().edns().is_none());
+        let mut r = q.clone();
+        r.set_message_type(MessageType::Response);
+        let r = restore_response(&query(true), r.to_vec().unwrap()).unwrap();
+        let r = Message::from_vec(&r).unwrap();
+        assert!(r.edns().is_some() && subnet(&r).is_none());
+
+        assert!(rewrite_query(&mut q, ClientSubnet::Strip));
+        assert!(subnet(&q).is_none());
+        assert!(!rewrite_query(&mut q, ClientSubnet::Strip));
+
+        let inject = ClientSubnet::Inject("2001:db8::1".parse().unwrap(), 56);
+        assert!(rewrite_query(&mut q, inject));
+        let s = subnet(&q).unwrap();
+        assert_eq!(s, vec![0, 2, 56, 0, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,111 @@
+//! Fake addresses given to domain names, so that connections to them
+//! can be routed by the domain names
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Mutex;
+
+use lru::LruCache;
+
+use crate::conf::DnsProxy;
+
+pub struct FakeIpPool {
+    network: u32,
+    prefix: u8,
+    inner: Mutex<Mappings>,
+}
+
+/// Least recently used names give their addresses to new ones
+/// when the pool runs out
+struct Mappings {
+    by_name: LruCache<String, Ipv4Addr>,
+    by_addr: HashMap<Ipv4Addr, String>,
+    /// addresses after this are never used yet
+    next: u32,
+}
+
+impl FakeIpPool {
+    /// None if no zone uses fake addresses
+    pub fn from_conf(conf: &DnsProxy) -> Option<FakeIpPool> {
+        if !conf.uses_fake_ip() {
+            return None;
+        }
+        let (a, p) = conf.fake_ip_pool;
+        Some(FakeIpPool::new(a, p))
+    }
+
+    pub fn new(network: Ipv4Addr, prefix: u8) -> FakeIpPool {
+        let mask = u32::MAX << (32 - prefix);
+        let size = 1usize << (32 - prefix);
+        FakeIpPool {
+            network: u32::from(network) & mask,
+            prefix,
+            inner: Mutex::new(Mappings {
+                // without the network and broadcast addresses
+                by_name: LruCache::new(size - 2),
+                by_addr: HashMap::new(),
+                next: 1,
+            }),
+        }
+    }
+
+    /// The fake address of a domain name, which is given one if it doesn't have
+    pub fn addr_of(&self, name: &str) -> Ipv4Addr {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let mut m = self.inner.lock().unwrap();
+        if let Some(a) = m.by_name.get(&name) {
+            return *a;
+        }
+        let a = if m.by_name.len() < m.by_name.cap() {
+            m.next += 1;
+            Ipv4Addr::from(self.network + m.next - 1)
+        } else {
+            let (old, a) = m.by_name.pop_lru().expect("the pool isn't empty");
+            debug!("Fake address {} is moved from {} to {}", a, old, name);
+            a
+        };
+        m.by_name.put(name.clone(), a);
+        m.by_addr.insert(a, name);
+        a
+    }
+
+    /// The domain name of a fake address
+    pub fn domain_of(&self, addr: IpAddr) -> Option<String> {
+        let a = match addr {
+            IpAddr::V4(a) if self.contains(a) => a,
+            _ => return None,
+        };
+        let mut m = self.inner.lock().unwrap();
+        let n = m.by_addr.get(&a)?.clone();
+        // it's still in use
+        m.by_name.get(&n);
+        Some(n)
+    }
+
+    fn contains(&self, a: Ipv4Addr) -> bool {
+        let mask = u32::MAX << (32 - self.prefix);
+        u32::from(a) & mask == self.network
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FakeIpPool;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn addresses_are_reused() {
+        let p = FakeIpPool::new(Ipv4Addr::new(198, 18, 0, 10), 30);
+        let a = p.addr_of("a.example.");
+        assert_eq!(a, Ipv4Addr::new(198, 18, 0, 9));
+        assert_eq!(p.addr_of("A.example"), a);
+        let b = p.addr_of("b.example");
+        assert_eq!(b, Ipv4Addr::new(198, 18, 0, 10));
+        assert_eq!(p.domain_of(a.into()), Some("a.example".into()));
+        // b is the least recently used one now
+        let c = p.addr_of("c.example");
+        assert_eq!(c, b);
+        assert_eq!(p.domain_of(b.into()), Some("c.example".into()));
+        assert_eq!(p.domain_of(Ipv4Addr::new(198, 18, 0, 11).into()), None);
+        assert_eq!(p.domain_of(Ipv4Addr::new(10, 0, 0, 1).into()), None);
+    }
+}
@@ -0,0 +1,230 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use failure::Error;
+use futures::future::select_ok;
+use rand::Rng;
+use tokio::time::timeout;
+use trust_dns::op::{Message, ResponseCode};
+
+use super::dnsclient::DnsClient;
+use super::stats::{Counters, Counts, TimedOut};
+use crate::conf::{NameServerGroup, Strategy};
+
+/// Clients of nameservers in the same zone
+#[derive(Debug)]
+pub struct ClientGroup {
+    strategy: Strategy,
+    clients: Vec<Upstream>,
+}
+
+#[derive(Debug)]
+struct Upstream {
+    client: DnsClient,
+    timeout: Duration,
+    /// As in the config
+    name: String,
+    counters: Counters,
+}
+
+/// The response, and the name of the nameserver which gave it
+type Answer<'s, 'a> = Pin<Box<dyn Future<Output = Result<(Vec<u8>, &'s str), Error>> + Send + 'a>>;
+
+impl ClientGroup {
+    pub fn new(g: &NameServerGroup) -> ClientGroup {
+        let clients = g
+            .servers
+            .iter()
+            .map(|ns| Upstream {
+                client: DnsClient::new(ns),
+                timeout: ns.timeout,
+                name: ns.to_string(),
+                counters: Counters::default(),
+            })
+            .collect();
+        ClientGroup {
+            strategy: g.strategy,
+            clients,
+        }
+    }
+
+    /// The counters of each nameserver
+    pub fn counts(&self) -> impl Iterator<Item = (&str, Counts)> {
+        self.clients
+            .iter()
+            .map(|u| (u.name.as_str(), u.counters.counts()))
+    }
+
+    pub async fn resolve<'s>(&'s self, data: &[u8]) -> Result<(Vec<u8>, &'s str), Error> {
+        match self.strategy {
+            Strategy::Race => {
+                let all = (0..self.clients.len()).map(|i| self.resolve_one(i, data));
+                let (r, _rest) = select_ok(all).await?;
+                Ok(r)
+            }
+            Strategy::Sequential => self.resolve_from(0, data).await,
+            Strategy::Random => {
+                let i = rand::thread_rng().gen_range(0, self.clients.len());
+                self.resolve_from(i, data).await
+            }
+        }
+    }
+
+    /// try every nameserver in turn, starting from the ith
+    async fn resolve_from<'s>(
+        &'s self,
+        i: usize,
+        data: &[u8],
+    ) -> Result<(Vec<u8>, &'s str), Error> {
+        let n = self.clients.len();
+        let mut err = format_err!("No nameserver");
+        for j in 0..n {
+            match self.resolve_one((i + j) % n, data).await {
+                Ok(r) => return Ok(r),
+                Err(e) => err = e,
+            }
+        }
+        Err(err)
+    }
+
+    /// counted in the counters of the nameserver
+    fn resolve_one<'s: 'a, 'a>(&'s self, i: usize, data: &'a [u8]) -> Answer<'s, 'a> {
+        let u = &self.clients[i];
+        Box::pin(async move {
+            u.counters.query();
+            let r = u.resolve(data).await;
+            if let Err(ref e) = r {
+                u.counters.error(e);
+            }
+            r.map(|r| (r, u.name.as_str()))
+        })
+    }
+}
+
+impl Upstream {
+    /// errors unless a valid answer comes in time
+    async fn resolve(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let r = timeout(self.timeout, self.client.resolve(data.to_vec()))
+            .await
+            .map_err(|_e| TimedOut(self.name.clone()))??;
+        let m = Message::from_vec(&r)?;
+        match m.response_code() {
+            ResponseCode::ServFail | ResponseCode::Refused => {
+                Err(format_err!("{} answered {}", self.name, m.response_code()))
+            }
+            _ => Ok(r),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::ClientGroup;
+    use crate::conf::{NameServer, NameServerGroup, NameServerRemote, Strategy};
+    use crate::resolver::stats::{is_timeout, Counts};
+    use std::net::{Ipv4Addr, UdpSocket};
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::Duration;
+    use trust_dns::op::{Message, MessageType, Query};
+    use trust_dns::rr::{Name, RData, Record, RecordType};
+
+    /// answers every query with the same address
+    pub fn fixed_nameserver(ip: Ipv4Addr) -> NameServer {
+        let s = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let a = s.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((n, p)) = s.recv_from(&mut buf) {
+                let mut r = Message::from_vec(&buf[..n]).unwrap();
+                let name = r.queries()[0].name().clone();
+                r.set_message_type(MessageType::Response);
+                r.add_answer(Record::from_rdata(name, 60, RData::A(ip)));
+                s.send_to(&r.to_vec().unwrap(), p).unwrap();
+            }
+        });
+        NameServer {
+            egress: None,
+            remote: NameServerRemote::Udp(a),
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// never answers
+    fn silent_nameserver() -> NameServer {
+        let s = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let a = s.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while s.recv_from(&mut buf).is_ok() {}
+        });
+        NameServer {
+            egress: None,
+            remote: NameServerRemote::Udp(a),
+            timeout: Duration::from_millis(200),
+        }
+    }
+
+    fn group(strategy: Strategy, servers: Vec<NameServer>) -> ClientGroup {
+        ClientGroup::new(&NameServerGroup { strategy, servers })
+    }
+
+    /// the address answered, and the name of the nameserver answering it
+    fn resolve(g: &ClientGroup) -> Result<(RData, String), failure::Error> {
+        let mut q = Message::new();
+        let name = Name::from_str("example.com.").unwrap();
+        q.add_query(Query::query(name, RecordType::A));
+        let q = q.to_vec().unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let (r, n) = rt.block_on(g.resolve(&q))?;
+        let a = Message::from_vec(&r)?.answers()[0].rdata().clone();
+        Ok((a, n.to_string()))
+    }
+
+    #[test]
+    fn strategies() {
+        let a = RData::A([1, 2, 3, 4].into());
+        let servers = vec![silent_nameserver(), fixed_nameserver([1, 2, 3, 4].into())];
+        let answering = servers[1].to_string();
+        let r = resolve(&group(Strategy::Race, servers)).unwrap();
+        assert_eq!(r, (a.clone(), answering));
+
+        let servers = vec![silent_nameserver(), fixed_nameserver([1, 2, 3, 4].into())];
+        let answering = servers[1].to_string();
+        let g = group(Strategy::Sequential, servers);
+        assert_eq!(resolve(&g).unwrap(), (a.clone(), answering));
+        let counts: Vec<Counts> = g.counts().map(|(_, c)| c).collect();
+        let timed_out = Counts {
+            queries: 1,
+            errors: 1,
+            timeouts: 1,
+            cache_hits: 0,
+        };
+        let answered = Counts {
+            queries: 1,
+            ..Counts::default()
+        };
+        assert_eq!(counts, vec![timed_out, answered]);
+
+        // the later ones aren't asked when the first answers
+        let servers = vec![
+            fixed_nameserver([1, 2, 3, 4].into()),
+            fixed_nameserver([5, 6, 7, 8].into()),
+        ];
+        let answering = servers[0].to_string();
+        let g = group(Strategy::Sequential, servers);
+        assert_eq!(resolve(&g).unwrap(), (a.clone(), answering));
+        let counts: Vec<u64> = g.counts().map(|(_, c)| c.queries).collect();
+        assert_eq!(counts, vec![1, 0]);
+
+        let servers = vec![silent_nameserver(), fixed_nameserver([1, 2, 3, 4].into())];
+        let answering = servers[1].to_string();
+        let r = resolve(&group(Strategy::Random, servers)).unwrap();
+        assert_eq!(r, (a, answering));
+
+        let servers = vec![silent_nameserver(), silent_nameserver()];
+        let e = resolve(&group(Strategy::Race, servers)).unwrap_err();
+        assert!(is_timeout(&e));
+    }
+}
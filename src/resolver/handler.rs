@@ -1,78 +1,788 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::Instant;
 
 use bytes::Bytes;
 use failure::Error;
+use futures::future::join_all;
 
-use trust_dns::op::Message;
-use trust_dns::rr::LowerName;
+use trust_dns::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns::rr::{LowerName, RData, RecordType};
 
-use super::dnsclient::DnsClient;
-use crate::conf::DnsProxy;
-use crate::conf::DomainMatcher;
+use super::cache::{CacheKey, DnsCache, Origin};
+use super::dnssec::{self, Validator};
+use super::ecs;
+use super::group::ClientGroup;
+use super::hints::DomainHints;
+use super::local::{self, LocalRecords};
+use super::message::{self, error_response, format_error};
+use super::querylog::{LogEntry, QueryLogger};
+use super::stats::{Counters, Counts};
+use crate::conf::{AnswerCheck, BlockMode, ClientSubnet, DnsProxy, DnsTarget};
+use crate::conf::{DomainMatcher, IpMatcher};
+use crate::util::BsDisp;
 use trust_dns::serialize::binary::BinDecodable;
 use trust_dns::serialize::binary::BinDecoder;
 
 pub struct SmartResolver {
-    region_resolver: Vec<(Bytes, DnsClient)>,
-    default_resolver: DnsClient,
+    region_resolver: Vec<(Bytes, Target)>,
+    default_resolver: Target,
+    /// By address zone of clients
+    client_resolvers: Vec<(Bytes, Vec<(Bytes, Target)>)>,
+    type_rules: Vec<TypeRule>,
     router: Arc<DomainMatcher>,
+    ip_matcher: Arc<IpMatcher>,
+    verifiers: Vec<(Bytes, Verifier)>,
+    client_subnet: Vec<(Bytes, ClientSubnet)>,
+    cache: Option<DnsCache>,
+    local: LocalRecords,
+    local_ttl: u32,
+    hints: DomainHints,
+    /// By zone, `local` for local records
+    zone_counters: BTreeMap<Bytes, Counters>,
+    query_log: Option<QueryLogger>,
+    validator: Option<Validator>,
+}
+
+/// Queries of the type, in the zone if given, are sent to the target
+struct TypeRule {
+    query_type: RecordType,
+    zone: Option<Bytes>,
+    target: Target,
+}
+
+/// Where a query is sent
+struct Choice<'a> {
+    /// None if the name is in no zone with a target
+    zone: Option<Bytes>,
+    target: &'a Target,
+    /// The address zone of the client, if the target is from its forwarding table
+    clients: Option<Bytes>,
+}
+
+/// How a query was answered, for logs and counters
+struct Trace<'a> {
+    zone: Bytes,
+    upstream: Option<&'a str>,
+    cached: bool,
+}
+
+/// Detects poisoned answers and asks again
+struct Verifier {
+    check: AnswerCheck,
+    retry: ClientGroup,
+}
+
+#[derive(Debug)]
+enum Target {
+    Forward(ClientGroup),
+    Block(BlockMode),
+    FakeIp,
+}
+
+impl Target {
+    fn new(t: &DnsTarget) -> Target {
+        match t {
+            DnsTarget::Forward(g) => Target::Forward(ClientGroup::new(g)),
+            DnsTarget::Block(m) => Target::Block(*m),
+            DnsTarget::FakeIp => Target::FakeIp,
+        }
+    }
 }
 
 impl SmartResolver {
-    pub fn new(router: Arc<DomainMatcher>, regionconf: &DnsProxy) -> Result<SmartResolver, Error> {
-        let rresolvers: Vec<(Bytes, DnsClient)> = regionconf
+    pub fn new(
+        router: Arc<DomainMatcher>,
+        ip_matcher: Arc<IpMatcher>,
+        hints: DomainHints,
+        regionconf: &DnsProxy,
+    ) -> Result<SmartResolver, Error> {
+        if regionconf.uses_fake_ip() && hints.fake_ip.is_none() {
+            return Err(format_err!("No pool for fake-ip zones"));
+        }
+        let rresolvers: Vec<(Bytes, Target)> = regionconf
             .forward
             .iter()
-            .map(|(r, s)| {
-                let dc = DnsClient::new(s);
-                (r.clone(), dc)
+            .map(|(r, t)| (r.clone(), Target::new(t)))
+            .collect();
+        let dresolver = Target::new(&regionconf.default);
+        let client_resolvers = regionconf
+            .client_forward
+            .iter()
+            .map(|(c, t)| {
+                let t = t.iter().map(|(r, t)| (r.clone(), Target::new(t))).collect();
+                (c.clone(), t)
             })
             .collect();
-        let dresolver = DnsClient::new(&regionconf.default);
+        let verifiers = regionconf
+            .verify
+            .iter()
+            .map(|(z, v)| {
+                let vf = Verifier {
+                    check: v.check.clone(),
+                    retry: ClientGroup::new(&v.retry),
+                };
+                (z.clone(), vf)
+            })
+            .collect();
+        let type_rules = regionconf
+            .query_types
+            .iter()
+            .map(|r| TypeRule {
+                query_type: r.query_type,
+                zone: r.zone.clone(),
+                target: Target::new(&r.target),
+            })
+            .collect();
+        let zone_counters = regionconf
+            .forward
+            .keys()
+            .chain(regionconf.client_forward.values().flat_map(|t| t.keys()))
+            .cloned()
+            .chain(vec!["else".into(), "local".into()])
+            .map(|z| (z, Counters::default()))
+            .collect();
+        let query_log = match regionconf.query_log {
+            Some(ref l) => Some(QueryLogger::new(l)?),
+            None => None,
+        };
+        let validator = match regionconf.dnssec {
+            Some(ref d) => Some(Validator::new(d)?),
+            None => None,
+        };
         Ok(SmartResolver {
             region_resolver: rresolvers,
             default_resolver: dresolver,
+            client_resolvers,
+            type_rules,
             router,
+            ip_matcher,
+            verifiers,
+            client_subnet: regionconf
+                .client_subnet
+                .iter()
+                .map(|(z, c)| (z.clone(), *c))
+                .collect(),
+            cache: regionconf.cache.as_ref().map(DnsCache::new),
+            local: LocalRecords::new(regionconf)?,
+            local_ttl: regionconf.local_ttl,
+            hints,
+            zone_counters,
+            query_log,
+            validator,
         })
     }
 
-    pub async fn handle_future<'a>(&'a self, buffer: &'a [u8]) -> Result<Vec<u8>, Error> {
+    /// The response to a query, or an error if there should be none
+    pub async fn handle_future<'a>(
+        &'a self,
+        buffer: &'a [u8],
+        client: IpAddr,
+    ) -> Result<Vec<u8>, Error> {
         let mut decoder = BinDecoder::new(&buffer);
-        let message = Message::read(&mut decoder).expect("msg deco err");
+        let message = match Message::read(&mut decoder) {
+            Ok(m) => m,
+            Err(e) => {
+                debug!("Malformed dns message: {}", e);
+                let r = format_error(buffer)
+                    .ok_or_else(|| format_err!("Malformed dns message: {}", e))?;
+                return Ok(r.to_vec()?);
+            }
+        };
+        if message.message_type() != MessageType::Query {
+            return Err(format_err!("Not a dns query: {:?}", message));
+        }
+        let code = if message.op_code() != OpCode::Query {
+            Some(ResponseCode::NotImp)
+        } else if message.queries().len() != 1 {
+            Some(ResponseCode::FormErr)
+        } else {
+            match message.queries()[0].query_type() {
+                RecordType::AXFR | RecordType::IXFR => Some(ResponseCode::Refused),
+                _ => None,
+            }
+        };
+        if let Some(c) = code {
+            debug!("Dns query {:?} answered with {}", message.queries(), c);
+            return Ok(error_response(&message, c).to_vec()?);
+        }
+        let start = Instant::now();
+        let mut trace = Trace {
+            zone: "local".into(),
+            upstream: None,
+            cached: false,
+        };
+        let r = self
+            .answer(&message, buffer, client, &mut trace, false)
+            .await;
+        let counters = &self.zone_counters[&trace.zone];
+        counters.query();
+        if trace.cached {
+            counters.cache_hit();
+        }
+        let r = match r {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("Error answering {:?}: {}", message.queries()[0], e);
+                counters.error(&e);
+                error_response(&message, ResponseCode::ServFail).to_vec()?
+            }
+        };
+        if let Some(ref l) = self.query_log {
+            let query = &message.queries()[0];
+            let code = Message::from_vec(&r).map_or(ResponseCode::ServFail, |m| m.response_code());
+            l.log(&LogEntry {
+                client,
+                name: &query.name().to_string(),
+                query_type: query.query_type(),
+                zone: &String::from_utf8_lossy(&trace.zone),
+                upstream: trace.upstream,
+                cached: trace.cached,
+                code,
+                latency: start.elapsed(),
+            });
+        }
+        Ok(r)
+    }
 
-        let name = {
-            let queries = message.queries();
-            if queries.len() != 1 {
-                return Err(format_err!(
-                    "more than 1 queries in a message: {:?}",
-                    message
-                ));
+    /// the message has exactly one query,
+    /// and the cache is skipped when refreshing it
+    async fn answer<'a>(
+        &'a self,
+        message: &Message,
+        buffer: &[u8],
+        client: IpAddr,
+        trace: &mut Trace<'a>,
+        refresh: bool,
+    ) -> Result<Vec<u8>, Error> {
+        let query = &message.queries()[0];
+        let name = LowerName::new(query.name());
+        if let Some(r) = self.local.answer(message) {
+            debug!("Dns query {:?} answered locally", name);
+            return Ok(r.to_vec()?);
+        }
+
+        let Choice {
+            zone,
+            target,
+            clients,
+        } = self.choose_resolver(query, client);
+        trace.zone = zone.clone().unwrap_or_else(|| "else".into());
+        let group = match target {
+            Target::Forward(g) => g,
+            Target::Block(m) => {
+                debug!("Dns query {:?} blocked", name);
+                return Ok(local::block(message, *m, self.local_ttl).to_vec()?);
+            }
+            Target::FakeIp => {
+                let pool = self.hints.fake_ip.as_ref().expect("checked when created");
+                let r = local::fake_ip(message, pool, self.local_ttl);
+                debug!("Dns query {:?} answered with {:?}", name, r.answers());
+                return Ok(r.to_vec()?);
+            }
+        };
+        let verifier = by_zone(&self.verifiers, zone.as_ref());
+        let validator = self.validator.as_ref().filter(|v| v.covers(&trace.zone));
+        let mut upstream = message.clone();
+        let rewritten = match by_zone(&self.client_subnet, zone.as_ref()) {
+            Some(c) => ecs::rewrite_query(&mut upstream, *c),
+            None => false,
+        };
+        if validator.is_some() {
+            dnssec::request_signatures(&mut upstream);
+        }
+        let data = if rewritten || validator.is_some() {
+            upstream.to_vec()?
+        } else {
+            buffer.to_vec()
+        };
+        let restore = |r: Vec<u8>| {
+            self.learn(&r);
+            let r = if rewritten {
+                ecs::restore_response(message, r)?
+            } else {
+                r
+            };
+            match validator {
+                Some(_) => dnssec::restore_response(message, r),
+                None => Ok(r),
+            }
+        };
+        let key = CacheKey::new(query, zone, clients, ecs::subnet(&upstream));
+        if let (Some(c), false) = (&self.cache, refresh) {
+            if let Some(r) = c.get(&key, message.id()) {
+                debug!("Dns query {:?} answered from cache", name);
+                trace.cached = true;
+                return restore(r);
+            }
+        }
+        debug!("Dns query {:?} using {:?}", name, group);
+        let (r, u) = group.resolve(&data).await?;
+        trace.upstream = Some(u);
+        let (r, group) = match verifier {
+            Some(v) if self.is_poisoned(&r, &v.check) => {
+                warn!("Dns answer of {} looks poisoned, using {:?}", name, v.retry);
+                let (r, u) = v.retry.resolve(&data).await?;
+                trace.upstream = Some(u);
+                (r, &v.retry)
+            }
+            _ => (r, group),
+        };
+        let r = match validator {
+            Some(v) => {
+                let mut m = Message::from_vec(&r)?;
+                let secure = v.validate(&m, group).await?;
+                debug!("Dns answer of {} validated, secure: {}", name, secure);
+                m.set_authentic_data(secure);
+                m.to_vec()?
             }
-            let q = &queries[0];
-            LowerName::new(q.name())
+            None => r,
         };
+        if let Some(ref c) = self.cache {
+            match Message::from_vec(&r) {
+                Ok(m) => {
+                    let origin = Origin {
+                        request: buffer.to_vec(),
+                        client,
+                    };
+                    c.insert(key, m, Some(origin))
+                }
+                Err(e) => debug!("Not caching response to {:?}: {}", name, e),
+            }
+        }
+        restore(r)
+    }
 
-        let client = self.choose_resolver(&name);
-        debug!("Dns query {:?} using {:?}", name, client);
-        client
-            .resolve(buffer.to_vec())
-            .await
-            .map_err(|e| format_err!("resolve error: {:?}", e))
+    /// refresh popular cached responses about to expire
+    pub async fn prefetch(&self) {
+        let due = match self.cache {
+            Some(ref c) => c.prefetch_due(),
+            None => return,
+        };
+        let refresh = due.iter().map(|o| async move {
+            let m = Message::from_vec(&o.request)?;
+            let mut trace = Trace {
+                zone: "local".into(),
+                upstream: None,
+                cached: false,
+            };
+            debug!("Prefetching dns response to {:?}", m.queries());
+            self.answer(&m, &o.request, o.client, &mut trace, true)
+                .await
+        });
+        for r in join_all(refresh).await {
+            if let Err(e) = r {
+                debug!("Error prefetching dns response: {}", e);
+            }
+        }
     }
 
-    fn choose_resolver(&self, name: &LowerName) -> &DnsClient {
+    pub fn save_cache(&self) {
+        if let Some(ref c) = self.cache {
+            match c.save() {
+                Ok(n) => debug!("Saved {} dns responses", n),
+                Err(e) => warn!("Error saving dns cache: {}", e),
+            }
+        }
+    }
+
+    /// the counters of each zone
+    pub fn zone_counts(&self) -> BTreeMap<Bytes, Counts> {
+        self.zone_counters
+            .iter()
+            .map(|(z, c)| (z.clone(), c.counts()))
+            .collect()
+    }
+
+    /// the counters of each nameserver, added up if it's in several zones
+    pub fn upstream_counts(&self) -> BTreeMap<String, Counts> {
+        let groups = self
+            .region_resolver
+            .iter()
+            .chain(self.client_resolvers.iter().flat_map(|(_, t)| t))
+            .map(|(_, t)| t)
+            .chain(self.type_rules.iter().map(|r| &r.target))
+            .chain(Some(&self.default_resolver))
+            .filter_map(|t| match t {
+                Target::Forward(g) => Some(g),
+                _ => None,
+            })
+            .chain(self.verifiers.iter().map(|(_, v)| &v.retry));
+        let mut r: BTreeMap<String, Counts> = BTreeMap::new();
+        for g in groups {
+            for (n, c) in g.counts() {
+                *r.entry(n.to_string()).or_default() += c;
+            }
+        }
+        r
+    }
+
+    pub fn log_stats(&self) {
+        for (z, c) in self.zone_counts() {
+            info!("Dns zone {}: {}", BsDisp::new(&z), c);
+        }
+        for (n, c) in self.upstream_counts() {
+            info!("Dns nameserver {}: {}", n, c);
+        }
+    }
+
+    /// remember the domain name of addresses in the response
+    fn learn(&self, response: &[u8]) {
+        if let Some(ref l) = self.hints.learned {
+            if let Ok(m) = Message::from_vec(response) {
+                l.learn(&m);
+            }
+        }
+    }
+
+    /// the target of the first rule of the query type matching the query,
+    /// or that of the zone of the name
+    fn choose_resolver(&self, query: &Query, client: IpAddr) -> Choice<'_> {
+        let name = LowerName::new(query.name());
         let n = name.to_string();
         let n: Vec<&str> = n.trim_end_matches('.').split('.').rev().collect();
         let d = n.join(".");
         let r = self.router.rule_domain(d.as_bytes());
+        let rule = self.type_rules.iter().find(|t| {
+            if t.query_type != query.query_type() {
+                return false;
+            }
+            let z = match t.zone {
+                Some(ref z) => z,
+                None => return true,
+            };
+            if t.query_type == RecordType::PTR {
+                let a = message::arpa_addr(query.name());
+                a.and_then(|a| self.ip_matcher.match_ip(a)).as_ref() == Some(z)
+            } else {
+                r.as_ref() == Some(z)
+            }
+        });
+        let mut choice = self.choose_by_name(&name, r, client);
+        if let Some(t) = rule {
+            choice.target = &t.target;
+            choice.clients = None;
+        }
+        choice
+    }
+
+    /// the zone of the name, if there's a resolver for it,
+    /// from the forwarding table of the client or the common one
+    fn choose_by_name(&self, name: &LowerName, r: Option<Bytes>, client: IpAddr) -> Choice<'_> {
+        let table = self
+            .ip_matcher
+            .match_ip(client)
+            .and_then(|c| self.client_resolvers.iter().find(|(z, _)| *z == c));
+        let from_table = |zone: &[u8]| {
+            let (c, t) = table?;
+            let target = find(t, zone)?;
+            Some((target, Some(c.clone())))
+        };
         if let Some(region) = r {
-            for &(ref reg, ref res) in &self.region_resolver {
-                if region.as_ref() == reg {
-                    return &res;
-                }
+            let found = from_table(&region).or_else(|| {
+                let target = find(&self.region_resolver, &region)?;
+                Some((target, None))
+            });
+            if let Some((target, clients)) = found {
+                return Choice {
+                    zone: Some(region),
+                    target,
+                    clients,
+                };
             }
             warn!("no server found for {}", name);
         }
-        &self.default_resolver
+        let (target, clients) = from_table(b"else").unwrap_or((&self.default_resolver, None));
+        Choice {
+            zone: None,
+            target,
+            clients,
+        }
+    }
+
+    /// whether any address in the answer fails the check
+    fn is_poisoned(&self, response: &[u8], check: &AnswerCheck) -> bool {
+        let m = match Message::from_vec(response) {
+            Ok(m) => m,
+            Err(_) => return false,
+        };
+        m.answers()
+            .iter()
+            .filter_map(|r| match r.rdata() {
+                RData::A(a) => Some((*a).into()),
+                RData::AAAA(a) => Some((*a).into()),
+                _ => None,
+            })
+            .any(|a| {
+                let z = self.ip_matcher.match_ip(a);
+                match check {
+                    AnswerCheck::Expect(e) => z.as_ref() != Some(e),
+                    AnswerCheck::Reject(r) => z.as_ref() == Some(r),
+                }
+            })
+    }
+}
+
+/// the item of a zone, or of the default zone
+fn by_zone<'a, T>(items: &'a [(Bytes, T)], zone: Option<&Bytes>) -> Option<&'a T> {
+    find(items, zone.map(Bytes::as_ref).unwrap_or(b"else"))
+}
+
+fn find<'a, T>(items: &'a [(Bytes, T)], zone: &[u8]) -> Option<&'a T> {
+    items
+        .iter()
+        .find(|(n, _)| n.as_ref() == zone)
+        .map(|(_, v)| v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SmartResolver;
+    use crate::conf::main::{DnsVerify, QueryTypeRule};
+    use crate::conf::{load_conf, AnswerCheck, BlockMode, DnsProxy, DnsTarget};
+    use crate::conf::{DomainMatcher, IpMatcher};
+    use crate::resolver::group::tests::fixed_nameserver;
+    use crate::resolver::message::{arpa_addr, truncate};
+    use crate::resolver::DomainHints;
+    use bytes::Bytes;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::path::Path;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use trust_dns::op::{Message, MessageType, Query, ResponseCode};
+    use trust_dns::rr::{Name, RData, RecordType};
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn conf() -> DnsProxy {
+        DnsProxy {
+            listen: vec![],
+            identity: None,
+            cache: None,
+            records: vec![],
+            hosts: vec![],
+            local_ttl: 300,
+            forward: BTreeMap::new(),
+            default: DnsTarget::Forward(fixed_nameserver([10, 1, 1, 1].into()).into()),
+            query_types: vec![],
+            client_forward: BTreeMap::new(),
+            verify: BTreeMap::new(),
+            client_subnet: BTreeMap::new(),
+            fake_ip_pool: ([198, 18, 0, 0].into(), 15),
+            learn_domains: None,
+            query_log: None,
+            stats_interval: None,
+            dnssec: None,
+        }
+    }
+
+    fn resolver(conf: &DnsProxy) -> SmartResolver {
+        let p = Path::new("test/conf.d");
+        let matcher = Arc::new(DomainMatcher::new(p).unwrap());
+        let ip_matcher = Arc::new(IpMatcher::new(p).unwrap());
+        SmartResolver::new(matcher, ip_matcher, DomainHints::default(), conf).unwrap()
+    }
+
+    fn query(name: &str, t: RecordType) -> Query {
+        Query::query(Name::from_str(name).unwrap(), t)
+    }
+
+    fn resolve(check: AnswerCheck) -> RData {
+        let mut c = conf();
+        let retry = fixed_nameserver([1, 2, 3, 4].into()).into();
+        c.verify.insert("else".into(), DnsVerify { check, retry });
+        let r = resolver(&c);
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let mut q = Message::new();
+        q.add_query(query("example.com.", RecordType::A));
+        let q = q.to_vec().unwrap();
+        let res = rt.block_on(r.handle_future(&q, LOCALHOST)).unwrap();
+        let m = Message::from_vec(&res).unwrap();
+        let a = m.answers()[0].rdata().clone();
+        assert_eq!(r.zone_counts()[&Bytes::from("else")].queries, 1);
+        let upstream: u64 = r.upstream_counts().values().map(|c| c.queries).sum();
+        let retried = a == RData::A([1, 2, 3, 4].into());
+        assert_eq!(upstream, if retried { 2 } else { 1 });
+        a
+    }
+
+    #[test]
+    fn poisoned_answers_retried() {
+        let poisoned = RData::A([10, 1, 1, 1].into());
+        let trusted = RData::A([1, 2, 3, 4].into());
+        assert_eq!(resolve(AnswerCheck::Reject("uccu".into())), trusted);
+        assert_eq!(resolve(AnswerCheck::Expect("uccu".into())), poisoned);
+        assert_eq!(resolve(AnswerCheck::Expect("cs".into())), trusted);
+    }
+
+    #[test]
+    fn client_tables() {
+        let mut c = conf();
+        let mut kids = BTreeMap::new();
+        let uccu = DnsTarget::Forward(fixed_nameserver([5, 6, 7, 8].into()).into());
+        kids.insert("uccu".into(), uccu);
+        kids.insert("else".into(), DnsTarget::Block(BlockMode::NxDomain));
+        c.client_forward.insert("uccu".into(), kids);
+        let r = resolver(&c);
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let mut ask = |name: &str, client: [u8; 4]| {
+            let mut q = Message::new();
+            q.add_query(query(name, RecordType::A));
+            let q = q.to_vec().unwrap();
+            let res = rt.block_on(r.handle_future(&q, client.into())).unwrap();
+            let m = Message::from_vec(&res).unwrap();
+            match m.answers().first() {
+                Some(a) => Ok(a.rdata().clone()),
+                None => Err(m.response_code()),
+            }
+        };
+        let a = |ip: [u8; 4]| Ok(RData::A(ip.into()));
+        assert_eq!(ask("uccu.example.com.", [10, 1, 2, 3]), a([5, 6, 7, 8]));
+        assert_eq!(
+            ask("example.com.", [10, 1, 2, 3]),
+            Err(ResponseCode::NXDomain)
+        );
+        assert_eq!(ask("uccu.example.com.", [127, 0, 0, 1]), a([10, 1, 1, 1]));
+        assert_eq!(ask("example.com.", [127, 0, 0, 1]), a([10, 1, 1, 1]));
+    }
+
+    #[test]
+    fn query_type_egress() {
+        let dir = std::env::temp_dir().join(format!("reflow-types-{}", std::process::id()));
+        fs::create_dir_all(dir.join("addrzone")).unwrap();
+        fs::copy("test/conf.d/addrzone/uccu", dir.join("addrzone/uccu")).unwrap();
+        let config = "egress proxy = socks5 127.0.0.1:1080
+dns {
+  listen = udp 127.0.0.1:53
+  forward = {
+    else => udp 1.1.1.1:53
+  }
+  query-types = {
+    PTR uccu => proxy|udp 10.1.1.1:53
+  }
+}
+";
+        fs::write(dir.join("config"), config).unwrap();
+        let conf = load_conf(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let conf = conf.unwrap();
+        let d = conf.dns.unwrap();
+        let hints = DomainHints::default();
+        assert!(SmartResolver::new(conf.domain_matcher, conf.ip_matcher, hints, &d).is_ok());
+    }
+
+    #[test]
+    fn query_type_rules() {
+        let mut c = conf();
+        let empty = DnsTarget::Block(BlockMode::Empty);
+        let rule = |t, z: Option<&str>, target| QueryTypeRule {
+            query_type: t,
+            zone: z.map(Bytes::from),
+            target,
+        };
+        let lan = DnsTarget::Forward(fixed_nameserver([5, 6, 7, 8].into()).into());
+        c.query_types = vec![
+            rule(RecordType::AAAA, Some("uccu"), empty.clone()),
+            rule(RecordType::Unknown(65), None, empty),
+            rule(RecordType::PTR, Some("uccu"), lan),
+        ];
+        let r = resolver(&c);
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let mut ask = |name: &str, t| {
+            let mut q = Message::new();
+            q.add_query(query(name, t));
+            let q = q.to_vec().unwrap();
+            let res = rt.block_on(r.handle_future(&q, LOCALHOST)).unwrap();
+            let m = Message::from_vec(&res).unwrap();
+            assert_eq!(m.response_code(), ResponseCode::NoError);
+            m.answers().first().map(|a| a.rdata().clone())
+        };
+        let a = |ip: [u8; 4]| Some(RData::A(ip.into()));
+        assert_eq!(ask("uccu.example.com.", RecordType::AAAA), None);
+        assert_eq!(ask("uccu.example.com.", RecordType::A), a([10, 1, 1, 1]));
+        assert_eq!(ask("example.com.", RecordType::Unknown(65)), None);
+        assert_eq!(
+            ask("1.0.0.10.in-addr.arpa.", RecordType::PTR),
+            a([5, 6, 7, 8])
+        );
+        assert_eq!(
+            ask("1.0.0.127.in-addr.arpa.", RecordType::PTR),
+            a([10, 1, 1, 1])
+        );
+
+        let v6 = "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa.";
+        let v6 = arpa_addr(&Name::from_str(v6).unwrap());
+        assert_eq!(v6, Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(
+            arpa_addr(&Name::from_str("0.10.in-addr.arpa.").unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn bad_queries_answered() {
+        let r = resolver(&conf());
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let mut handle = |q: &[u8]| {
+            rt.block_on(r.handle_future(q, LOCALHOST))
+                .map(|res| Message::from_vec(&res).unwrap())
+        };
+
+        let mut q = Message::new();
+        q.set_id(7);
+        q.add_query(query("example.com.", RecordType::A));
+        q.add_query(query("example.net.", RecordType::A));
+        let m = handle(&q.to_vec().unwrap()).unwrap();
+        assert_eq!(m.id(), 7);
+        assert_eq!(m.response_code(), ResponseCode::FormErr);
+
+        let mut q = Message::new();
+        q.add_query(query("example.com.", RecordType::AXFR));
+        let m = handle(&q.to_vec().unwrap()).unwrap();
+        assert_eq!(m.response_code(), ResponseCode::Refused);
+
+        let mut q = Message::new();
+        q.set_id(9);
+        q.add_query(query("example.com.", RecordType::A));
+        let q = q.to_vec().unwrap();
+        for l in 0..q.len() {
+            if let Ok(m) = handle(&q[..l]) {
+                assert_eq!(m.id(), 9);
+                assert_eq!(m.response_code(), ResponseCode::FormErr);
+            }
+        }
+        let mut seed = 1u32;
+        for l in 0..200 {
+            let g: Vec<u8> = (0..l)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (seed >> 16) as u8
+                })
+                .collect();
+            if let Ok(m) = handle(&g) {
+                assert_eq!(m.message_type(), MessageType::Response);
+            }
+        }
+    }
+
+    #[test]
+    fn large_responses_truncated() {
+        let mut m = Message::new();
+        m.set_message_type(MessageType::Response);
+        m.add_query(query("example.com.", RecordType::A));
+        let name = Name::from_str("example.com.").unwrap();
+        for i in 0..100 {
+            let a = RData::A([10, 0, 0, i].into());
+            m.add_answer(trust_dns::rr::Record::from_rdata(name.clone(), 60, a));
+        }
+        let r = m.to_vec().unwrap();
+        assert_eq!(truncate(r.clone(), 4096).unwrap(), r);
+        let t = Message::from_vec(&truncate(r, 512).unwrap()).unwrap();
+        assert!(t.truncated());
+        assert!(t.answers().is_empty());
+        assert_eq!(t.queries(), m.queries());
     }
 }
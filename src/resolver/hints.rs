@@ -0,0 +1,25 @@
+use std::sync::Arc;
+
+use super::fake::FakeIpPool;
+use super::learned::LearnedDomains;
+use crate::conf::DnsProxy;
+
+/// Shared by the dns proxy and relays, to tell the domain names of addresses
+#[derive(Clone, Default)]
+pub struct DomainHints {
+    pub fake_ip: Option<Arc<FakeIpPool>>,
+    pub learned: Option<Arc<LearnedDomains>>,
+}
+
+impl DomainHints {
+    pub fn new(conf: Option<&DnsProxy>) -> DomainHints {
+        let conf = match conf {
+            Some(c) => c,
+            None => return DomainHints::default(),
+        };
+        DomainHints {
+            fake_ip: FakeIpPool::from_conf(conf).map(Arc::new),
+            learned: conf.learn_domains.map(|n| Arc::new(LearnedDomains::new(n))),
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! Remember the domain names of addresses in dns answers
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use trust_dns::op::Message;
+
+pub struct LearnedDomains {
+    /// with the time the answer expires
+    addrs: Mutex<LruCache<IpAddr, (String, Instant)>>,
+}
+
+impl LearnedDomains {
+    pub fn new(size: usize) -> LearnedDomains {
+        LearnedDomains {
+            addrs: Mutex::new(LruCache::new(size)),
+        }
+    }
+
+    /// Addresses in the answer are associated with the name in the query,
+    /// not the canonical name
+    pub fn learn(&self, response: &Message) {
+        self.learn_at(response, Instant::now())
+    }
+
+    fn learn_at(&self, response: &Message, now: Instant) {
+        let name = match response.queries().first() {
+            Some(q) => q.name().to_ascii(),
+            None => return,
+        };
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let mut addrs = self.addrs.lock().unwrap();
+        for r in response.answers() {
+            if let Some(a) = r.rdata().to_ip_addr() {
+                let expire = now + Duration::from_secs(u64::from(r.ttl()));
+                addrs.put(a, (name.clone(), expire));
+            }
+        }
+    }
+
+    pub fn domain_of(&self, addr: IpAddr) -> Option<String> {
+        self.domain_at(addr, Instant::now())
+    }
+
+    fn domain_at(&self, addr: IpAddr, now: Instant) -> Option<String> {
+        let mut addrs = self.addrs.lock().unwrap();
+        let (n, expire) = addrs.get(&addr)?.clone();
+        if expire <= now {
+            addrs.pop(&addr);
+            return None;
+        }
+        Some(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LearnedDomains;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+    use trust_dns::op::{Message, Query};
+    use trust_dns::rr::{Name, RData, Record, RecordType};
+
+    #[test]
+    fn learn_until_expired() {
+        let l = LearnedDomains::new(2);
+        let name = Name::from_str("WWW.example.com.").unwrap();
+        let cname = Name::from_str("cdn.example.net.").unwrap();
+        let mut m = Message::new();
+        m.add_query(Query::query(name.clone(), RecordType::A));
+        m.add_answer(Record::from_rdata(name, 60, RData::CNAME(cname.clone())));
+        m.add_answer(Record::from_rdata(cname, 10, RData::A([1, 2, 3, 4].into())));
+        let now = Instant::now();
+        l.learn_at(&m, now);
+        let a = [1, 2, 3, 4].into();
+        let later = now + Duration::from_secs(9);
+        assert_eq!(l.domain_at(a, later), Some("www.example.com".into()));
+        let later = now + Duration::from_secs(10);
+        assert_eq!(l.domain_at(a, later), None);
+        assert_eq!(l.domain_at([1, 2, 3, 5].into(), now), None);
+    }
+}
@@ -0,0 +1,272 @@
+//! Answer queries using records in the config and hosts files
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str;
+
+use failure::Error;
+use trust_dns::op::{Message, ResponseCode};
+use trust_dns::rr::{LowerName, RData, Record, RecordType};
+
+use super::fake::FakeIpPool;
+use super::message::{fqdn, response_to, soa_record};
+use crate::conf::{BlockMode, DnsProxy, LocalData};
+
+/// Follow cnames for at most this many times
+const MAX_CNAME_CHAIN: usize = 8;
+
+pub struct LocalRecords {
+    names: HashMap<LowerName, Vec<RData>>,
+    ttl: u32,
+}
+
+impl LocalRecords {
+    pub fn new(conf: &DnsProxy) -> Result<LocalRecords, Error> {
+        let mut l = LocalRecords {
+            names: HashMap::new(),
+            ttl: conf.local_ttl,
+        };
+        for (n, d) in &conf.records {
+            l.add(n, d.clone())?;
+        }
+        for p in &conf.hosts {
+            l.read_hosts(p)?;
+        }
+        if !l.names.is_empty() {
+            info!("Loaded {} local domain names", l.names.len());
+        }
+        Ok(l)
+    }
+
+    fn add(&mut self, name: &str, d: LocalData) -> Result<(), Error> {
+        let n = LowerName::new(&fqdn(name)?);
+        let r = match d {
+            LocalData::Addr(IpAddr::V4(a)) => RData::A(a),
+            LocalData::Addr(IpAddr::V6(a)) => RData::AAAA(a),
+            LocalData::Cname(c) => RData::CNAME(fqdn(&c)?),
+        };
+        let rs = self.names.entry(n).or_default();
+        let has_cname = rs.iter().any(|r| r.to_record_type() == RecordType::CNAME);
+        if has_cname || (!rs.is_empty() && r.to_record_type() == RecordType::CNAME) {
+            return Err(format_err!("{} can't have a cname and other records", name));
+        }
+        rs.push(r);
+        Ok(())
+    }
+
+    /// each line is an address followed by names
+    fn read_hosts(&mut self, p: &Path) -> Result<(), Error> {
+        let contents = fs::read(p).map_err(|e| format_err!("Error reading {:?}: {}", p, e))?;
+        // lines other programs make sense of are skipped, like scoped addresses
+        for (i, line) in contents.split(|&c| c == b'\n').enumerate() {
+            let line = line.split(|&c| c == b'#').next().unwrap_or(b"");
+            let line = match str::from_utf8(line) {
+                Ok(l) => l,
+                Err(_e) => {
+                    warn!("Skipping line {} of {:?}, which isn't utf-8", i + 1, p);
+                    continue;
+                }
+            };
+            let mut words = line.split_whitespace();
+            let addr = match words.next().map(|a| (a, a.parse())) {
+                Some((_, Ok(a))) => a,
+                Some((a, Err(_e))) => {
+                    warn!("Skipping line {} of {:?}, {} isn't an address", i + 1, p, a);
+                    continue;
+                }
+                None => continue,
+            };
+            for n in words {
+                if let Err(e) = self.add(n, LocalData::Addr(addr)) {
+                    warn!("Skipping {} on line {} of {:?}: {}", n, i + 1, p, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// None if the name isn't defined locally
+    pub fn answer(&self, req: &Message) -> Option<Message> {
+        let q = req.queries().first()?;
+        let mut name = q.name().clone();
+        let mut rs = self.names.get(&LowerName::new(&name))?;
+        let mut res = response_to(req);
+        res.set_authoritative(true);
+        for _ in 0..MAX_CNAME_CHAIN {
+            let cname = rs.iter().find_map(|r| match r {
+                RData::CNAME(c) if q.query_type() != RecordType::CNAME => Some(c.clone()),
+                _ => None,
+            });
+            if let Some(c) = cname {
+                res.add_answer(Record::from_rdata(name, self.ttl, RData::CNAME(c.clone())));
+                name = c;
+                rs = match self.names.get(&LowerName::new(&name)) {
+                    Some(rs) => rs,
+                    // the rest is up to the client
+                    None => break,
+                };
+                continue;
+            }
+            for r in rs {
+                let t = q.query_type();
+                if t == r.to_record_type() || t == RecordType::ANY {
+                    res.add_answer(Record::from_rdata(name.clone(), self.ttl, r.clone()));
+                }
+            }
+            break;
+        }
+        if res.answers().is_empty() {
+            res.add_name_server(soa_record(q.name(), self.ttl));
+        }
+        Some(res)
+    }
+}
+
+/// Answer a query in a blocked zone
+pub fn block(req: &Message, mode: BlockMode, ttl: u32) -> Message {
+    let mut res = response_to(req);
+    let q = match req.queries().first() {
+        Some(q) => q,
+        None => return res,
+    };
+    let zero = match (mode, q.query_type()) {
+        (BlockMode::Zero, RecordType::A) => Some(RData::A([0, 0, 0, 0].into())),
+        (BlockMode::Zero, RecordType::AAAA) => Some(RData::AAAA([0; 16].into())),
+        _ => None,
+    };
+    match zero {
+        Some(r) => {
+            res.add_answer(Record::from_rdata(q.name().clone(), ttl, r));
+        }
+        None => {
+            if mode == BlockMode::NxDomain {
+                res.set_response_code(ResponseCode::NXDomain);
+            }
+            res.add_name_server(soa_record(q.name(), ttl));
+        }
+    }
+    res
+}
+
+/// Answer a query in a fake-ip zone, only ipv4 addresses are given
+pub fn fake_ip(req: &Message, pool: &FakeIpPool, ttl: u32) -> Message {
+    let mut res = response_to(req);
+    let q = match req.queries().first() {
+        Some(q) => q,
+        None => return res,
+    };
+    if q.query_type() == RecordType::A {
+        let a = pool.addr_of(&q.name().to_ascii());
+        res.add_answer(Record::from_rdata(q.name().clone(), ttl, RData::A(a)));
+    } else {
+        res.add_name_server(soa_record(q.name(), ttl));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{block, fake_ip, LocalRecords};
+    use crate::conf::{BlockMode, DnsProxy, DnsTarget, LocalData};
+    use crate::resolver::fake::FakeIpPool;
+    use std::collections::BTreeMap;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use trust_dns::op::{Message, Query, ResponseCode};
+    use trust_dns::rr::{Name, RData, RecordType};
+
+    fn query(name: &str, t: RecordType) -> Message {
+        let mut m = Message::new();
+        m.set_id(3)
+            .add_query(Query::query(Name::from_str(name).unwrap(), t));
+        m
+    }
+
+    fn local() -> LocalRecords {
+        let addr = |a| LocalData::Addr(IpAddr::from_str(a).unwrap());
+        let conf = DnsProxy {
+            listen: vec![],
+            identity: None,
+            cache: None,
+            records: vec![
+                ("www.lan".into(), LocalData::Cname("Router.lan.".into())),
+                ("router.lan".into(), addr("192.168.1.1")),
+                ("router.lan".into(), addr("fd00::1")),
+            ],
+            hosts: vec!["test/hosts".into()],
+            local_ttl: 60,
+            forward: BTreeMap::new(),
+            default: DnsTarget::Block(BlockMode::NxDomain),
+            query_types: vec![],
+            client_forward: BTreeMap::new(),
+            verify: BTreeMap::new(),
+            client_subnet: BTreeMap::new(),
+            fake_ip_pool: ([198, 18, 0, 0].into(), 15),
+            learn_domains: None,
+            query_log: None,
+            stats_interval: None,
+            dnssec: None,
+        };
+        LocalRecords::new(&conf).unwrap()
+    }
+
+    #[test]
+    fn local_answers() {
+        let l = local();
+        let r = l.answer(&query("www.lan.", RecordType::AAAA)).unwrap();
+        assert_eq!(r.id(), 3);
+        let rs: Vec<_> = r.answers().iter().map(|r| r.rdata().clone()).collect();
+        assert_eq!(
+            rs,
+            vec![
+                RData::CNAME(Name::from_str("router.lan.").unwrap()),
+                RData::AAAA("fd00::1".parse().unwrap())
+            ]
+        );
+
+        let r = l.answer(&query("NAS.home.", RecordType::A)).unwrap();
+        assert_eq!(r.answers()[0].rdata(), &RData::A([10, 0, 0, 2].into()));
+        assert_eq!(r.answers()[0].ttl(), 60);
+        let r = l.answer(&query("nas.home.", RecordType::MX)).unwrap();
+        assert!(r.answers().is_empty());
+        assert_eq!(r.response_code(), ResponseCode::NoError);
+        assert!(l.answer(&query("example.com.", RecordType::A)).is_none());
+        // after the lines skipped
+        let r = l.answer(&query("printer.home.", RecordType::A)).unwrap();
+        assert_eq!(r.answers()[0].rdata(), &RData::A([10, 0, 0, 3].into()));
+        assert!(l.answer(&query("router.link.", RecordType::AAAA)).is_none());
+    }
+
+    #[test]
+    fn block_answers() {
+        let r = block(&query("ad.example.", RecordType::A), BlockMode::Zero, 10);
+        assert_eq!(r.answers()[0].rdata(), &RData::A([0, 0, 0, 0].into()));
+        let r = block(&query("ad.example.", RecordType::TXT), BlockMode::Zero, 10);
+        assert_eq!(r.response_code(), ResponseCode::NoError);
+        assert!(r.answers().is_empty());
+        let r = block(
+            &query("ad.example.", RecordType::A),
+            BlockMode::NxDomain,
+            10,
+        );
+        assert_eq!(r.response_code(), ResponseCode::NXDomain);
+        assert_eq!(r.name_servers()[0].ttl(), 10);
+        let r = block(&query("ad.example.", RecordType::A), BlockMode::Empty, 10);
+        assert_eq!(r.response_code(), ResponseCode::NoError);
+        assert!(r.answers().is_empty());
+    }
+
+    #[test]
+    fn fake_ip_answers() {
+        let p = FakeIpPool::new([198, 18, 0, 0].into(), 15);
+        let r = fake_ip(&query("cdn.example.", RecordType::A), &p, 1);
+        let a = match r.answers()[0].rdata() {
+            RData::A(a) => *a,
+            x => panic!("not an address: {:?}", x),
+        };
+        assert_eq!(p.domain_of(a.into()), Some("cdn.example".into()));
+        let r = fake_ip(&query("cdn.example.", RecordType::AAAA), &p, 1);
+        assert!(r.answers().is_empty());
+    }
+}
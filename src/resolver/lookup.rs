@@ -1,8 +1,8 @@
 use super::dnsclient::DnsClient;
 use crate::conf::NameServer;
 
-use failure::Error;
 use failure::_core::time::Duration;
+use failure::Error;
 use std::net::IpAddr;
 use std::str::FromStr;
 use tokio::time::timeout;
@@ -10,29 +10,31 @@ use trust_dns::op::Message;
 use trust_dns::op::Query;
 use trust_dns::proto::rr::Name;
 use trust_dns::rr::RecordType;
-use trust_dns::serialize::binary::{BinDecodable, BinDecoder};
 
 pub struct AsyncResolver {
     client: DnsClient,
+    timeout: Duration,
 }
 impl AsyncResolver {
     pub fn new(rm: &NameServer) -> AsyncResolver {
         let client = DnsClient::new(rm);
-        AsyncResolver { client }
+        AsyncResolver {
+            client,
+            timeout: rm.timeout,
+        }
     }
 
     pub async fn resolve(&self, name: &str) -> Result<Vec<IpAddr>, Error> {
-        let result = timeout(Duration::from_secs(10), self.resolve_eternal(name)).await?;
+        let result = timeout(self.timeout, self.resolve_eternal(name)).await?;
         result
     }
     async fn resolve_eternal(&self, name: &str) -> Result<Vec<IpAddr>, Error> {
         let mut msg = Message::new();
-        let name = Name::from_str(name).unwrap();
+        let name = Name::from_str(name)?;
         let query = Query::query(name, RecordType::A);
         msg.add_query(query);
         let res = self.client.resolve(msg.to_vec()?).await?;
-        let mut decoder = BinDecoder::new(&res);
-        let message = Message::read(&mut decoder).expect("msg deco err");
+        let message = Message::from_vec(&res)?;
         let ips = message
             .answers()
             .into_iter()
@@ -48,13 +50,15 @@ impl AsyncResolver {
 
 #[cfg(test)]
 mod tests {
+    use crate::conf::main::DEFAULT_TIMEOUT;
     use crate::conf::{main::RefVal, Egress, EgressAddr};
     use crate::conf::{NameServer, NameServerRemote};
     use crate::resolver::AsyncResolver;
     use bytes::Bytes;
     use std::net::IpAddr;
-    use std::net::SocketAddr;
+    use std::net::{SocketAddr, UdpSocket};
     use std::str::FromStr;
+    use std::thread;
 
     #[test]
     fn udp_test() {
@@ -63,9 +67,10 @@ mod tests {
         let ns = NameServer {
             remote,
             egress: None,
+            timeout: DEFAULT_TIMEOUT,
         };
         let resolver = AsyncResolver::new(&ns);
-        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
         let response = rt.block_on(async move { resolver.resolve("www.example.com.").await });
         assert!(response.is_err());
     }
@@ -80,10 +85,36 @@ mod tests {
                 name: Bytes::new(),
                 addr: EgressAddr::Socks5(SocketAddr::from_str("1.1.1.1:3128").unwrap()),
             })),
+            timeout: DEFAULT_TIMEOUT,
         };
         let resolver = AsyncResolver::new(&ns);
-        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
         let response = rt.block_on(async move { resolver.resolve("www.example.com").await });
         assert!(response.is_err());
     }
+
+    #[test]
+    fn malformed() {
+        // answers every query with a truncated header
+        let s = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let remote = NameServerRemote::Udp(s.local_addr().unwrap());
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((_, p)) = s.recv_from(&mut buf) {
+                s.send_to(&buf[..5], p).unwrap();
+            }
+        });
+        let ns = NameServer {
+            remote,
+            egress: None,
+            timeout: DEFAULT_TIMEOUT,
+        };
+        let resolver = AsyncResolver::new(&ns);
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let long = format!("{}.example.com", "a".repeat(64));
+        rt.block_on(async move {
+            assert!(resolver.resolve("www.example.com").await.is_err());
+            assert!(resolver.resolve(&long).await.is_err());
+        });
+    }
 }
@@ -0,0 +1,128 @@
+//! Build responses without asking upstream
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use failure::Error;
+use trust_dns::op::{Edns, Header, Message, MessageType, ResponseCode};
+use trust_dns::rr::rdata::SOA;
+use trust_dns::rr::{Name, RData, Record};
+use trust_dns::serialize::binary::{BinDecodable, BinDecoder};
+
+/// The largest udp payload we accept
+pub const MAX_PAYLOAD: u16 = 4096;
+/// For clients without EDNS, RFC 1035
+const MIN_PAYLOAD: u16 = 512;
+
+/// A response to a query, without any record
+pub fn response_to(req: &Message) -> Message {
+    let mut m = Message::new();
+    m.set_id(req.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(req.op_code())
+        .set_recursion_desired(req.recursion_desired())
+        .set_recursion_available(true)
+        .set_checking_disabled(req.checking_disabled())
+        .set_response_code(ResponseCode::NoError);
+    m.add_queries(req.queries().to_vec());
+    if let Some(e) = req.edns() {
+        let mut edns = Edns::new();
+        edns.set_max_payload(MAX_PAYLOAD);
+        edns.set_dnssec_ok(e.dnssec_ok());
+        m.set_edns(edns);
+    }
+    m
+}
+
+/// A response with the code and no record
+pub fn error_response(req: &Message, code: ResponseCode) -> Message {
+    let mut m = response_to(req);
+    m.set_response_code(code);
+    m
+}
+
+/// The response to a query that can't be decoded,
+/// if its header is good enough to reply to
+pub fn format_error(buffer: &[u8]) -> Option<Message> {
+    let h = Header::read(&mut BinDecoder::new(buffer)).ok()?;
+    if h.message_type() != MessageType::Query {
+        return None;
+    }
+    let mut m = Message::error_msg(h.id(), h.op_code(), ResponseCode::FormErr);
+    m.set_recursion_desired(h.recursion_desired())
+        .set_recursion_available(true);
+    Some(m)
+}
+
+/// The largest response the client can receive over udp
+pub fn udp_payload(req: &Message) -> usize {
+    let p = match req.edns() {
+        Some(e) => e.max_payload().clamp(MIN_PAYLOAD, MAX_PAYLOAD),
+        None => MIN_PAYLOAD,
+    };
+    p.into()
+}
+
+/// Responses too large are replaced by ones without records, and with the TC bit,
+/// so that the client can ask again over tcp
+pub fn truncate(response: Vec<u8>, limit: usize) -> Result<Vec<u8>, Error> {
+    if response.len() <= limit {
+        return Ok(response);
+    }
+    let r = Message::from_vec(&response)?;
+    let mut t = r.truncate();
+    t.add_queries(r.queries().to_vec());
+    let mut v = t.to_vec()?;
+    // the encoder recomputes the TC bit from the records
+    v[2] |= 0x02;
+    Ok(v)
+}
+
+/// Lets clients cache negative answers, RFC 2308
+pub fn soa_record(zone: &Name, ttl: u32) -> Record {
+    let soa = SOA::new(
+        zone.clone(),
+        zone.clone(),
+        1,
+        ttl as i32,
+        ttl as i32,
+        ttl as i32,
+        ttl,
+    );
+    Record::from_rdata(zone.clone(), ttl, RData::SOA(soa))
+}
+
+/// The address asked about by a PTR query of the name, like 4.3.2.1.in-addr.arpa.
+pub fn arpa_addr(name: &Name) -> Option<IpAddr> {
+    let labels: Vec<String> = name
+        .iter()
+        .rev()
+        .map(|l| String::from_utf8_lossy(l).to_ascii_lowercase())
+        .collect();
+    match labels.as_slice() {
+        [arpa, v4, d @ ..] if arpa == "arpa" && v4 == "in-addr" && d.len() == 4 => {
+            let mut o = [0u8; 4];
+            for (b, l) in o.iter_mut().zip(d) {
+                *b = l.parse().ok()?;
+            }
+            Some(o.into())
+        }
+        [arpa, v6, d @ ..] if arpa == "arpa" && v6 == "ip6" && d.len() == 32 => {
+            let mut o = [0u8; 16];
+            for (i, l) in d.iter().enumerate() {
+                if l.len() != 1 {
+                    return None;
+                }
+                let n = u8::from_str_radix(l, 16).ok()?;
+                o[i / 2] |= if i % 2 == 0 { n << 4 } else { n };
+            }
+            Some(o.into())
+        }
+        _ => None,
+    }
+}
+
+/// A domain name in the config, which may or may not end with a dot
+pub fn fqdn(name: &str) -> Result<Name, Error> {
+    let n = format!("{}.", name.trim_end_matches('.'));
+    Name::from_str(&n).map_err(|e| format_err!("Invalid domain name {}: {}", name, e))
+}
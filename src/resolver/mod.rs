@@ -1,10 +1,22 @@
 //! Proxy dns requests based on rules
 
+mod cache;
 pub mod client;
 mod dnsclient;
+mod dnssec;
+mod ecs;
+mod fake;
+mod group;
 mod handler;
+mod hints;
+mod learned;
+mod local;
 mod lookup;
+mod message;
+mod querylog;
 mod serve;
+mod stats;
 
+pub use self::hints::DomainHints;
 pub use self::lookup::AsyncResolver;
 pub use self::serve::serve;
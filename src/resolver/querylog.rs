@@ -0,0 +1,157 @@
+//! Every query as a line of json, for later analysis
+
+use std::fs::OpenOptions;
+use std::io::{self, LineWriter, Write};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure::Error;
+use trust_dns::op::ResponseCode;
+use trust_dns::rr::RecordType;
+
+use crate::conf::QueryLog;
+
+pub struct QueryLogger {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+/// How a query was answered
+pub struct LogEntry<'a> {
+    pub client: IpAddr,
+    pub name: &'a str,
+    pub query_type: RecordType,
+    /// The zone of the name, `local` if answered by local records
+    pub zone: &'a str,
+    /// The nameserver that answered, if any
+    pub upstream: Option<&'a str>,
+    pub cached: bool,
+    pub code: ResponseCode,
+    pub latency: Duration,
+}
+
+impl QueryLogger {
+    pub fn new(conf: &QueryLog) -> Result<QueryLogger, Error> {
+        let out: Box<dyn Write + Send> = match conf {
+            QueryLog::Stderr => Box::new(io::stderr()),
+            QueryLog::File(p) => {
+                let f = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(p)
+                    .map_err(|e| format_err!("Can't open query log {:?}: {}", p, e))?;
+                Box::new(LineWriter::new(f))
+            }
+        };
+        Ok(QueryLogger::with_output(out))
+    }
+
+    fn with_output(out: Box<dyn Write + Send>) -> QueryLogger {
+        QueryLogger {
+            out: Mutex::new(out),
+        }
+    }
+
+    pub fn log(&self, e: &LogEntry) {
+        let line = e.to_json(SystemTime::now());
+        let mut out = self.out.lock().unwrap();
+        if let Err(err) = writeln!(out, "{}", line) {
+            warn!("Error writing query log: {}", err);
+        }
+    }
+}
+
+impl<'a> LogEntry<'a> {
+    fn to_json(&self, now: SystemTime) -> String {
+        let time = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let upstream = match self.upstream {
+            Some(u) => json_str(u),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"time\":{},\"client\":\"{}\",\"name\":{},\"type\":{},\"zone\":{},\
+             \"upstream\":{},\"cached\":{},\"rcode\":{},\"latency_ms\":{}}}",
+            time,
+            self.client,
+            json_str(self.name),
+            json_str(&self.query_type.to_string()),
+            json_str(self.zone),
+            upstream,
+            self.cached,
+            json_str(&self.code.to_string()),
+            self.latency.as_millis()
+        )
+    }
+}
+
+fn json_str(s: &str) -> String {
+    let mut r = String::with_capacity(s.len() + 2);
+    r.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => r.push_str("\\\""),
+            '\\' => r.push_str("\\\\"),
+            c if (c as u32) < 0x20 => r.push_str(&format!("\\u{:04x}", c as u32)),
+            c => r.push(c),
+        }
+    }
+    r.push('"');
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogEntry, QueryLogger};
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
+    use trust_dns::op::ResponseCode;
+    use trust_dns::rr::RecordType;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, b: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(b)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_lines() {
+        let e = LogEntry {
+            client: [192, 168, 1, 2].into(),
+            name: "a\"b.example.com.",
+            query_type: RecordType::AAAA,
+            zone: "else",
+            upstream: Some("proxy|udp 8.8.8.8:53"),
+            cached: false,
+            code: ResponseCode::NXDomain,
+            latency: Duration::from_millis(12),
+        };
+        assert_eq!(
+            e.to_json(UNIX_EPOCH + Duration::from_secs(1)),
+            "{\"time\":1000,\"client\":\"192.168.1.2\",\"name\":\"a\\\"b.example.com.\",\
+             \"type\":\"AAAA\",\"zone\":\"else\",\"upstream\":\"proxy|udp 8.8.8.8:53\",\
+             \"cached\":false,\"rcode\":\"Non-Existent Domain\",\"latency_ms\":12}"
+        );
+        let out = Shared::default();
+        let l = QueryLogger::with_output(Box::new(out.clone()));
+        let e = LogEntry {
+            upstream: None,
+            cached: true,
+            ..e
+        };
+        l.log(&e);
+        l.log(&e);
+        let written = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(written.lines().count(), 2);
+        assert!(written.lines().all(|l| l.contains("\"upstream\":null")));
+    }
+}
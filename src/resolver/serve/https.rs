@@ -0,0 +1,185 @@
+//! Dns over https, RFC 8484
+use failure::Error;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::net::TcpListener as TcpListenerStd;
+use std::sync::Arc;
+
+use h2::server::SendResponse;
+use h2::RecvStream;
+use http::header::{HeaderValue, CONTENT_TYPE};
+use http::{Method, Request, Response, StatusCode};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+
+use crate::resolver::handler::SmartResolver;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+const PATH: &str = "/dns-query";
+
+pub fn serve_https(
+    addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    handler: Arc<SmartResolver>,
+) -> Result<(), Error> {
+    let l = TcpListenerStd::bind(addr)?;
+    let mut l = TcpListener::from_std(l)?;
+    tokio::spawn(async move {
+        loop {
+            let (s, peer) = match l.accept().await {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("Error accepting dns over https connection: {}", e);
+                    continue;
+                }
+            };
+            let h = handler.clone();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                if let Err(e) = handle_connection(s, peer, acceptor, h).await {
+                    debug!("Error handling dns over https client {}: {}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+async fn handle_connection(
+    s: TcpStream,
+    peer: SocketAddr,
+    acceptor: TlsAcceptor,
+    handler: Arc<SmartResolver>,
+) -> Result<(), Error> {
+    let s = acceptor.accept(s).await?;
+    let mut conn = h2::server::Builder::new().handshake(s).await?;
+    while let Some(r) = conn.accept().await {
+        let (req, respond) = r?;
+        let h = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(req, respond, peer, h).await {
+                debug!("Error responding to dns over https client {}: {}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn handle_request(
+    req: Request<RecvStream>,
+    mut respond: SendResponse<Cursor<Vec<u8>>>,
+    peer: SocketAddr,
+    handler: Arc<SmartResolver>,
+) -> Result<(), Error> {
+    let query = match read_query(req).await {
+        Ok(q) => q,
+        Err(status) => {
+            let r = Response::builder().status(status).body(())?;
+            respond.send_response(r, true)?;
+            return Ok(());
+        }
+    };
+    let ans = match handler.handle_future(&query, peer.ip()).await {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Error handling dns client {}: {:?}", peer, e);
+            let r = Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(())?;
+            respond.send_response(r, true)?;
+            return Ok(());
+        }
+    };
+    let r = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, DNS_MESSAGE)
+        .body(())?;
+    let mut body = respond.send_response(r, false)?;
+    body.send_data(Cursor::new(ans), true)?;
+    Ok(())
+}
+
+/// the dns message in either a GET or a POST request
+async fn read_query(req: Request<RecvStream>) -> Result<Vec<u8>, StatusCode> {
+    if req.uri().path() != PATH {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    match *req.method() {
+        Method::GET => {
+            let q = req.uri().query().unwrap_or("");
+            let d = q
+                .split('&')
+                .find(|p| p.starts_with("dns="))
+                .ok_or(StatusCode::BAD_REQUEST)?;
+            base64::decode_config(&d[4..], base64::URL_SAFE_NO_PAD)
+                .map_err(|_e| StatusCode::BAD_REQUEST)
+        }
+        Method::POST => {
+            let ct = req.headers().get(CONTENT_TYPE);
+            if ct != Some(&HeaderValue::from_static(DNS_MESSAGE)) {
+                return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            let mut body = req.into_body();
+            let mut buf = vec![];
+            while let Some(chunk) = body.data().await {
+                let chunk = chunk.map_err(|_e| StatusCode::BAD_REQUEST)?;
+                buf.extend_from_slice(&chunk);
+                if buf.len() > u16::MAX as usize {
+                    return Err(StatusCode::PAYLOAD_TOO_LARGE);
+                }
+                let _ = body.flow_control().release_capacity(chunk.len());
+            }
+            Ok(buf)
+        }
+        _ => Err(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::serve_https;
+    use crate::conf::{DohServer, TlsIdentity};
+    use crate::resolver::client::https::DohClient;
+    use crate::resolver::serve::tcp::tests::{echo_resolver, query, unused_addr};
+    use crate::resolver::serve::tls::tls_acceptor;
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::sync::Arc;
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::TlsConnector;
+
+    fn client(template: &str) -> DohClient {
+        let mut config = ClientConfig::new();
+        let mut ca = BufReader::new(File::open("test/tls/ca.pem").unwrap());
+        config.root_store.add_pem_file(&mut ca).unwrap();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let server = DohServer::new(template, Some([127, 0, 0, 1].into())).unwrap();
+        DohClient::with_connector(server, None, TlsConnector::from(Arc::new(config)))
+    }
+
+    #[test]
+    fn get_and_post() {
+        let id = TlsIdentity {
+            cert: "test/tls/cert.pem".into(),
+            key: "test/tls/key.pem".into(),
+        };
+        let acceptor = tls_acceptor(&id, vec![b"h2".to_vec()]).unwrap();
+        let addr = unused_addr();
+        let url = format!("https://dns.test:{}/dns-query", addr.port());
+        let post = client(&url);
+        let get = client(&format!("{}{{?dns}}", url));
+        let wrong_path = client(&format!("https://dns.test:{}/query", addr.port()));
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            serve_https(addr, acceptor, echo_resolver()).unwrap();
+            for i in 1..3 {
+                let r = post.get(query(i)).await.unwrap();
+                assert_eq!(r[1], i);
+                assert!(r[2] & 0x80 != 0);
+                let r = get.get(query(i + 10)).await.unwrap();
+                assert_eq!(r[1], i + 10);
+            }
+            assert!(wrong_path.get(query(1)).await.is_err());
+        });
+    }
+}
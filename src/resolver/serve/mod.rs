@@ -1,61 +1,73 @@
 use failure::Error;
-use std::io;
-
-use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio;
+use std::time::Duration;
 
+use crate::conf::DnsListen;
 use crate::conf::DnsProxy;
-use crate::conf::DomainMatcher;
+use crate::conf::{DomainMatcher, IpMatcher};
 use crate::resolver::handler;
 use crate::resolver::handler::SmartResolver;
+use crate::resolver::DomainHints;
 
-use std::net::UdpSocket as UdpSocketStd;
-use tokio::net::UdpSocket;
-use tokio_net::driver::Handle;
+mod https;
+pub(super) mod tcp;
+pub(super) mod tls;
+mod udp;
 
-pub fn serve(conf: DnsProxy, matcher: Arc<DomainMatcher>) -> Result<(), Error> {
-    let handler = handler::SmartResolver::new(matcher, &conf)?;
+/// The handler is returned to save the cache when shutting down
+pub fn serve(
+    conf: DnsProxy,
+    matcher: Arc<DomainMatcher>,
+    ip_matcher: Arc<IpMatcher>,
+    hints: DomainHints,
+) -> Result<Arc<SmartResolver>, Error> {
+    let handler = handler::SmartResolver::new(matcher, ip_matcher, hints, &conf)?;
     let handler = Arc::new(handler);
-    let addr = conf.listen;
-
-    let sock_std = UdpSocketStd::bind(addr)?;
-
-    tokio::spawn(async move {
-        loop {
-            let (sock, mut buf, size, peer) = match recv_req(&sock_std).await {
-                Ok(x) => x,
-                Err(e) => {
-                    warn!("Error receiving datagram: {}", e);
-                    continue;
+    if let Some(ref c) = conf.cache {
+        if c.prefetch > 0 {
+            let h = handler.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::delay_for(Duration::from_secs(1)).await;
+                    h.prefetch().await;
                 }
-            };
-            buf.truncate(size);
-            let h = (&handler).clone();
+            });
+        }
+        if c.file.is_some() {
+            let h = handler.clone();
+            let i = Duration::from_secs(c.save_interval.into());
             tokio::spawn(async move {
-                if let Err(e) = handle_dns_client(&buf, peer, sock, h).await {
-                    error!("Error handling dns client: {:?}", e);
+                loop {
+                    tokio::time::delay_for(i).await;
+                    h.save_cache();
                 }
             });
         }
-    });
-    Ok(())
-}
-
-async fn recv_req(sock: &UdpSocketStd) -> io::Result<(UdpSocket, Vec<u8>, usize, SocketAddr)> {
-    let mut sock_tok = UdpSocket::from_std(sock.try_clone()?)?;
-    let mut buf = vec![0; 998];
-    let (n, a) = sock_tok.recv_from(&mut buf).await?;
-    Ok((sock_tok, buf, n, a))
-}
-
-async fn handle_dns_client(
-    data: &[u8],
-    peer: SocketAddr,
-    mut sock: UdpSocket,
-    handler: Arc<SmartResolver>,
-) -> Result<(), Error> {
-    let x = handler.handle_future(&data).await?;
-    sock.send_to(&x, &peer).await?;
-    Ok(())
+    }
+    if let Some(i) = conf.stats_interval {
+        let h = handler.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::delay_for(i).await;
+                h.log_stats();
+            }
+        });
+    }
+    for l in &conf.listen {
+        match *l {
+            DnsListen::Udp(a) => udp::serve_udp(a, handler.clone())?,
+            DnsListen::Tcp(a) => tcp::serve_tcp(a, handler.clone())?,
+            DnsListen::Tls(a) => {
+                let id = conf.identity.as_ref().expect("checked when loading");
+                let acceptor = tls::tls_acceptor(id, vec![])?;
+                tls::serve_tls(a, acceptor, handler.clone())?
+            }
+            DnsListen::Https(a) => {
+                let id = conf.identity.as_ref().expect("checked when loading");
+                let acceptor = tls::tls_acceptor(id, vec![b"h2".to_vec()])?;
+                https::serve_https(a, acceptor, handler.clone())?
+            }
+        }
+    }
+    Ok(handler)
 }
@@ -0,0 +1,182 @@
+//! Dns over tcp, RFC 7766
+use failure::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::net::TcpListener as TcpListenerStd;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{split, AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+use crate::resolver::client::tcp::{read_message, send_message};
+use crate::resolver::handler::SmartResolver;
+
+/// Close connections without any query for this long
+const IDLE_TIMEOUT: u64 = 30;
+
+pub fn serve_tcp(addr: SocketAddr, handler: Arc<SmartResolver>) -> Result<(), Error> {
+    let l = TcpListenerStd::bind(addr)?;
+    let mut l = TcpListener::from_std(l)?;
+    tokio::spawn(async move {
+        loop {
+            match l.accept().await {
+                Ok((s, peer)) => {
+                    let h = handler.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_dns_stream(s, peer, h).await {
+                            debug!("Error handling dns client {}: {}", peer, e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Error accepting dns connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Queries on one connection are handled concurrently,
+/// and the responses are sent in the order they are ready
+pub async fn handle_dns_stream<S>(
+    stream: S,
+    peer: SocketAddr,
+    handler: Arc<SmartResolver>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = split(stream);
+    let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(16);
+    tokio::spawn(async move {
+        while let Some(m) = receiver.recv().await {
+            if let Err(e) = send_message(&mut writer, &m).await {
+                debug!("Error sending dns response to {}: {}", peer, e);
+                break;
+            }
+        }
+    });
+    loop {
+        let idle = Duration::from_secs(IDLE_TIMEOUT);
+        let m = match timeout(idle, read_message(&mut reader)).await {
+            Ok(Ok(m)) => m,
+            Ok(Err(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                trace!("Closing idle dns connection from {}", peer);
+                return Ok(());
+            }
+        };
+        let h = handler.clone();
+        let mut sender = sender.clone();
+        tokio::spawn(async move {
+            match h.handle_future(&m, peer.ip()).await {
+                Ok(r) => {
+                    let _ = sender.send(r).await;
+                }
+                Err(e) => error!("Error handling dns client {}: {:?}", peer, e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::serve_tcp;
+    use crate::conf::main::DEFAULT_TIMEOUT;
+    use crate::conf::{DnsProxy, DnsTarget, DomainMatcher, IpMatcher};
+    use crate::conf::{NameServer, NameServerRemote};
+    use crate::resolver::client::tcp::{read_message, send_message};
+    use crate::resolver::handler::SmartResolver;
+    use crate::resolver::DomainHints;
+    use std::collections::BTreeMap;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+    use tokio::net::TcpStream;
+
+    /// answers every query with the query itself
+    pub fn echo_nameserver() -> SocketAddr {
+        let s = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let a = s.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            while let Ok((n, p)) = s.recv_from(&mut buf) {
+                buf[2] |= 0x80;
+                s.send_to(&buf[..n], p).unwrap();
+            }
+        });
+        a
+    }
+
+    /// forwards everything to the echo server
+    pub fn echo_resolver() -> Arc<SmartResolver> {
+        let conf = DnsProxy {
+            listen: vec![],
+            identity: None,
+            cache: None,
+            records: vec![],
+            hosts: vec![],
+            local_ttl: 300,
+            forward: BTreeMap::new(),
+            default: DnsTarget::Forward(
+                NameServer {
+                    egress: None,
+                    remote: NameServerRemote::Udp(echo_nameserver()),
+                    timeout: DEFAULT_TIMEOUT,
+                }
+                .into(),
+            ),
+            query_types: vec![],
+            client_forward: BTreeMap::new(),
+            verify: BTreeMap::new(),
+            client_subnet: BTreeMap::new(),
+            fake_ip_pool: ([198, 18, 0, 0].into(), 15),
+            learn_domains: None,
+            query_log: None,
+            stats_interval: None,
+            dnssec: None,
+        };
+        let p = Path::new("test/conf.d");
+        let matcher = Arc::new(DomainMatcher::new(p).unwrap());
+        let ip_matcher = Arc::new(IpMatcher::new(p).unwrap());
+        Arc::new(SmartResolver::new(matcher, ip_matcher, DomainHints::default(), &conf).unwrap())
+    }
+
+    pub fn unused_addr() -> SocketAddr {
+        let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        l.local_addr().unwrap()
+    }
+
+    pub fn query(id: u8) -> Vec<u8> {
+        let mut q = vec![0, id, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        q.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        q
+    }
+
+    #[test]
+    fn pipelined_queries() {
+        let handler = echo_resolver();
+        let addr = unused_addr();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let ids = rt.block_on(async move {
+            serve_tcp(addr, handler).unwrap();
+            let mut s = TcpStream::connect(&addr).await.unwrap();
+            for id in 1..=3u8 {
+                send_message(&mut s, &query(id)).await.unwrap();
+            }
+            let mut ids = vec![];
+            for _ in 0..3 {
+                let r = read_message(&mut s).await.unwrap();
+                assert!(r[2] & 0x80 != 0);
+                ids.push(r[1]);
+            }
+            ids.sort();
+            ids
+        });
+        assert_eq!(ids, vec![1, 2, 3]);
+    }
+}
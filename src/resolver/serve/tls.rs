@@ -0,0 +1,113 @@
+//! Dns over tls, RFC 7858
+use failure::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::net::TcpListener as TcpListenerStd;
+use std::sync::Arc;
+
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use super::tcp::handle_dns_stream;
+use crate::conf::TlsIdentity;
+use crate::resolver::handler::SmartResolver;
+
+pub fn tls_acceptor(id: &TlsIdentity, alpn: Vec<Vec<u8>>) -> Result<TlsAcceptor, Error> {
+    let mut r = BufReader::new(File::open(&id.cert)?);
+    let cert = certs(&mut r).map_err(|_e| format_err!("Invalid certificate {:?}", id.cert))?;
+    if cert.is_empty() {
+        return Err(format_err!("No certificate found in {:?}", id.cert));
+    }
+    let mut r = BufReader::new(File::open(&id.key)?);
+    let mut keys = pkcs8_private_keys(&mut r).unwrap_or_default();
+    if keys.is_empty() {
+        let mut r = BufReader::new(File::open(&id.key)?);
+        keys = rsa_private_keys(&mut r).unwrap_or_default();
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| format_err!("No private key found in {:?}", id.key))?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(cert, key)?;
+    config.set_protocols(&alpn);
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+pub fn serve_tls(
+    addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    handler: Arc<SmartResolver>,
+) -> Result<(), Error> {
+    let l = TcpListenerStd::bind(addr)?;
+    let mut l = TcpListener::from_std(l)?;
+    tokio::spawn(async move {
+        loop {
+            let (s, peer) = match l.accept().await {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("Error accepting dns over tls connection: {}", e);
+                    continue;
+                }
+            };
+            let h = handler.clone();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let s = match acceptor.accept(s).await {
+                    Ok(s) => s,
+                    Err(e) => {
+                        debug!("Tls error with dns client {}: {}", peer, e);
+                        return;
+                    }
+                };
+                if let Err(e) = handle_dns_stream(s, peer, h).await {
+                    debug!("Error handling dns client {}: {}", peer, e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serve_tls, tls_acceptor};
+    use crate::conf::TlsIdentity;
+    use crate::resolver::client::tcp::{read_message, send_message};
+    use crate::resolver::serve::tcp::tests::{echo_resolver, query, unused_addr};
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::sync::Arc;
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::webpki::DNSNameRef;
+    use tokio_rustls::TlsConnector;
+
+    #[test]
+    fn query_over_tls() {
+        let id = TlsIdentity {
+            cert: "test/tls/cert.pem".into(),
+            key: "test/tls/key.pem".into(),
+        };
+        let acceptor = tls_acceptor(&id, vec![]).unwrap();
+        let mut config = ClientConfig::new();
+        let mut ca = BufReader::new(File::open("test/tls/ca.pem").unwrap());
+        config.root_store.add_pem_file(&mut ca).unwrap();
+        let connector = TlsConnector::from(Arc::new(config));
+        let addr = unused_addr();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let r = rt.block_on(async move {
+            serve_tls(addr, acceptor, echo_resolver()).unwrap();
+            let s = TcpStream::connect(&addr).await.unwrap();
+            let name = DNSNameRef::try_from_ascii_str("dns.test").unwrap();
+            let mut s = connector.connect(name, s).await.unwrap();
+            send_message(&mut s, &query(7)).await.unwrap();
+            read_message(&mut s).await.unwrap()
+        });
+        assert_eq!(r[1], 7);
+        assert!(r[2] & 0x80 != 0);
+    }
+}
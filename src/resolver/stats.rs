@@ -0,0 +1,80 @@
+//! Counters of dns queries, by zone and by upstream nameserver
+
+use std::fmt;
+use std::ops::AddAssign;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use failure::Error;
+
+/// A nameserver didn't answer in time
+#[derive(Debug, Fail)]
+#[fail(display = "{} timed out", _0)]
+pub struct TimedOut(pub String);
+
+/// Updated concurrently by queries
+#[derive(Debug, Default)]
+pub struct Counters {
+    queries: AtomicU64,
+    errors: AtomicU64,
+    timeouts: AtomicU64,
+    cache_hits: AtomicU64,
+}
+
+/// Counters at some point
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counts {
+    pub queries: u64,
+    /// Including timeouts
+    pub errors: u64,
+    pub timeouts: u64,
+    pub cache_hits: u64,
+}
+
+impl Counters {
+    pub fn query(&self) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn error(&self, e: &Error) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        if is_timeout(e) {
+            self.timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn counts(&self) -> Counts {
+        Counts {
+            queries: self.queries.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub fn is_timeout(e: &Error) -> bool {
+    e.downcast_ref::<TimedOut>().is_some()
+}
+
+impl AddAssign for Counts {
+    fn add_assign(&mut self, o: Counts) {
+        self.queries += o.queries;
+        self.errors += o.errors;
+        self.timeouts += o.timeouts;
+        self.cache_hits += o.cache_hits;
+    }
+}
+
+impl fmt::Display for Counts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "{} queries, {} cache hits, {} errors, {} timeouts",
+            self.queries, self.cache_hits, self.errors, self.timeouts
+        )
+    }
+}